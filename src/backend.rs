@@ -0,0 +1,116 @@
+use crate::cpu::Cpu;
+use crate::palette::Palette;
+use crate::phosphor::Intensities;
+
+// The emulator itself doesn't know anything about windows, keyboards or sound cards. It drives a
+// frontend through these three traits, so raylib is just one way of showing a game and a new
+// frontend only has to implement them.
+
+// Controls for the emulator itself rather than the game, however the frontend wants to trigger them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostEvent {
+    ToggleMute,
+    NextPalette,
+    NextPersistence,
+    ToggleRecording,
+    SaveState,
+    LoadState,
+    // Runs one instruction while in step mode
+    Step,
+    TogglePause,
+    // Runs one frame and stays paused
+    FrameAdvance,
+    ToggleStepMode,
+    // Loads the ROM into a fresh cpu
+    Reset,
+    Faster,
+    Slower,
+}
+
+pub trait InputSource {
+    // Called once per pass of the main loop. Fills in which CHIP-8 keys are held and adds any
+    // emulator controls that were pressed since last time.
+    fn poll(&mut self, keypad: &mut [bool; 16], events: &mut Vec<HostEvent>);
+
+    fn should_quit(&self) -> bool;
+}
+
+// Everything a frontend might want to draw
+pub struct FrameView<'a> {
+    pub cpu: &'a Cpu,
+    // The screen after the phosphor stage, see phosphor.rs
+    pub pixels: &'a Intensities,
+    pub palette: &'a Palette,
+    // Previous, current and next opcode
+    pub opcodes: [u16; 3],
+    pub frames_per_second: u32,
+    pub instructions_per_second: u64,
+    pub paused: bool,
+    // 1.0 is normal speed
+    pub speed: f32,
+}
+
+impl FrameView<'_> {
+    // One line saying what the emulator is up to, for the status bar
+    pub fn status(&self) -> String {
+        let state = if self.cpu.should_halt {
+            "Halted"
+        } else if self.paused {
+            "Paused"
+        } else if self.cpu.step_mode {
+            "Step mode"
+        } else {
+            "Running"
+        };
+        format!(
+            "{state}  {}x  FPS {}  IPS {}",
+            self.speed, self.frames_per_second, self.instructions_per_second
+        )
+    }
+}
+
+pub trait DisplaySink {
+    fn present(&mut self, frame: &FrameView<'_>);
+}
+
+pub trait AudioSink {
+    // How many samples the device can take right now, 0 if it's still busy with the last lot
+    fn samples_wanted(&mut self) -> usize;
+
+    // Signed 16 bit mono at buzzer::SAMPLE_RATE
+    fn play(&mut self, samples: &[i16]);
+}
+
+// Does nothing with the output and never presses anything unless told to. Used for headless runs,
+// and handy anywhere the emulator needs driving without a real frontend.
+#[derive(Debug, Default)]
+pub struct NullBackend {
+    // Held down on every poll
+    pub keypad: [bool; 16],
+    pub frames_presented: u64,
+}
+
+impl InputSource for NullBackend {
+    fn poll(&mut self, keypad: &mut [bool; 16], _events: &mut Vec<HostEvent>) {
+        *keypad = self.keypad;
+    }
+
+    fn should_quit(&self) -> bool {
+        false
+    }
+}
+
+impl DisplaySink for NullBackend {
+    fn present(&mut self, _frame: &FrameView<'_>) {
+        self.frames_presented += 1;
+    }
+}
+
+impl AudioSink for NullBackend {
+    // The buzzer caps its own queue, so never taking anything is fine
+    fn samples_wanted(&mut self) -> usize {
+        0
+    }
+
+    fn play(&mut self, _samples: &[i16]) {}
+}
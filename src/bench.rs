@@ -0,0 +1,167 @@
+use std::time::Instant;
+
+use crate::cpu::Cpu;
+
+// A handful of tiny programs that each lean on a different part of the interpreter. None of them
+// ever jump to themselves, so they run for as long as the benchmark wants.
+pub struct Workload {
+    pub name: &'static str,
+    pub program: &'static [u8],
+}
+
+pub const WORKLOADS: [Workload; 4] = [
+    // Register arithmetic in a tight loop, what most game logic boils down to
+    Workload {
+        name: "alu",
+        program: &[
+            0x60, 0x00, // 200: V0 = 0
+            0x61, 0x01, // 202: V1 = 1
+            0x80, 0x14, // 204: V0 += V1
+            0x82, 0x03, // 206: V2 ^= V0
+            0x83, 0x06, // 208: V3 = V0 >> 1
+            0x72, 0x01, // 20A: V2 += 1
+            0x30, 0x00, // 20C: skip if V0 == 0
+            0x12, 0x04, // 20E: jump 204
+            0x12, 0x00, // 210: jump 200
+        ],
+    },
+    // Font sprites drawn all over the screen
+    Workload {
+        name: "sprites",
+        program: &[
+            0x00, 0xE0, // 200: clear the screen
+            0x60, 0x00, // 202: V0 = 0
+            0x61, 0x00, // 204: V1 = 0
+            0xF0, 0x29, // 206: I = font character V0
+            0xD0, 0x15, // 208: draw 5 rows at V0, V1
+            0x70, 0x07, // 20A: V0 += 7
+            0x71, 0x03, // 20C: V1 += 3
+            0x12, 0x06, // 20E: jump 206
+        ],
+    },
+    // A subroutine that converts a number to decimal and shuffles it through memory
+    Workload {
+        name: "memory",
+        program: &[
+            0x6A, 0x7B, // 200: VA = 123
+            0x22, 0x10, // 202: call 210
+            0x7A, 0x01, // 204: VA += 1
+            0x12, 0x02, // 206: jump 202
+            0x00, 0x00, // 208
+            0x00, 0x00, // 20A
+            0x00, 0x00, // 20C
+            0x00, 0x00, // 20E
+            0xA3, 0x00, // 210: I = 300
+            0xFA, 0x33, // 212: store VA as decimal
+            0xF2, 0x65, // 214: load V0 to V2
+            0xA3, 0x10, // 216: I = 310
+            0xF2, 0x55, // 218: store V0 to V2
+            0x00, 0xEE, // 21A: return
+        ],
+    },
+    // Rewrites one of its own instructions every time around, the worst case for the decode cache
+    Workload {
+        name: "self-modifying",
+        program: &[
+            0x60, 0x72, // 200: V0 = 72
+            0x61, 0x01, // 202: V1 = 1
+            0xA2, 0x0A, // 204: I = 20A
+            0xF1, 0x55, // 206: store V0 and V1 over 20A
+            0x71, 0x01, // 208: V1 += 1
+            0x00, 0x00, // 20A: becomes 72 V1, V2 += V1
+            0x12, 0x04, // 20C: jump 204
+        ],
+    },
+];
+
+// How long a run took and how much it got through
+pub struct Measurement {
+    pub executed: u64,
+    pub seconds: f64,
+}
+
+impl Measurement {
+    pub fn mips(&self) -> f64 {
+        self.executed as f64 / self.seconds / 1_000_000.0
+    }
+}
+
+// Decodes every instruction from scratch, the way the interpreter used to run
+pub fn run_uncached(cpu: &mut Cpu, instructions: u64) -> Measurement {
+    measure(cpu, instructions, |cpu, _| {
+        let instruction = cpu.fetch();
+        cpu.decode_and_execute(instruction);
+        1
+    })
+}
+
+// Goes through the decode cache, the way frames are run now
+pub fn run_cached(cpu: &mut Cpu, instructions: u64) -> Measurement {
+    measure(cpu, instructions, |cpu, _| {
+        let op = cpu.fetch_decoded();
+        cpu.execute(op);
+        1
+    })
+}
+
+// Whole blocks at a time through the recompiler
+pub fn run_recompiled(cpu: &mut Cpu, instructions: u64) -> Measurement {
+    measure(cpu, instructions, |cpu, budget| cpu.run_block(budget))
+}
+
+// `step` runs as many instructions as it likes up to the budget it's given and says how many
+fn measure(
+    cpu: &mut Cpu,
+    instructions: u64,
+    mut step: impl FnMut(&mut Cpu, u64) -> u64,
+) -> Measurement {
+    let start = Instant::now();
+    let mut executed: u64 = 0;
+    while executed < instructions && !cpu.should_halt {
+        executed += step(cpu, instructions - executed);
+    }
+    Measurement {
+        executed,
+        seconds: start.elapsed().as_secs_f64(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The numbers are only worth comparing if all three did the same work, so every workload has to
+    // end up in the same place however it's run. How fast each one is gets left to `chipp bench`,
+    // timings on a busy machine are too noisy to fail a test on.
+    #[test]
+    fn every_way_of_running_ends_the_same() {
+        let runs = [
+            ("uncached", run_uncached as fn(&mut Cpu, u64) -> Measurement),
+            ("cached", run_cached),
+            ("recompiled", run_recompiled),
+        ];
+        for workload in &WORKLOADS {
+            let mut cpu = Cpu::new();
+            cpu.load_program_into_memory(&mut &workload.program[..])
+                .unwrap();
+            let start = cpu.save_state();
+
+            let mut ends = Vec::new();
+            for (name, run) in runs {
+                cpu.load_state(&start).unwrap();
+                let measurement = run(&mut cpu, 100_000);
+                assert_eq!(measurement.executed, 100_000, "{} {name}", workload.name);
+                ends.push((name, cpu.save_state()));
+            }
+            let (_, uncached) = &ends[0];
+            for (name, end) in &ends[1..] {
+                assert!(
+                    end == uncached,
+                    "{} {name} ended differently: {:?}",
+                    workload.name,
+                    end.differences(uncached)
+                );
+            }
+        }
+    }
+}
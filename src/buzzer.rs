@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::config::Config;
+
+pub const SAMPLE_RATE: u32 = 44100;
+// The timers run at 60Hz so we generate exactly one frame's worth of audio at a time
+pub const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / 60) as usize;
+// If the speaker stops pulling samples for a while we don't want the queue to grow forever and
+// end up beeping seconds after the game asked us to
+const MAX_QUEUED_SAMPLES: usize = SAMPLES_PER_FRAME * 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "square" => Some(Waveform::Square),
+            "sine" => Some(Waveform::Sine),
+            "triangle" => Some(Waveform::Triangle),
+            _ => None,
+        }
+    }
+
+    // Phase goes from 0 to 1 over a single period, the result is between -1 and 1
+    fn sample(&self, phase: f32) -> f32 {
+        match self {
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (phase * TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+        }
+    }
+}
+
+// The CHIP-8 only has a single buzzer that's either on or off, the tone itself is up to us.
+// This doesn't know anything about raylib, it just turns the state of the sound timer into
+// samples, which means the same samples can be written out to a WAV file.
+pub struct Buzzer {
+    pub waveform: Waveform,
+    pub frequency: f32,
+    pub volume: f32,
+    pub muted: bool,
+    phase: f32,
+    queue: VecDeque<i16>,
+    recording: Option<Vec<i16>>,
+}
+
+impl Buzzer {
+    pub fn new(waveform: Waveform, frequency: f32, volume: f32) -> Self {
+        Buzzer {
+            waveform,
+            frequency,
+            volume: volume.clamp(0.0, 1.0),
+            muted: false,
+            phase: 0.0,
+            queue: VecDeque::new(),
+            recording: None,
+        }
+    }
+
+    // Reads the [audio] section of the config, anything missing falls back to a quiet 440Hz square
+    pub fn from_config(config: &Config) -> Self {
+        let waveform = match config.get("audio", "waveform") {
+            Some(name) => Waveform::from_name(name).unwrap_or_else(|| {
+                eprintln!("[audio] Unknown waveform {name}, expected square, sine or triangle");
+                Waveform::Square
+            }),
+            None => Waveform::Square,
+        };
+        let frequency = config.get_parsed("audio", "frequency").unwrap_or(440.0);
+        let volume = config.get_parsed("audio", "volume").unwrap_or(0.25);
+
+        let mut buzzer = Buzzer::new(waveform, frequency, volume);
+        buzzer.muted = config.get_parsed("audio", "muted").unwrap_or(false);
+        buzzer
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    // Generates a single 60Hz frame of audio, `active` should be whether the sound timer is
+    // non-zero for this frame.
+    pub fn render_frame(&mut self, active: bool) {
+        let step = self.frequency / SAMPLE_RATE as f32;
+        for _ in 0..SAMPLES_PER_FRAME {
+            let sample = if active {
+                let value = self.waveform.sample(self.phase) * self.volume;
+                self.phase = (self.phase + step).fract();
+                (value * i16::MAX as f32) as i16
+            } else {
+                // Restarting the wave from the same point every time keeps each beep identical
+                self.phase = 0.0;
+                0
+            };
+            // Muting only silences the speaker, a recording still gets every beep
+            self.queue.push_back(if self.muted { 0 } else { sample });
+            if let Some(recording) = self.recording.as_mut() {
+                recording.push(sample);
+            }
+        }
+
+        while self.queue.len() > MAX_QUEUED_SAMPLES {
+            self.queue.pop_front();
+        }
+    }
+
+    // Hands over up to `count` queued samples, padding with silence if the emulator is behind
+    pub fn take_samples(&mut self, count: usize) -> Vec<i16> {
+        let mut samples: Vec<i16> = Vec::with_capacity(count);
+        while samples.len() < count {
+            samples.push(self.queue.pop_front().unwrap_or(0));
+        }
+        samples
+    }
+
+    pub fn save_recording(&self, path: &Path) -> std::io::Result<()> {
+        match &self.recording {
+            Some(samples) => write_wav(path, samples, SAMPLE_RATE),
+            None => Ok(()),
+        }
+    }
+}
+
+// Writes 16 bit mono PCM, which is about as simple as a WAV file gets
+pub fn write_wav(path: &Path, samples: &[i16], sample_rate: u32) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let data_size = (samples.len() * 2) as u32;
+
+    file.write_all(b"RIFF")?;
+    file.write_all(&(36 + data_size).to_le_bytes())?;
+    file.write_all(b"WAVE")?;
+
+    file.write_all(b"fmt ")?;
+    file.write_all(&16u32.to_le_bytes())?; // Size of the fmt chunk
+    file.write_all(&1u16.to_le_bytes())?; // PCM
+    file.write_all(&1u16.to_le_bytes())?; // Mono
+    file.write_all(&sample_rate.to_le_bytes())?;
+    file.write_all(&(sample_rate * 2).to_le_bytes())?; // Bytes per second
+    file.write_all(&2u16.to_le_bytes())?; // Bytes per sample
+    file.write_all(&16u16.to_le_bytes())?; // Bits per sample
+
+    file.write_all(b"data")?;
+    file.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        file.write_all(&sample.to_le_bytes())?;
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(buzzer: &mut Buzzer, active: bool) -> Vec<i16> {
+        buzzer.render_frame(active);
+        buzzer.take_samples(buzzer.queue.len())
+    }
+
+    #[test]
+    fn a_frame_is_a_sixtieth_of_a_second() {
+        let mut buzzer = Buzzer::new(Waveform::Square, 440.0, 0.5);
+        buzzer.render_frame(true);
+        assert_eq!(buzzer.queue.len(), SAMPLES_PER_FRAME);
+        assert_eq!(SAMPLES_PER_FRAME, 735);
+    }
+
+    #[test]
+    fn silent_while_the_timer_is_off() {
+        let mut buzzer = Buzzer::new(Waveform::Square, 440.0, 0.5);
+        assert!(frame(&mut buzzer, false).iter().all(|sample| *sample == 0));
+        assert!(frame(&mut buzzer, true).iter().any(|sample| *sample != 0));
+    }
+
+    #[test]
+    fn every_beep_starts_the_wave_again() {
+        let mut buzzer = Buzzer::new(Waveform::Sine, 440.0, 0.5);
+        let first = frame(&mut buzzer, true);
+        // A frame of 440Hz is seven and a third periods, so carrying straight on starts partway in
+        let carried_on = frame(&mut buzzer, true);
+        frame(&mut buzzer, false);
+        assert_eq!(frame(&mut buzzer, true), first);
+        assert_ne!(carried_on, first);
+    }
+
+    #[test]
+    fn muting_still_records() {
+        let mut buzzer = Buzzer::new(Waveform::Square, 440.0, 0.5);
+        buzzer.muted = true;
+        buzzer.start_recording();
+        assert!(frame(&mut buzzer, true).iter().all(|sample| *sample == 0));
+        let recording = buzzer.recording.as_ref().unwrap();
+        assert_eq!(recording.len(), SAMPLES_PER_FRAME);
+        assert!(recording.iter().any(|sample| *sample != 0));
+    }
+
+    #[test]
+    fn wav_header() {
+        let path = std::env::temp_dir().join(format!("chipp-test-{}.wav", std::process::id()));
+        write_wav(&path, &[1, -2, 3], 22050).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u16_at = |at: usize| u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap());
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + 6);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(16), 16);
+        assert_eq!(u16_at(20), 1);
+        assert_eq!(u16_at(22), 1);
+        assert_eq!(u32_at(24), 22050);
+        assert_eq!(u32_at(28), 44100);
+        assert_eq!(u16_at(32), 2);
+        assert_eq!(u16_at(34), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(40), 6);
+        assert_eq!(&bytes[44..], &[1, 0, 0xFE, 0xFF, 3, 0]);
+    }
+}
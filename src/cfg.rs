@@ -0,0 +1,473 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::cpu::{Instruction, STACK_LEVELS};
+use crate::decode::Op;
+use crate::disasm::{BasicBlock, PROGRAM_START, find_blocks, mnemonic};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    // Runs on into the next instruction, which is also where a skip goes when it doesn't skip
+    Next,
+    // A skip that's taken, over the next instruction
+    Skip,
+    Jump,
+    // 2NNN, into the subroutine
+    Call,
+    // From a 2NNN to where its subroutine's 00EE comes back to
+    Return,
+    // BNNN, which can't be followed without knowing V0
+    Computed,
+}
+
+pub struct Edge {
+    pub from: u16,
+    // None for BNNN
+    pub to: Option<u16>,
+    pub kind: EdgeKind,
+}
+
+// The ROM's basic blocks and how execution can get from one to another
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+    // Which of the edges leave each block, they're added a block at a time
+    outgoing: Vec<Range<usize>>,
+    // The blocks each block can go on to without going into a subroutine. A call is taken to come
+    // back, so it goes straight to the instruction after it.
+    successors: Vec<Vec<usize>>,
+    rom_len: usize,
+}
+
+impl Cfg {
+    pub fn build(rom: &[u8]) -> Self {
+        let blocks = find_blocks(rom);
+        let mut edges = Vec::new();
+        let mut outgoing = Vec::new();
+        for block in &blocks {
+            let first = edges.len();
+            let last = block.opcodes.last().copied().unwrap_or(0);
+            let from = block.start;
+            let next = block.end() & 0xFFF;
+            let mut edge = |to: Option<u16>, kind: EdgeKind| edges.push(Edge { from, to, kind });
+            match Op::decode(&Instruction::from_opcode(last)) {
+                Op::Jump { nnn } => edge(Some(nnn), EdgeKind::Jump),
+                Op::Call { nnn } => {
+                    edge(Some(nnn), EdgeKind::Call);
+                    edge(Some(next), EdgeKind::Return);
+                }
+                Op::Return => {}
+                Op::JumpWithOffset { .. } => edge(None, EdgeKind::Computed),
+                Op::SkipIfEqual { .. }
+                | Op::SkipIfNotEqual { .. }
+                | Op::SkipIfRegistersEqual { .. }
+                | Op::SkipIfRegistersNotEqual { .. }
+                | Op::SkipIfKey { .. }
+                | Op::SkipIfNotKey { .. } => {
+                    edge(Some(next), EdgeKind::Next);
+                    edge(Some((next + 2) & 0xFFF), EdgeKind::Skip);
+                }
+                _ => edge(Some(next), EdgeKind::Next),
+            }
+            outgoing.push(first..edges.len());
+        }
+        let mut cfg = Cfg {
+            blocks,
+            edges,
+            outgoing,
+            successors: Vec::new(),
+            rom_len: rom.len(),
+        };
+        cfg.successors = (0..cfg.blocks.len())
+            .map(|index| {
+                cfg.edges_from(index)
+                    .iter()
+                    .filter(|edge| edge.kind != EdgeKind::Call)
+                    .filter_map(|edge| edge.to.and_then(|to| cfg.block_index(to)))
+                    .collect()
+            })
+            .collect();
+        cfg
+    }
+
+    fn block_index(&self, address: u16) -> Option<usize> {
+        self.blocks
+            .binary_search_by_key(&address, |block| block.start)
+            .ok()
+    }
+
+    fn edges_from(&self, index: usize) -> &[Edge] {
+        &self.edges[self.outgoing[index].clone()]
+    }
+
+    // Anything the code can't reach, as ranges of addresses. Usually sprites and other data, but
+    // code only reached through BNNN turns up here too.
+    pub fn unreachable_regions(&self) -> Vec<(u16, u16)> {
+        let mut reached = vec![false; self.rom_len];
+        for block in &self.blocks {
+            let start = (block.start - PROGRAM_START) as usize;
+            let end = ((block.end() - PROGRAM_START) as usize).min(self.rom_len);
+            reached[start..end].fill(true);
+        }
+        let mut regions = Vec::new();
+        let mut offset = 0;
+        while offset < self.rom_len {
+            if reached[offset] {
+                offset += 1;
+                continue;
+            }
+            let start = offset;
+            while offset < self.rom_len && !reached[offset] {
+                offset += 1;
+            }
+            regions.push((
+                PROGRAM_START + start as u16,
+                PROGRAM_START + offset as u16 - 1,
+            ));
+        }
+        regions
+    }
+
+    // Every subroutine (and the program itself, at 0x200) with the subroutines it calls
+    fn call_graph(&self) -> BTreeMap<u16, BTreeSet<u16>> {
+        let mut entries: BTreeSet<u16> = self
+            .edges
+            .iter()
+            .filter(|edge| edge.kind == EdgeKind::Call)
+            .filter_map(|edge| edge.to)
+            .collect();
+        entries.insert(PROGRAM_START);
+
+        let mut graph = BTreeMap::new();
+        for entry in entries {
+            let mut callees = BTreeSet::new();
+            if let Some(index) = self.block_index(entry) {
+                for body in self.reachable_from(index) {
+                    callees.extend(
+                        self.edges_from(body)
+                            .iter()
+                            .filter(|edge| edge.kind == EdgeKind::Call)
+                            .filter_map(|edge| edge.to),
+                    );
+                }
+            }
+            graph.insert(entry, callees);
+        }
+        graph
+    }
+
+    // The block itself and everything it can get to
+    fn reachable_from(&self, index: usize) -> Vec<usize> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut pending = vec![index];
+        let mut reached = Vec::new();
+        while let Some(index) = pending.pop() {
+            if std::mem::replace(&mut seen[index], true) {
+                continue;
+            }
+            reached.push(index);
+            pending.extend(&self.successors[index]);
+        }
+        reached
+    }
+
+    // The longest chain of calls from 0x200, or the first subroutine found that can end up
+    // calling itself, which no stack is deep enough for
+    pub fn deepest_calls(&self) -> CallDepth {
+        let graph = self.call_graph();
+        let mut chain = Vec::new();
+        let mut deepest = BTreeMap::new();
+        match deepest_from(PROGRAM_START, &graph, &mut chain, &mut deepest) {
+            Ok(chain) => CallDepth::Bounded(chain),
+            Err(cycle) => CallDepth::Recursive(cycle),
+        }
+    }
+
+    // Loops with no way out whatever the registers hold: nothing in them skips, jumps or returns
+    // to anywhere outside the loop. A game's main loop usually looks like this, and so does a
+    // jump to itself at the end of a program.
+    pub fn infinite_loops(&self) -> Vec<Vec<u16>> {
+        let reach: Vec<Vec<bool>> = (0..self.blocks.len())
+            .map(|index| {
+                // Reached again from one of its successors, so a block only reaches itself when
+                // it's part of a loop
+                let mut reached = vec![false; self.blocks.len()];
+                for &next in &self.successors[index] {
+                    for block in self.reachable_from(next) {
+                        reached[block] = true;
+                    }
+                }
+                reached
+            })
+            .collect();
+
+        let mut assigned = vec![false; self.blocks.len()];
+        let mut loops = Vec::new();
+        for index in 0..self.blocks.len() {
+            if assigned[index] || !reach[index][index] {
+                continue;
+            }
+            let members: Vec<usize> = (0..self.blocks.len())
+                .filter(|other| reach[index][*other] && reach[*other][index])
+                .collect();
+            for member in &members {
+                assigned[*member] = true;
+            }
+            let escapes = members.iter().any(|member| {
+                let block = &self.blocks[*member];
+                let last = block.opcodes.last().copied().unwrap_or(0);
+                Op::decode(&Instruction::from_opcode(last)) == Op::Return
+                    || self.edges_from(*member).iter().any(|edge| {
+                        edge.kind != EdgeKind::Call
+                            && edge
+                                .to
+                                .and_then(|to| self.block_index(to))
+                                .is_none_or(|to| !members.contains(&to))
+                    })
+            });
+            if !escapes {
+                loops.push(
+                    members
+                        .iter()
+                        .map(|member| self.blocks[*member].start)
+                        .collect(),
+                );
+            }
+        }
+        loops
+    }
+
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let graph = self.call_graph();
+        lines.push(format!(
+            "{} blocks, {} edges, {} subroutines",
+            self.blocks.len(),
+            self.edges.len(),
+            graph.len() - 1
+        ));
+        let computed: Vec<String> = self
+            .edges
+            .iter()
+            .filter(|edge| edge.kind == EdgeKind::Computed)
+            .map(|edge| format!("{:#05X}", self.end_of(edge.from)))
+            .collect();
+        if !computed.is_empty() {
+            lines.push(format!(
+                "Computed jumps that can't be followed: {}",
+                computed.join(", ")
+            ));
+        }
+
+        lines.push(String::new());
+        let regions = self.unreachable_regions();
+        if regions.is_empty() {
+            lines.push("Everything is reachable from the start".to_string());
+        } else {
+            lines.push("Unreachable, most likely data:".to_string());
+            for (start, end) in regions {
+                lines.push(format!(
+                    "  {start:#05X} to {end:#05X}  {} bytes",
+                    end - start + 1
+                ));
+            }
+        }
+
+        lines.push(String::new());
+        match self.deepest_calls() {
+            CallDepth::Bounded(chain) if chain.len() == 1 => {
+                lines.push("Deepest calls: none, nothing calls a subroutine".to_string());
+            }
+            CallDepth::Bounded(chain) => {
+                let depth = chain.len() - 1;
+                let fits = if depth <= STACK_LEVELS {
+                    format!("the stack has room for {STACK_LEVELS}")
+                } else {
+                    format!("more than the {STACK_LEVELS} the stack has room for")
+                };
+                lines.push(format!(
+                    "Deepest calls: {depth} levels, {fits}: {}",
+                    address_chain(&chain)
+                ));
+            }
+            CallDepth::Recursive(cycle) => lines.push(format!(
+                "Deepest calls: no limit, these subroutines can call themselves: {}",
+                address_chain(&cycle)
+            )),
+        }
+
+        lines.push(String::new());
+        let loops = self.infinite_loops();
+        if loops.is_empty() {
+            lines.push("No loops without a way out".to_string());
+        } else {
+            lines.push("Loops without a way out:".to_string());
+            for blocks in loops {
+                let start = blocks[0];
+                let end = self.end_of(*blocks.last().unwrap_or(&start));
+                let range = if start == end {
+                    format!("{start:#05X}")
+                } else {
+                    format!("{start:#05X} to {end:#05X}")
+                };
+                if blocks.len() == 1 {
+                    lines.push(format!("  {range}  goes back to itself"));
+                } else {
+                    lines.push(format!("  {range}  {} blocks", blocks.len()));
+                }
+            }
+        }
+        lines
+    }
+
+    // Where the last instruction of the block starting at `start` is
+    fn end_of(&self, start: u16) -> u16 {
+        self.block_index(start)
+            .map_or(start, |index| self.blocks[index].end() - 2)
+    }
+
+    // A box for every block with its instructions in it. Calls are blue, returns dotted and taken
+    // skips dashed, anything outside the ROM gets a dashed box, and BNNN goes off to a question mark.
+    pub fn to_dot(&self, name: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph \"{}\" {{", name.replace('"', "'"));
+        out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        for block in &self.blocks {
+            let mut label = String::new();
+            for (address, opcode) in (block.start..).step_by(2).zip(&block.opcodes) {
+                let _ = write!(label, "{address:#05X}  {}\\l", mnemonic(*opcode));
+            }
+            let _ = writeln!(out, "    b{:03x} [label=\"{label}\"];", block.start);
+        }
+        let missing: BTreeSet<u16> = self
+            .edges
+            .iter()
+            .filter_map(|edge| edge.to)
+            .filter(|to| self.block_index(*to).is_none())
+            .collect();
+        for address in missing {
+            let _ = writeln!(
+                out,
+                "    b{address:03x} [label=\"{address:#05X}\", style=dashed];"
+            );
+        }
+        for edge in &self.edges {
+            let to = match edge.to {
+                Some(to) => format!("b{to:03x}"),
+                None => {
+                    let node = format!("computed_{:03x}", edge.from);
+                    let _ = writeln!(out, "    {node} [label=\"?\", shape=diamond];");
+                    node
+                }
+            };
+            let style = match edge.kind {
+                EdgeKind::Next | EdgeKind::Jump => "",
+                EdgeKind::Skip => " [label=\"skip\", style=dashed]",
+                EdgeKind::Call => " [label=\"call\", color=blue]",
+                EdgeKind::Return => " [label=\"returns\", style=dotted]",
+                EdgeKind::Computed => " [label=\"BNNN\", style=dashed]",
+            };
+            let _ = writeln!(out, "    b{:03x} -> {to}{style};", edge.from);
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+pub enum CallDepth {
+    // From 0x200 down to the deepest subroutine
+    Bounded(Vec<u16>),
+    // A subroutine through to where it gets called again
+    Recursive(Vec<u16>),
+}
+
+fn deepest_from(
+    entry: u16,
+    graph: &BTreeMap<u16, BTreeSet<u16>>,
+    chain: &mut Vec<u16>,
+    deepest: &mut BTreeMap<u16, Vec<u16>>,
+) -> Result<Vec<u16>, Vec<u16>> {
+    if let Some(position) = chain.iter().position(|caller| *caller == entry) {
+        let mut cycle = chain[position..].to_vec();
+        cycle.push(entry);
+        return Err(cycle);
+    }
+    if let Some(known) = deepest.get(&entry) {
+        return Ok(known.clone());
+    }
+    chain.push(entry);
+    let mut longest = Vec::new();
+    for callee in graph.get(&entry).into_iter().flatten() {
+        let below = deepest_from(*callee, graph, chain, deepest)?;
+        if below.len() > longest.len() {
+            longest = below;
+        }
+    }
+    chain.pop();
+    longest.insert(0, entry);
+    deepest.insert(entry, longest.clone());
+    Ok(longest)
+}
+
+fn address_chain(addresses: &[u16]) -> String {
+    addresses
+        .iter()
+        .map(|address| format!("{address:#05X}"))
+        .collect::<Vec<_>>()
+        .join(" > ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Calls two subroutines deep, the second of which counts to ten, then sits in a loop forever.
+    // There's a sprite after the loop that nothing runs.
+    const ROM: [u8; 20] = [
+        0x22, 0x08, // 200: call 208
+        0x60, 0x01, // 202: V0 = 1
+        0x12, 0x02, // 204: jump 202
+        0xF0, 0x90, // 206: sprite
+        0x22, 0x0C, // 208: call 20C
+        0x00, 0xEE, // 20A: return
+        0x70, 0x01, // 20C: V0 += 1
+        0x30, 0x0A, // 20E: skip if V0 == 10
+        0x12, 0x0C, // 210: jump 20C
+        0x00, 0xEE, // 212: return
+    ];
+
+    #[test]
+    fn data_nothing_runs_is_unreachable() {
+        assert_eq!(Cfg::build(&ROM).unreachable_regions(), [(0x206, 0x207)]);
+    }
+
+    #[test]
+    fn deepest_calls_follow_the_longest_chain() {
+        match Cfg::build(&ROM).deepest_calls() {
+            CallDepth::Bounded(chain) => assert_eq!(chain, [0x200, 0x208, 0x20C]),
+            CallDepth::Recursive(cycle) => panic!("recursive through {cycle:X?}"),
+        }
+    }
+
+    #[test]
+    fn a_subroutine_calling_itself_is_recursive() {
+        let rom = [
+            0x22, 0x04, // 200: call 204
+            0x12, 0x02, // 202: jump 202
+            0x30, 0x01, // 204: skip if V0 == 1
+            0x22, 0x04, // 206: call 204
+            0x00, 0xEE, // 208: return
+        ];
+        match Cfg::build(&rom).deepest_calls() {
+            CallDepth::Bounded(chain) => panic!("bounded at {chain:X?}"),
+            CallDepth::Recursive(cycle) => assert_eq!(cycle, [0x204, 0x204]),
+        }
+    }
+
+    #[test]
+    fn only_loops_with_no_way_out_are_endless() {
+        // The counting loop at 20C skips its way out, the one at 202 never leaves
+        assert_eq!(Cfg::build(&ROM).infinite_loops(), [vec![0x202]]);
+    }
+}
@@ -0,0 +1,518 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::palette::parse_hex_colour;
+use crate::quirks::{Platform, Quirks};
+use crate::watcher::ReloadMode;
+
+pub const USAGE: &str = "\
+C8-Emu, a CHIP-8 interpreter
+
+Usage:
+    chipp [run] [rom] [options]    Run a ROM, or pick one in the launcher if there isn't one
+    chipp disasm <rom> [options]   Print a disassembly of a ROM
+    chipp asm <source> [-o <rom>]  Assemble Octo source into a ROM, with a symbol file next to it.
+                                   A .8o can also be given straight to run
+    chipp cfg <rom> [--dot <path>] Work out the control flow graph, report what can't be reached,
+                                   how deep the calls go and loops with no way out
+    chipp info <rom>               Print what we know about a ROM, including the ROM database
+    chipp transpile <rom>          Print the ROM translated into a Rust module
+    chipp lockstep <rom> [options] Run the recompiler next to the interpreter and check they agree
+    chipp bench [rom] [options]    Measure how fast a ROM, or the built in workloads, run without
+                                   drawing anything
+    chipp help                     Show this message
+
+Options:
+    --ipf <n>            Instructions to run every frame
+    --timing <mode>      ipf or vip, vip runs every instruction at the speed of a COSMAC VIP
+    --platform <name>    chip8, schip or xochip, picks the default quirks instead of the ROM
+                         database (default chip8 for ROMs it doesn't know)
+    --quirks <list>      Override quirks, e.g. --quirks shifting=on,clipping=off
+                         Quirks: vf-reset, memory, display-wait, clipping, shifting, jumping
+    --scale <n>          Size of a CHIP-8 pixel on screen (default 10)
+    --palette <name>     classic, green, amber, mono, high-contrast or one from the config.
+                         Can also be two hex colours, e.g. --palette 33FF66,001100
+    --headless           Run without opening a window and print the screen at the end
+    --terminal           Play in the terminal instead of a window, handy over SSH
+    --frames <n>         How many frames to run for with --headless and lockstep (default 600)
+    --instructions <n>   How many instructions to run for with bench (default 10000000)
+    --min-mips <n>       Make bench fail if anything runs slower than this many million
+                         instructions per second
+    --recompile          Run straight lines of code as compiled blocks, for bulk runs where speed
+                         is all that matters
+    --start-paused       Start in step mode, press space to run one instruction
+    --seed <n>           Seed the random number generator so runs are repeatable
+    --trace              Print every instruction as it runs
+    --record <path>      Record the game from the start. The format comes from the extension, .gif
+                         or .y4m, anything else is a folder of PNGs
+    --record-movie <path>  Save the keys pressed on every frame so the run can be replayed exactly
+    --play-movie <path>  Replay a movie instead of reading the keyboard, and check it ends the same
+    --profile <path>     Count what every address and subroutine costs, print the hottest ones at
+                         the end and save the calls as a trace for chrome://tracing or Perfetto
+    --coverage <path>    Save which bytes ran as code, were read as data or were written, and where
+                         the program wrote over its own code. Given to disasm, reads one back to
+                         tell code from data by what actually ran
+    --symbols <path>     Labels and source lines for the ROM, from a symbol file. A .sym file next
+                         to the ROM gets picked up without this
+    --break <where>      Drop into step mode before running a label or address, can be given more
+                         than once
+    --watch              Reload the ROM whenever the file changes, for an edit and see loop
+    --on-reload <mode>   What --watch does after reloading: reset to start over (default), state to
+                         go back to the quick save slot, or replay to play the keys pressed so far
+    --linear             Disassemble every two bytes instead of following the code
+    --dot <path>         Save the control flow graph for Graphviz as well
+    -o, --output <path>  Where asm puts the ROM (default the source with a .ch8 extension)
+    -h, --help           Show this message
+
+Exit codes:
+    0  Everything went fine
+    1  Something went wrong running the ROM
+    2  The arguments didn't make sense";
+
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    // Empty when the launcher is going to pick one
+    pub rom: String,
+    pub instructions_per_frame: Option<u32>,
+    pub vip_timing: Option<bool>,
+    pub platform: Option<Platform>,
+    pub quirk_overrides: Vec<(String, bool)>,
+    pub scale: i32,
+    pub palette: Option<PaletteArg>,
+    pub headless: bool,
+    pub terminal: bool,
+    pub frames: u32,
+    pub instructions: u64,
+    pub min_mips: Option<f64>,
+    pub recompile: bool,
+    pub start_paused: bool,
+    pub seed: Option<u64>,
+    pub trace: bool,
+    pub linear: bool,
+    pub dot: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub record_movie: Option<PathBuf>,
+    pub play_movie: Option<PathBuf>,
+    pub profile: Option<PathBuf>,
+    pub coverage: Option<PathBuf>,
+    pub symbols: Option<PathBuf>,
+    pub breakpoints: Vec<String>,
+    pub watch: bool,
+    pub reload_mode: ReloadMode,
+}
+
+impl RunOptions {
+    fn new(rom: String) -> Self {
+        RunOptions {
+            rom,
+            instructions_per_frame: None,
+            vip_timing: None,
+            platform: None,
+            quirk_overrides: Vec::new(),
+            scale: 10,
+            palette: None,
+            headless: false,
+            terminal: false,
+            frames: 600,
+            instructions: 10_000_000,
+            min_mips: None,
+            recompile: false,
+            start_paused: false,
+            seed: None,
+            trace: false,
+            linear: false,
+            dot: None,
+            output: None,
+            record: None,
+            record_movie: None,
+            play_movie: None,
+            profile: None,
+            coverage: None,
+            symbols: None,
+            breakpoints: Vec::new(),
+            watch: false,
+            reload_mode: ReloadMode::Reset,
+        }
+    }
+
+    // The quirks of --platform if one was given, otherwise whatever the ROM database says, with
+    // anything passed to --quirks on top
+    pub fn quirks(&self, database_quirks: Option<Quirks>) -> Quirks {
+        let mut quirks = match (self.platform, database_quirks) {
+            (Some(platform), _) => platform.default_quirks(),
+            (None, Some(quirks)) => quirks,
+            (None, None) => Platform::Chip8.default_quirks(),
+        };
+        for (name, value) in &self.quirk_overrides {
+            // These were already checked when the arguments were parsed
+            let _ = quirks.set(name, *value);
+        }
+        quirks
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PaletteArg {
+    // Checked against the palettes once the config has been loaded
+    Named(String),
+    Colours([u8; 3], [u8; 3]),
+}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Run(RunOptions),
+    Disasm(RunOptions),
+    Cfg(RunOptions),
+    Asm(RunOptions),
+    Info(RunOptions),
+    Transpile(RunOptions),
+    Lockstep(RunOptions),
+    Bench(RunOptions),
+    Help,
+}
+
+#[derive(Debug, Clone)]
+pub struct CliError(pub String);
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// `args` shouldn't include the program name. Running `chipp <rom>` with no subcommand still works
+// the way it always has.
+pub fn parse_args(args: &[String]) -> Result<Command, CliError> {
+    // No ROM at all opens the launcher
+    let Some(first) = args.first() else {
+        return Ok(Command::Run(RunOptions::new(String::new())));
+    };
+
+    let (command, rest): (fn(RunOptions) -> Command, &[String]) = match first.as_str() {
+        "help" | "-h" | "--help" => return Ok(Command::Help),
+        "run" => (Command::Run, &args[1..]),
+        "disasm" => (Command::Disasm, &args[1..]),
+        "cfg" => (Command::Cfg, &args[1..]),
+        "asm" => (Command::Asm, &args[1..]),
+        "info" => (Command::Info, &args[1..]),
+        "transpile" => (Command::Transpile, &args[1..]),
+        "lockstep" => (Command::Lockstep, &args[1..]),
+        "bench" => (Command::Bench, &args[1..]),
+        _ => (Command::Run, args),
+    };
+
+    let mut rom: Option<String> = None;
+    let mut options = RunOptions::new(String::new());
+    let mut remaining = rest.iter();
+
+    while let Some(arg) = remaining.next() {
+        // Both `--ipf 20` and `--ipf=20` are fine
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = |name: &str| -> Result<String, CliError> {
+            inline_value
+                .clone()
+                .or_else(|| remaining.next().cloned())
+                .ok_or_else(|| CliError(format!("{name} needs a value")))
+        };
+
+        match flag {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--ipf" => options.instructions_per_frame = Some(parse_number(flag, &value(flag)?)?),
+            "--timing" => {
+                options.vip_timing = match value(flag)?.as_str() {
+                    "vip" => Some(true),
+                    "ipf" => Some(false),
+                    other => return Err(CliError(format!("Unknown timing mode {other}"))),
+                }
+            }
+            "--platform" => {
+                let name = value(flag)?;
+                options.platform = Some(
+                    Platform::from_name(&name)
+                        .ok_or_else(|| CliError(format!("Unknown platform {name}")))?,
+                );
+            }
+            "--quirks" => options.quirk_overrides.extend(parse_quirks(&value(flag)?)?),
+            "--scale" => {
+                options.scale = parse_number(flag, &value(flag)?)?;
+                if options.scale < 1 {
+                    return Err(CliError("--scale has to be at least 1".to_string()));
+                }
+            }
+            "--palette" => options.palette = Some(parse_palette(&value(flag)?)?),
+            "--headless" => options.headless = true,
+            "--terminal" => options.terminal = true,
+            "--frames" => options.frames = parse_number(flag, &value(flag)?)?,
+            "--instructions" => options.instructions = parse_number(flag, &value(flag)?)?,
+            "--min-mips" => options.min_mips = Some(parse_number(flag, &value(flag)?)?),
+            "--recompile" => options.recompile = true,
+            "--start-paused" => options.start_paused = true,
+            "--seed" => options.seed = Some(parse_number(flag, &value(flag)?)?),
+            "--trace" => options.trace = true,
+            "--linear" => options.linear = true,
+            "--dot" => options.dot = Some(PathBuf::from(value(flag)?)),
+            "-o" | "--output" => options.output = Some(PathBuf::from(value(flag)?)),
+            "--record" => options.record = Some(PathBuf::from(value(flag)?)),
+            "--record-movie" => options.record_movie = Some(PathBuf::from(value(flag)?)),
+            "--play-movie" => options.play_movie = Some(PathBuf::from(value(flag)?)),
+            "--profile" => options.profile = Some(PathBuf::from(value(flag)?)),
+            "--coverage" => options.coverage = Some(PathBuf::from(value(flag)?)),
+            "--symbols" => options.symbols = Some(PathBuf::from(value(flag)?)),
+            "--break" => options.breakpoints.push(value(flag)?),
+            "--watch" => options.watch = true,
+            "--on-reload" => {
+                let name = value(flag)?;
+                options.reload_mode = ReloadMode::from_name(&name)
+                    .ok_or_else(|| CliError(format!("Unknown reload mode {name}")))?;
+            }
+            _ if flag.starts_with('-') => return Err(CliError(format!("Unknown option {flag}"))),
+            _ if rom.is_none() => rom = Some(arg.clone()),
+            _ => return Err(CliError(format!("Unexpected argument {arg}"))),
+        }
+    }
+
+    // Only the window has a launcher to pick a ROM with, and bench has its own workloads, everything
+    // else needs one up front
+    let windowed = !matches!(
+        first.as_str(),
+        "disasm" | "cfg" | "asm" | "info" | "transpile" | "lockstep" | "bench"
+    ) && !options.headless
+        && !options.terminal
+        && options.play_movie.is_none();
+    match rom {
+        Some(rom) => options.rom = rom,
+        None if windowed || first == "bench" => {}
+        None => return Err(CliError("No ROM given".to_string())),
+    }
+    if options.record_movie.is_some() && options.play_movie.is_some() {
+        return Err(CliError(
+            "Can't record a movie and play one at the same time".to_string(),
+        ));
+    }
+    // A reload would leave a movie with half its frames on one ROM and half on another
+    if options.watch && (options.record_movie.is_some() || options.play_movie.is_some()) {
+        return Err(CliError(
+            "Can't watch the ROM while recording or playing a movie".to_string(),
+        ));
+    }
+    Ok(command(options))
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, CliError> {
+    value
+        .parse()
+        .map_err(|_| CliError(format!("{flag} expects a number, got {value}")))
+}
+
+// e.g. "shifting=on,clipping=off"
+fn parse_quirks(list: &str) -> Result<Vec<(String, bool)>, CliError> {
+    let mut quirks = Vec::new();
+    for entry in list.split(',').filter(|entry| !entry.is_empty()) {
+        let (name, value) = entry
+            .split_once('=')
+            .ok_or_else(|| CliError(format!("Expected quirk=on or quirk=off, got {entry}")))?;
+        let value = match value {
+            "on" | "true" | "1" => true,
+            "off" | "false" | "0" => false,
+            _ => return Err(CliError(format!("Quirk {name} should be on or off"))),
+        };
+        // Check the name now so a typo doesn't get silently ignored
+        Platform::Chip8
+            .default_quirks()
+            .set(name, value)
+            .map_err(CliError)?;
+        quirks.push((name.to_string(), value));
+    }
+    Ok(quirks)
+}
+
+// Either a palette name or "33FF66,001100", foreground first
+fn parse_palette(value: &str) -> Result<PaletteArg, CliError> {
+    match value.split_once(',') {
+        Some((foreground, background)) => Ok(PaletteArg::Colours(
+            parse_colour(foreground)?,
+            parse_colour(background)?,
+        )),
+        None => Ok(PaletteArg::Named(value.to_string())),
+    }
+}
+
+fn parse_colour(hex: &str) -> Result<[u8; 3], CliError> {
+    parse_hex_colour(hex).ok_or_else(|| {
+        CliError(format!(
+            "{} isn't a colour, expected something like FF8800",
+            hex.trim()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, CliError> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse_args(&args)
+    }
+
+    fn run_options(args: &[&str]) -> RunOptions {
+        match parse(args) {
+            Ok(Command::Run(options)) => options,
+            other => panic!("{args:?} gave {other:?}"),
+        }
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parse(args) {
+            Err(CliError(message)) => message,
+            Ok(command) => panic!("{args:?} was accepted as {command:?}"),
+        }
+    }
+
+    #[test]
+    fn a_bare_rom_is_run() {
+        let options = run_options(&["pong.ch8"]);
+        assert_eq!(options.rom, "pong.ch8");
+        assert_eq!(options.scale, 10);
+        assert_eq!(options.frames, 600);
+        assert!(options.platform.is_none());
+        assert!(!options.headless);
+    }
+
+    #[test]
+    fn no_rom_opens_the_launcher_only_in_the_window() {
+        assert_eq!(run_options(&[]).rom, "");
+        assert_eq!(run_options(&["--scale", "4"]).rom, "");
+        assert_eq!(error(&["--headless"]), "No ROM given");
+        assert_eq!(error(&["--terminal"]), "No ROM given");
+        assert_eq!(error(&["disasm"]), "No ROM given");
+    }
+
+    #[test]
+    fn subcommands_pick_the_command() {
+        assert!(matches!(parse(&["run", "a.ch8"]), Ok(Command::Run(_))));
+        assert!(matches!(
+            parse(&["disasm", "a.ch8"]),
+            Ok(Command::Disasm(_))
+        ));
+        assert!(matches!(parse(&["cfg", "a.ch8"]), Ok(Command::Cfg(_))));
+        assert!(matches!(parse(&["asm", "a.8o"]), Ok(Command::Asm(_))));
+        assert!(matches!(parse(&["info", "a.ch8"]), Ok(Command::Info(_))));
+        assert!(matches!(
+            parse(&["transpile", "a.ch8"]),
+            Ok(Command::Transpile(_))
+        ));
+        assert!(matches!(
+            parse(&["lockstep", "a.ch8"]),
+            Ok(Command::Lockstep(_))
+        ));
+        assert!(matches!(parse(&["bench"]), Ok(Command::Bench(_))));
+        assert!(matches!(parse(&["help"]), Ok(Command::Help)));
+        assert!(matches!(parse(&["a.ch8", "--help"]), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn values_can_follow_or_use_equals() {
+        let options = run_options(&["a.ch8", "--ipf", "20", "--frames=30", "--seed=7"]);
+        assert_eq!(options.instructions_per_frame, Some(20));
+        assert_eq!(options.frames, 30);
+        assert_eq!(options.seed, Some(7));
+
+        let options = run_options(&["--timing", "vip", "--scale=3", "a.ch8", "--headless"]);
+        assert_eq!(options.vip_timing, Some(true));
+        assert_eq!(options.scale, 3);
+        assert!(options.headless);
+    }
+
+    #[test]
+    fn palettes_are_names_or_two_colours() {
+        let options = run_options(&["a.ch8", "--palette", "amber"]);
+        assert!(matches!(options.palette, Some(PaletteArg::Named(name)) if name == "amber"));
+
+        let options = run_options(&["a.ch8", "--palette", "#33FF66,001100"]);
+        assert!(matches!(
+            options.palette,
+            Some(PaletteArg::Colours([0x33, 0xFF, 0x66], [0x00, 0x11, 0x00]))
+        ));
+    }
+
+    #[test]
+    fn bad_arguments_are_explained() {
+        assert_eq!(error(&["a.ch8", "--fast"]), "Unknown option --fast");
+        assert_eq!(error(&["a.ch8", "b.ch8"]), "Unexpected argument b.ch8");
+        assert_eq!(error(&["a.ch8", "--ipf"]), "--ipf needs a value");
+        assert_eq!(
+            error(&["a.ch8", "--ipf", "lots"]),
+            "--ipf expects a number, got lots"
+        );
+        assert_eq!(
+            error(&["a.ch8", "--timing", "fast"]),
+            "Unknown timing mode fast"
+        );
+        assert_eq!(
+            error(&["a.ch8", "--platform", "eti660"]),
+            "Unknown platform eti660"
+        );
+        assert_eq!(
+            error(&["a.ch8", "--scale", "0"]),
+            "--scale has to be at least 1"
+        );
+        assert_eq!(
+            error(&["a.ch8", "--palette", "33FF66,green"]),
+            "green isn't a colour, expected something like FF8800"
+        );
+        assert_eq!(
+            error(&["a.ch8", "--record-movie", "a.c8m", "--play-movie", "b.c8m"]),
+            "Can't record a movie and play one at the same time"
+        );
+    }
+
+    #[test]
+    fn quirks_are_checked_as_theyre_parsed() {
+        let options = run_options(&["a.ch8", "--quirks", "shifting=on,clipping=off"]);
+        assert_eq!(
+            options.quirk_overrides,
+            [
+                ("shifting".to_string(), true),
+                ("clipping".to_string(), false)
+            ]
+        );
+        assert!(error(&["a.ch8", "--quirks", "wrapping=on"]).starts_with("Unknown quirk wrapping"));
+        assert_eq!(
+            error(&["a.ch8", "--quirks", "shifting=maybe"]),
+            "Quirk shifting should be on or off"
+        );
+        assert_eq!(
+            error(&["a.ch8", "--quirks", "shifting"]),
+            "Expected quirk=on or quirk=off, got shifting"
+        );
+    }
+
+    #[test]
+    fn the_platform_wins_over_the_database_and_quirks_win_over_both() {
+        let superchip = Platform::SuperChip.default_quirks();
+        let xochip = Platform::XoChip.default_quirks();
+
+        let options = run_options(&["a.ch8"]);
+        assert_eq!(options.quirks(None), Platform::Chip8.default_quirks());
+        assert_eq!(options.quirks(Some(xochip)), xochip);
+
+        let options = run_options(&["a.ch8", "--platform", "schip"]);
+        assert_eq!(options.quirks(Some(xochip)), superchip);
+
+        let options = run_options(&["a.ch8", "--platform=schip", "--quirks", "jumping=off"]);
+        let quirks = options.quirks(Some(xochip));
+        assert!(!quirks.jumping);
+        assert_eq!(
+            Quirks {
+                jumping: true,
+                ..quirks
+            },
+            superchip
+        );
+    }
+}
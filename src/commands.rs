@@ -0,0 +1,496 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::backend::NullBackend;
+use crate::bench::{Measurement, WORKLOADS, run_cached, run_recompiled, run_uncached};
+use crate::cfg::Cfg;
+use crate::cli::RunOptions;
+use crate::config::Config;
+use crate::coverage::CoverageExport;
+use crate::cpu::{Cpu, Instruction};
+use crate::disasm::{PROGRAM_START, disassemble, find_code};
+use crate::emulator::Emulator;
+use crate::movie::MovieSession;
+use crate::octo::{assemble_file, is_source};
+use crate::quirks::Platform;
+use crate::recorder::{Recorder, RecordingFormat, RecordingSettings};
+use crate::romdb::{RomDatabase, RomInfo, sha1_hex};
+use crate::symbols::{Symbols, resolve_address};
+use crate::timing::{DEFAULT_INSTRUCTIONS_PER_FRAME, TimingMode, TimingSettings};
+use crate::transpile::{native_blocks, transpile};
+
+// Octo source gets assembled on the way in, so a .8o runs like any other ROM
+pub fn read_rom(path: &str) -> io::Result<Vec<u8>> {
+    if is_source(path) {
+        return assemble_file(Path::new(path)).map(|program| program.bytes);
+    }
+    fs::read(path).map_err(|err| io::Error::new(err.kind(), format!("Couldn't read {path}: {err}")))
+}
+
+// Sets up a cpu with the ROM loaded and everything from the command line applied. If the ROM is in
+// the database we use its quirks unless --platform says otherwise, and hand back the rest of what
+// the database knows so the caller can set up the speed, keys and colours.
+pub fn create_cpu(options: &RunOptions) -> io::Result<(Cpu, Option<RomInfo>)> {
+    let (rom, symbols) = load_rom_and_symbols(options)?;
+    let mut cpu = Cpu::new();
+    cpu.load_program_into_memory(&mut rom.as_slice())?;
+
+    let rom_info = RomDatabase::load().lookup(&cpu.rom_sha1);
+    if let Some(info) = &rom_info {
+        println!(
+            "Found {} in the ROM database ({})",
+            info.title, info.platform
+        );
+    }
+    cpu.quirks = options.quirks(rom_info.as_ref().map(|info| info.quirks));
+    cpu.trace = options.trace;
+    cpu.recompile = options.recompile;
+    cpu.native = native_blocks(&cpu.rom_sha1);
+    cpu.set_step_mode(options.start_paused);
+    if let Some(seed) = options.seed {
+        cpu.seed_rng(seed);
+    }
+    cpu.symbols = symbols;
+    for name in &options.breakpoints {
+        let address = resolve_address(name, cpu.symbols.as_ref())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        cpu.breakpoints.push(address);
+    }
+    Ok((cpu, rom_info))
+}
+
+// Assembling source gives us its symbols as well, though --symbols still gets the last word
+fn load_rom_and_symbols(options: &RunOptions) -> io::Result<(Vec<u8>, Option<Symbols>)> {
+    if is_source(&options.rom) && options.symbols.is_none() {
+        let program = assemble_file(Path::new(&options.rom))?;
+        return Ok((program.bytes, Some(program.symbols)));
+    }
+    Ok((read_rom(&options.rom)?, load_symbols(options)?))
+}
+
+// Symbols asked for with --symbols have to load. The ones found next to the ROM are a bonus, so
+// if they don't load we carry on without them.
+pub fn load_symbols(options: &RunOptions) -> io::Result<Option<Symbols>> {
+    if let Some(path) = &options.symbols {
+        return Symbols::load(path).map(Some).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Couldn't read {}: {err}", path.display()),
+            )
+        });
+    }
+    let path = Symbols::path_for(&options.rom);
+    if !path.exists() {
+        return Ok(None);
+    }
+    match Symbols::load(&path) {
+        Ok(symbols) => Ok(Some(symbols)),
+        Err(err) => {
+            eprintln!(
+                "Couldn't read {}, carrying on without symbols: {err}",
+                path.display()
+            );
+            Ok(None)
+        }
+    }
+}
+
+// The config file sets the speed, unless it doesn't say and the ROM database does. Anything passed
+// on the command line wins over both.
+pub fn resolve_timing(
+    config: &Config,
+    options: &RunOptions,
+    rom_info: Option<&RomInfo>,
+) -> TimingSettings {
+    let mut timing = TimingSettings::from_config(config);
+    if config.get("timing", "ipf").is_none()
+        && matches!(timing.mode, TimingMode::InstructionsPerFrame(_))
+        && let Some(tickrate) = rom_info.and_then(|info| info.tickrate)
+    {
+        timing.mode = TimingMode::InstructionsPerFrame(tickrate);
+    }
+    apply_timing_options(&mut timing, options);
+    timing
+}
+
+fn apply_timing_options(timing: &mut TimingSettings, options: &RunOptions) {
+    if let Some(instructions_per_frame) = options.instructions_per_frame {
+        timing.mode = TimingMode::InstructionsPerFrame(instructions_per_frame);
+    }
+    match options.vip_timing {
+        Some(true) => timing.mode = TimingMode::Vip,
+        Some(false) if timing.mode == TimingMode::Vip => {
+            timing.mode = TimingMode::InstructionsPerFrame(
+                options
+                    .instructions_per_frame
+                    .unwrap_or(crate::timing::DEFAULT_INSTRUCTIONS_PER_FRAME),
+            );
+        }
+        _ => {}
+    }
+}
+
+// Runs a set number of frames as fast as possible with no window, then prints the screen. Handy
+// for checking test ROMs on a machine without a display, and with --record for making clips.
+pub fn run_headless(options: &RunOptions, config: &Config) -> io::Result<()> {
+    let mut emulator = Emulator::new(options, config)?;
+    let mut frontend = NullBackend::default();
+    let mut audio = NullBackend::default();
+
+    // A replay runs for as long as the movie does
+    let frames = if emulator.is_replaying() {
+        u64::MAX
+    } else {
+        options.frames as u64
+    };
+    for _ in 0..frames {
+        // There's nothing to step with or carry on from step mode without a window, so
+        // --start-paused or a breakpoint ends the run there. A replay would never get past it.
+        if emulator.cpu().should_halt || emulator.cpu().step_mode {
+            break;
+        }
+        emulator.tick(&mut frontend, &mut audio, 1)?;
+    }
+    emulator.cpu().dump_pixel_buffer();
+    emulator.finish()
+}
+
+// Sets up --record-movie or --play-movie. Playing a movie swaps the seed, quirks and timing for the
+// ones it was recorded with.
+pub fn start_movie(
+    options: &RunOptions,
+    cpu: &mut Cpu,
+    timing: &mut TimingSettings,
+) -> io::Result<Option<MovieSession>> {
+    if let Some(path) = &options.play_movie {
+        let (session, mode) = MovieSession::play(path, cpu)?;
+        timing.mode = mode;
+        return Ok(Some(session));
+    }
+    if let Some(path) = &options.record_movie {
+        // A movie can only be replayed if we know the seed, so pick one if we weren't given one
+        let seed = options.seed.unwrap_or_else(rand::random);
+        cpu.seed_rng(seed);
+        return Ok(Some(MovieSession::record(path, cpu, seed, timing.mode)));
+    }
+    Ok(None)
+}
+
+// For --record, the format comes from the path and the scale from the config
+pub fn start_recording(path: &Path, config: &Config) -> io::Result<Recorder> {
+    let settings = RecordingSettings::from_config(config);
+    let recorder = Recorder::start(path, RecordingFormat::from_path(path), settings.scale)?;
+    println!("Recording to {}", path.display());
+    Ok(recorder)
+}
+
+pub fn finish_recording(recorder: Recorder) -> io::Result<()> {
+    let path = recorder.path().to_path_buf();
+    let frames = recorder.finish()?;
+    println!("Saved {frames} frames to {}", path.display());
+    Ok(())
+}
+
+pub fn disasm(options: &RunOptions) -> io::Result<()> {
+    let (rom, symbols) = load_rom_and_symbols(options)?;
+    let coverage = match &options.coverage {
+        Some(path) => {
+            let coverage = CoverageExport::read(path)?;
+            if coverage.rom_sha1 != sha1_hex(&rom) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is the coverage of a different ROM", path.display()),
+                ));
+            }
+            Some(coverage)
+        }
+        None => None,
+    };
+    for line in disassemble(&rom, options.linear, coverage.as_ref(), symbols.as_ref()) {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+// Assembles Octo source into a ROM, with a symbol file next to it so running the ROM on its own
+// still shows the labels and source lines, see octo.rs
+pub fn assemble(options: &RunOptions) -> io::Result<()> {
+    let source = Path::new(&options.rom);
+    let program = assemble_file(source)?;
+    let output = options
+        .output
+        .clone()
+        .unwrap_or_else(|| source.with_extension("ch8"));
+    if output == source {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The ROM would go over the source, give it somewhere else with --output",
+        ));
+    }
+    fs::write(&output, &program.bytes).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("Couldn't write {}: {err}", output.display()),
+        )
+    })?;
+    // The symbol file finds the source relative to itself
+    let symbols_path = Symbols::path_for(&output.to_string_lossy());
+    let source_path = if source.parent() == output.parent() {
+        rom_name(&options.rom)
+    } else {
+        fs::canonicalize(source)?.to_string_lossy().into_owned()
+    };
+    program.symbols.save(&symbols_path, &source_path)?;
+    println!(
+        "Assembled {} bytes into {}, with symbols in {}",
+        program.bytes.len(),
+        output.display(),
+        symbols_path.display()
+    );
+    Ok(())
+}
+
+// Prints what the control flow graph shows up, see cfg.rs
+pub fn control_flow(options: &RunOptions) -> io::Result<()> {
+    let rom = read_rom(&options.rom)?;
+    let cfg = Cfg::build(&rom);
+    for line in cfg.report() {
+        println!("{line}");
+    }
+    if let Some(path) = &options.dot {
+        fs::write(path, cfg.to_dot(&rom_name(&options.rom)))?;
+        println!("Saved the graph to {}", path.display());
+    }
+    Ok(())
+}
+
+// Prints the ROM translated into a Rust module, see transpile.rs
+pub fn transpile_rom(options: &RunOptions) -> io::Result<()> {
+    let rom = read_rom(&options.rom)?;
+    print!(
+        "{}",
+        transpile(&rom, &rom_name(&options.rom), &sha1_hex(&rom))
+    );
+    Ok(())
+}
+
+// Just the file name, for naming what gets generated from the ROM
+fn rom_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or(path.to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+pub fn info(options: &RunOptions) -> io::Result<()> {
+    let rom = read_rom(&options.rom)?;
+    let is_code = find_code(&rom);
+    let instructions = is_code.iter().filter(|is_code| **is_code).count();
+
+    println!("File:          {}", options.rom);
+    println!("Size:          {} bytes", rom.len());
+    println!(
+        "Loads at:      {:#05X} to {:#05X}",
+        PROGRAM_START,
+        PROGRAM_START as usize + rom.len().saturating_sub(1)
+    );
+    println!("Instructions:  {instructions} reachable from the start");
+    println!(
+        "Data:          {} bytes",
+        rom.len().saturating_sub(instructions * 2)
+    );
+    println!("SHA-1:         {}", sha1_hex(&rom));
+
+    match RomDatabase::load().lookup(&sha1_hex(&rom)) {
+        Some(info) => print_rom_info(&info),
+        None => {
+            println!("Not in the ROM database");
+            println!(
+                "Platform:      {} (guessed from the instructions it uses)",
+                guess_platform(&rom, &is_code).name()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_rom_info(info: &RomInfo) {
+    println!("Title:         {}", info.title);
+    if let Some(file) = &info.file {
+        println!("Known as:      {file}");
+    }
+    println!("Platform:      {}", info.platform);
+    println!("Quirks:        {}", info.quirks);
+    if let Some(tickrate) = info.tickrate {
+        println!("Speed:         {tickrate} instructions per frame");
+    }
+    if !info.keys.is_empty() {
+        let keys: Vec<String> = info
+            .keys
+            .iter()
+            .map(|(name, key)| format!("{name}={key:X}"))
+            .collect();
+        println!("Keys:          {}", keys.join(", "));
+    }
+    if let Some((foreground, background)) = info.colours {
+        println!(
+            "Colours:       {:02X}{:02X}{:02X} on {:02X}{:02X}{:02X}",
+            foreground[0],
+            foreground[1],
+            foreground[2],
+            background[0],
+            background[1],
+            background[2]
+        );
+    }
+}
+
+// SUPER-CHIP and XO-CHIP added instructions that don't exist on the original, so if the code uses
+// any of them that's a pretty good hint
+fn guess_platform(rom: &[u8], is_code: &[bool]) -> Platform {
+    let mut platform = Platform::Chip8;
+    for offset in (0..rom.len().saturating_sub(1)).filter(|offset| is_code[*offset]) {
+        let opcode = ((rom[offset] as u16) << 8) | rom[offset + 1] as u16;
+        let instruction = Instruction::from_opcode(opcode);
+        match (instruction.instruction, instruction.nn) {
+            (0x5, _) if instruction.n == 2 || instruction.n == 3 => return Platform::XoChip,
+            (0xF, 0x01) | (0xF, 0x02) | (0xF, 0x3A) => return Platform::XoChip,
+            (0xF, 0x00) if opcode == 0xF000 => return Platform::XoChip,
+            (0x0, 0xFB..=0xFF) | (0xF, 0x30) | (0xF, 0x75) | (0xF, 0x85) => {
+                platform = Platform::SuperChip
+            }
+            (0x0, _) if instruction.y == 0xC => platform = Platform::SuperChip,
+            _ => {}
+        }
+    }
+    platform
+}
+
+// Runs the ROM twice side by side, once through the recompiler and once through the interpreter,
+// and compares the two after every block. Anything the recompiler gets wrong shows up as the first
+// block where they stop agreeing.
+pub fn lockstep(options: &RunOptions, config: &Config) -> io::Result<()> {
+    let (mut recompiled, rom_info) = create_cpu(options)?;
+    recompiled.trace = false;
+    recompiled.recompile = true;
+    let mut interpreted = Cpu::new();
+    interpreted.rom_sha1 = recompiled.rom_sha1.clone();
+    interpreted
+        .load_state(&recompiled.save_state())
+        .map_err(io::Error::other)?;
+
+    // Blocks are only used with a fixed number of instructions per frame
+    let instructions_per_frame = match resolve_timing(config, options, rom_info.as_ref()).mode {
+        TimingMode::InstructionsPerFrame(instructions_per_frame) => instructions_per_frame as u64,
+        TimingMode::Vip => DEFAULT_INSTRUCTIONS_PER_FRAME as u64,
+    };
+
+    let mut blocks: u64 = 0;
+    let mut instructions: u64 = 0;
+    let mut frames: u32 = 0;
+    while frames < options.frames && !recompiled.should_halt {
+        let mut executed: u64 = 0;
+        while executed < instructions_per_frame {
+            let address = recompiled.program_counter;
+            let ran = recompiled.run_block(instructions_per_frame - executed);
+            for _ in 0..ran {
+                let op = interpreted.fetch_decoded();
+                interpreted.execute(op);
+            }
+            executed += ran;
+            blocks += 1;
+
+            let (expected, actual) = (interpreted.save_state(), recompiled.save_state());
+            if actual != expected || recompiled.should_halt != interpreted.should_halt {
+                let mut report = format!(
+                    "The block at {address:#05X} ({ran} instructions) on frame {frames} doesn't \
+                     match the interpreter, recompiled vs interpreted:"
+                );
+                for difference in actual.differences(&expected) {
+                    report.push_str(&format!("\n    {difference}"));
+                }
+                return Err(io::Error::other(report));
+            }
+            if recompiled.should_halt
+                || (recompiled.quirks.display_wait && recompiled.waiting_for_vblank)
+            {
+                break;
+            }
+        }
+        instructions += executed;
+        frames += 1;
+        for cpu in [&mut recompiled, &mut interpreted] {
+            cpu.waiting_for_vblank = false;
+            cpu.tick_timers();
+        }
+    }
+
+    println!("{blocks} blocks and {instructions} instructions over {frames} frames all matched");
+    Ok(())
+}
+
+// Runs each workload flat out, decoding every instruction as it goes, through the decode cache and
+// through the recompiler, and reports how fast each was. The built in workloads get used without a
+// ROM. --min-mips checks the decode cache, since that's what normally runs.
+type RunFn = fn(&mut Cpu, u64) -> Measurement;
+
+pub fn bench(options: &RunOptions) -> io::Result<()> {
+    let rom;
+    let workloads: Vec<(&str, &[u8])> = if options.rom.is_empty() {
+        WORKLOADS
+            .iter()
+            .map(|workload| (workload.name, workload.program))
+            .collect()
+    } else {
+        rom = read_rom(&options.rom)?;
+        vec![(options.rom.as_str(), rom.as_slice())]
+    };
+
+    let mut slowest = f64::INFINITY;
+    for (name, program) in workloads {
+        let mut cpu = Cpu::new();
+        cpu.load_program_into_memory(&mut &program[..])?;
+        let rom_info = RomDatabase::load().lookup(&cpu.rom_sha1);
+        cpu.quirks = options.quirks(rom_info.map(|info| info.quirks));
+        if let Some(seed) = options.seed {
+            cpu.seed_rng(seed);
+        }
+        let start = cpu.save_state();
+
+        let uncached = run_uncached(&mut cpu, options.instructions);
+        let uncached_end = cpu.save_state();
+        // Every run starts from exactly the same place, so they had better all end there too
+        let mut faster_runs = Vec::new();
+        for (how, run) in [
+            ("the decode cache", run_cached as RunFn),
+            ("the recompiler", run_recompiled),
+        ] {
+            cpu.load_state(&start).map_err(io::Error::other)?;
+            faster_runs.push(run(&mut cpu, options.instructions));
+            if cpu.save_state() != uncached_end {
+                return Err(io::Error::other(format!(
+                    "{name} ended up somewhere different with {how}"
+                )));
+            }
+        }
+        let [cached, recompiled] = [&faster_runs[0], &faster_runs[1]];
+
+        println!(
+            "{name:<16} {:>9} instructions  uncached {:>7.2}  cached {:>7.2}  recompiled {:>7.2} MIPS",
+            uncached.executed,
+            uncached.mips(),
+            cached.mips(),
+            recompiled.mips()
+        );
+        slowest = slowest.min(cached.mips());
+    }
+
+    if let Some(min_mips) = options.min_mips
+        && slowest < min_mips
+    {
+        return Err(io::Error::other(format!(
+            "Only managed {slowest:.2} million instructions per second, expected at least {min_mips}"
+        )));
+    }
+    Ok(())
+}
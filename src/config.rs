@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const CONFIG_FILE_NAME: &str = "config.ini";
+
+// A really small INI style config. Sections go in [brackets] and everything under them is
+// `key = value`. Lines starting with # or ; are comments. Anything we can't make sense of gets
+// reported and skipped rather than stopping the emulator from starting.
+#[derive(Debug, Default)]
+pub struct Config {
+    sections: HashMap<String, HashMap<String, String>>,
+}
+
+impl Config {
+    // Loads config.ini from the config directory, if there isn't one we just use the defaults
+    pub fn load() -> Self {
+        let Some(path) = config_dir().map(|dir| dir.join(CONFIG_FILE_NAME)) else {
+            return Config::default();
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => Config::parse(&text),
+            Err(_) => Config::default(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut config = Config::default();
+        let mut current_section = String::new();
+
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                current_section = section.trim().to_string();
+                config.sections.entry(current_section.clone()).or_default();
+            } else if let Some((key, value)) = line.split_once('=') {
+                config
+                    .sections
+                    .entry(current_section.clone())
+                    .or_default()
+                    .insert(key.trim().to_string(), value.trim().to_string());
+            } else {
+                eprintln!(
+                    "Skipping line {} of the config, expected `key = value`: {line}",
+                    line_number + 1
+                );
+            }
+        }
+        config
+    }
+
+    pub fn section(&self, name: &str) -> Option<&HashMap<String, String>> {
+        self.sections.get(name)
+    }
+
+    pub fn section_names(&self) -> impl Iterator<Item = &str> {
+        self.sections.keys().map(|name| name.as_str())
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.section(section)
+            .and_then(|entries| entries.get(key))
+            .map(|value| value.as_str())
+    }
+
+    // Reads a value that should parse into a number (or anything else), complaining if it doesn't
+    pub fn get_parsed<T: std::str::FromStr>(&self, section: &str, key: &str) -> Option<T> {
+        let value = self.get(section, key)?;
+        match value.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                eprintln!("[{section}] {key} has an invalid value: {value}");
+                None
+            }
+        }
+    }
+}
+
+// Follows the usual places each platform keeps its config, ending up in a c8-emu folder
+pub fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("C8_EMU_CONFIG_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+        return Some(PathBuf::from(dir).join("c8-emu"));
+    }
+    if let Some(dir) = env::var_os("APPDATA") {
+        return Some(PathBuf::from(dir).join("c8-emu"));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("c8-emu"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_land_in_their_sections() {
+        let config = Config::parse(
+            "top = level\n\
+             [audio]\n\
+             volume = 0.5\n\
+             \n\
+             [ keymap.pong.ch8 ]\n\
+             1 = UP, W\n\
+             [audio]\n\
+             waveform=sine\n",
+        );
+        assert_eq!(config.get("", "top"), Some("level"));
+        assert_eq!(config.get("audio", "volume"), Some("0.5"));
+        assert_eq!(config.get("audio", "waveform"), Some("sine"));
+        assert_eq!(config.get("keymap.pong.ch8", "1"), Some("UP, W"));
+        assert_eq!(config.get("keymap.pong.ch8", "volume"), None);
+        assert_eq!(config.get("video", "volume"), None);
+    }
+
+    #[test]
+    fn comments_and_bad_lines_are_skipped() {
+        let config = Config::parse(
+            "[display]\n\
+             # scale = 2\n\
+             ; palette = amber\n\
+             this line makes no sense\n\
+             scale = 3\n\
+             [empty]\n",
+        );
+        let display = config.section("display").unwrap();
+        assert_eq!(display.len(), 1);
+        assert_eq!(config.get("display", "scale"), Some("3"));
+        // A section with nothing in it still counts, so per-ROM sections can be found
+        assert!(config.section("empty").unwrap().is_empty());
+    }
+
+    #[test]
+    fn later_values_win() {
+        let config = Config::parse("[timing]\nipf = 10\nipf = 20\n");
+        assert_eq!(config.get_parsed("timing", "ipf"), Some(20));
+    }
+
+    #[test]
+    fn values_that_dont_parse_are_ignored() {
+        let config = Config::parse("[timing]\nipf = fast\n");
+        assert_eq!(config.get("timing", "ipf"), Some("fast"));
+        assert_eq!(config.get_parsed::<u32>("timing", "ipf"), None);
+        assert_eq!(config.get_parsed::<u32>("timing", "mode"), None);
+    }
+}
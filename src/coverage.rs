@@ -0,0 +1,124 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+// The cpu's memory is always this big, which lets an address get wrapped with a mask rather than
+// a division on every instruction
+const MEMORY_SIZE: usize = 4096;
+
+// What's happened to each byte of memory, as bits so a byte can be more than one of them
+pub const EXECUTED: u8 = 1;
+// Where an executed instruction started, rather than its second byte
+pub const INSTRUCTION_START: u8 = 1 << 1;
+// Read as data, by DXYN drawing it or FX65 loading it into registers
+pub const READ: u8 = 1 << 2;
+// Written by FX33 or FX55
+pub const WRITTEN: u8 = 1 << 3;
+// Written after it had already run as code
+pub const SELF_MODIFIED: u8 = 1 << 4;
+
+// A write over code that had already run
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SelfModification {
+    pub address: u16,
+    // Where the FX33 or FX55 that did it was
+    pub written_by: u16,
+}
+
+// Everything the cpu has done to memory since the ROM was loaded
+pub struct Coverage {
+    flags: Box<[u8; MEMORY_SIZE]>,
+    // Only the first write over each byte, a loop rewriting the same code would fill this up
+    // otherwise
+    self_modifications: Vec<SelfModification>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage {
+            flags: Box::new([0; MEMORY_SIZE]),
+            self_modifications: Vec::new(),
+        }
+    }
+
+    pub fn flags(&self, address: u16) -> u8 {
+        self.flags[address as usize & (MEMORY_SIZE - 1)]
+    }
+
+    pub fn executed(&mut self, address: u16) {
+        let address = address as usize;
+        self.flags[address & (MEMORY_SIZE - 1)] |= EXECUTED | INSTRUCTION_START;
+        self.flags[(address + 1) & (MEMORY_SIZE - 1)] |= EXECUTED;
+    }
+
+    pub fn read(&mut self, start: u16, len: usize) {
+        for offset in 0..len {
+            self.flags[(start as usize + offset) & (MEMORY_SIZE - 1)] |= READ;
+        }
+    }
+
+    pub fn written(&mut self, start: u16, len: usize, written_by: u16) {
+        for offset in 0..len {
+            let address = (start as usize + offset) & (MEMORY_SIZE - 1);
+            let flags = &mut self.flags[address];
+            if *flags & EXECUTED != 0 && *flags & SELF_MODIFIED == 0 {
+                *flags |= SELF_MODIFIED;
+                self.self_modifications.push(SelfModification {
+                    address: address as u16,
+                    written_by,
+                });
+            }
+            *flags |= WRITTEN;
+        }
+    }
+
+    pub fn self_modifications(&self) -> &[SelfModification] {
+        &self.self_modifications
+    }
+
+    pub fn clear(&mut self) {
+        self.flags.fill(0);
+        self.self_modifications.clear();
+    }
+
+    pub fn export(&self, rom_sha1: &str) -> CoverageExport {
+        let with = |flag: u8| -> Vec<u16> {
+            (0..MEMORY_SIZE as u16)
+                .filter(|address| self.flags[*address as usize] & flag != 0)
+                .collect()
+        };
+        CoverageExport {
+            rom_sha1: rom_sha1.to_string(),
+            instructions: with(INSTRUCTION_START),
+            read: with(READ),
+            written: with(WRITTEN),
+            self_modifications: self.self_modifications.clone(),
+        }
+    }
+}
+
+// The coverage map as it's saved with --coverage, addresses rather than a flag for every byte so
+// it's still readable. `chipp disasm --coverage` reads it back to tell code from data.
+#[derive(Serialize, Deserialize)]
+pub struct CoverageExport {
+    pub rom_sha1: String,
+    // Where every instruction that ran started
+    pub instructions: Vec<u16>,
+    pub read: Vec<u16>,
+    pub written: Vec<u16>,
+    pub self_modifications: Vec<SelfModification>,
+}
+
+impl CoverageExport {
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(path, json)
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let json = fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
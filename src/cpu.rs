@@ -1,9 +1,50 @@
-use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
+use std::rc::Rc;
 
-use rand::Rng;
+use crate::coverage::Coverage;
+use crate::decode::{DecodeCache, Op};
+use crate::prng::Prng;
+use crate::quirks::{Platform, Quirks};
+use crate::recompiler::{Block, BlockCache};
+use crate::romdb::sha1_hex;
+use crate::savestate::SaveState;
+use crate::symbols::Symbols;
+use crate::transpile::{NativeBlock, run_native};
 
-#[derive(Debug)]
+//Printing every instruction slows everything down a lot, so it only happens when tracing is on
+macro_rules! trace {
+    ($cpu:ident, $($arg:tt)*) => {
+        if $cpu.trace {
+            println!($($arg)*);
+        }
+    };
+}
+
+//How many return addresses the original interpreter's stack had room for
+pub const STACK_LEVELS: usize = 16;
+
+//The built in font lives in the interpreter's area of memory, each character is 5 bytes
+const FONT_ADDRESS: u16 = 0x50;
+const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+#[derive(Debug, Clone, Copy)]
 pub struct Instruction {
     pub instruction: u8,
     pub x: u8,
@@ -13,324 +54,502 @@ pub struct Instruction {
     pub nnn: u16,
 }
 
+impl Instruction {
+    //Looks scary but we're just doing bitwise operations to extract 4 nibbles and a 12 bit memory
+    //address from the final 3 nibbles.
+    pub fn from_opcode(opcode: u16) -> Self {
+        Instruction {
+            instruction: (opcode >> 12) as u8,
+            x: ((opcode >> 8) & 0x0F) as u8,
+            y: ((opcode >> 4) & 0x0F) as u8,
+            n: (opcode & 0x0F) as u8,
+            nn: (opcode & 0xFF) as u8,
+            nnn: opcode & 0x0FFF,
+        }
+    }
+}
+
 pub struct Cpu {
     memory: [u8; 4096_usize],
     pub v_registers: [u8; 16],
     index_register: u16,
     pub program_counter: u16,
     stack: Vec<u16>,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
     pub should_halt: bool,
     pub pixel_buffer: [[bool; 64]; 32],
-    //The clock speed is what will determine raylibs FPS. Seems to be the easiest way to implement
-    //a cycle speed since we're calling cpu functions from within the raylib game loop.
     pub step_mode: bool,
-    pub current_key: u8,
+    //Which of the 16 keys are currently held down, this gets updated once per frame
+    pub keypad: [bool; 16],
+    //FX0A waits for a key to be pressed and then released, this is the key we're waiting on
+    awaited_key: Option<u8>,
+    //On the VIP, drawing a sprite waited for the display interrupt before carrying on
+    pub waiting_for_vblank: bool,
+    pub quirks: Quirks,
+    pub trace: bool,
+    rng: Prng,
+    //SHA-1 of the loaded ROM, used to look it up in the ROM database
+    pub rom_sha1: String,
+    //Instructions that have already been decoded, kept up to date as memory changes
+    decoded: DecodeCache,
+    //Run whole blocks of compiled instructions at a time instead of one instruction at a time
+    pub recompile: bool,
+    blocks: BlockCache,
+    //Code translated ahead of time with `chipp transpile`, used instead of compiling blocks
+    pub native: Option<&'static [NativeBlock]>,
+    //Which bytes have run as code, been read as data or been written since the ROM was loaded
+    coverage: Coverage,
+    //Labels and source lines from the ROM's symbol file, if it has one
+    pub symbols: Option<Symbols>,
+    //Addresses to drop into step mode at before they run
+    pub breakpoints: Vec<u16>,
+    //Carrying on from a breakpoint mustn't stop straight away on the same one
+    resumed_at: Option<u16>,
 }
 
 impl Cpu {
     pub fn new() -> Self {
-        Cpu {
+        let mut cpu = Cpu {
             memory: [0; 4096_usize],
             v_registers: [0; 16],
             index_register: 0,
             program_counter: 0x200,
             stack: Vec::new(),
+            delay_timer: 0,
+            sound_timer: 0,
             should_halt: false,
             pixel_buffer: [[false; 64]; 32],
             step_mode: false,
-            current_key: 255,
-        }
+            keypad: [false; 16],
+            awaited_key: None,
+            waiting_for_vblank: false,
+            quirks: Platform::Chip8.default_quirks(),
+            trace: false,
+            rng: Prng::from_entropy(),
+            rom_sha1: String::new(),
+            decoded: DecodeCache::new(4096),
+            recompile: false,
+            blocks: BlockCache::new(4096),
+            native: None,
+            coverage: Coverage::new(),
+            symbols: None,
+            breakpoints: Vec::new(),
+            resumed_at: None,
+        };
+        let font_start = FONT_ADDRESS as usize;
+        cpu.memory[font_start..font_start + FONT.len()].copy_from_slice(&FONT);
+        cpu
     }
 
-    pub fn load_program_into_memory(&mut self, program: &mut File) {
+    pub fn load_program_into_memory(&mut self, program: &mut impl Read) -> io::Result<()> {
         let mut data: Vec<u8> = Vec::new();
         //Traditionally, the interpreter was put into the first 512 bytes of memory, meaning that
         //ROMs had to fit into the remaining memory, we check that here
-        match program.read_to_end(&mut data) {
-            Ok(value) => {
-                if value > 3584 {
-                    panic!(
-                        "Memory Violation! Attempted to write {} bytes into memory from {:#04x}",
-                        value, self.program_counter
-                    );
-                } else {
-                    println!("Loading program with size of {value} bytes into memory");
-                }
-            }
-            Err(err) => eprintln!("{err:?}"),
+        let value = program.read_to_end(&mut data)?;
+        if value > 3584 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Memory Violation! Attempted to write {} bytes into memory from {:#04x}",
+                    value, self.program_counter
+                ),
+            ));
         }
+        println!("Loading program with size of {value} bytes into memory");
+        self.rom_sha1 = sha1_hex(&data);
         for byte in data {
             self.memory[self.program_counter as usize] = byte;
             self.increment_program_counter(1);
         }
         self.set_program_counter(0x200); //Set it back to 512 which is the first instruction.
+        self.decoded.clear();
+        self.blocks.clear();
+        self.coverage.clear();
+        Ok(())
+    }
+
+    //Makes CXNN produce the same numbers every run
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Prng::from_seed(seed);
+    }
+
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            rom_sha1: self.rom_sha1.clone(),
+            memory: self.memory.to_vec(),
+            v_registers: self.v_registers,
+            index_register: self.index_register,
+            program_counter: self.program_counter,
+            stack: self.stack.clone(),
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            pixel_rows: self
+                .pixel_buffer
+                .iter()
+                .map(|row| row.iter().fold(0, |bits, &lit| (bits << 1) | lit as u64))
+                .collect(),
+            awaited_key: self.awaited_key,
+            waiting_for_vblank: self.waiting_for_vblank,
+            quirks: self.quirks,
+            rng: self.rng,
+        }
+    }
+
+    //Anything that doesn't fit this ROM or this machine gets turned away before we touch the cpu
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), String> {
+        if state.rom_sha1 != self.rom_sha1 {
+            return Err("That save state is from a different ROM".to_string());
+        }
+        //Anything out of range would only panic later on, so it gets turned away before any of it
+        //is loaded. I can legitimately end up past the end of memory, which is fine since
+        //everything that uses it wraps round.
+        let damaged = state.memory.len() != self.memory.len()
+            || state.pixel_rows.len() != 32
+            || state.program_counter > 0xFFF
+            || state.stack.len() > STACK_LEVELS
+            || state.stack.iter().any(|address| *address > 0xFFF)
+            || state.awaited_key.is_some_and(|key| key >= 16);
+        if damaged {
+            return Err("That save state is damaged".to_string());
+        }
+        self.memory.copy_from_slice(&state.memory);
+        self.decoded.clear();
+        self.blocks.clear();
+        self.v_registers = state.v_registers;
+        self.index_register = state.index_register;
+        self.program_counter = state.program_counter;
+        self.stack = state.stack.clone();
+        self.delay_timer = state.delay_timer;
+        self.sound_timer = state.sound_timer;
+        for (row, bits) in self.pixel_buffer.iter_mut().zip(&state.pixel_rows) {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = bits & (1 << (63 - x)) != 0;
+            }
+        }
+        self.awaited_key = state.awaited_key;
+        self.waiting_for_vblank = state.waiting_for_vblank;
+        self.quirks = state.quirks;
+        self.rng = state.rng;
+        self.should_halt = false;
+        Ok(())
     }
 
-    //Looks scary but we're just doing bitwise operations on each byte to extract 4 nibbles and a 12
-    //bit memory address from the final 3 nibbles.
     pub fn fetch(&mut self) -> Instruction {
-        let decoded_opcode: u16 = ((self.memory[self.program_counter as usize] as u16) << 8)
-            | self.memory[(self.program_counter + 1) as usize] as u16;
-        let decoded_instruction = Instruction {
-            instruction: (self.memory[self.program_counter as usize] >> 4),
-            x: (self.memory[self.program_counter as usize] & 0x0F),
-            y: (self.memory[(self.program_counter + 1) as usize] >> 4),
-            n: (self.memory[(self.program_counter + 1) as usize] & 0x0F),
-            nn: self.memory[(self.program_counter + 1) as usize],
-            nnn: decoded_opcode & 0x0FFF,
-        };
+        let decoded_instruction = self.instruction_at(self.program_counter);
+        self.coverage.executed(self.program_counter);
         self.increment_program_counter(2);
         decoded_instruction
     }
 
+    // Whether memory still holds these bytes, so code translated ahead of time can tell it hasn't
+    // been written over since
+    pub fn code_matches(&self, address: u16, bytes: &[u8]) -> bool {
+        let start = address as usize;
+        self.memory.get(start..start + bytes.len()) == Some(bytes)
+    }
+
     // Creates a string representation of the Current, Previous and Next instruction in memory so
-    // that we can show it in the UI
+    // that we can show it in the UI. Memory wraps round at either end.
     pub fn fetch_opcodes(&mut self) -> [u16; 3] {
-        let opcode_previous: u16 = ((self.memory[self.program_counter as usize - 2] as u16) << 8)
-            | self.memory[(self.program_counter - 1) as usize] as u16;
+        [
+            self.program_counter.wrapping_sub(2),
+            self.program_counter,
+            self.program_counter + 2,
+        ]
+        .map(|address| {
+            let address = (address & 0xFFF) as usize;
+            ((self.memory[address] as u16) << 8) | self.memory[(address + 1) & 0xFFF] as u16
+        })
+    }
 
-        let opcode_current: u16 = ((self.memory[self.program_counter as usize] as u16) << 8)
-            | self.memory[(self.program_counter + 1) as usize] as u16;
+    // The uncached way of running an instruction, for anything that only runs one now and then
+    pub fn decode_and_execute(&mut self, instruction: Instruction) {
+        self.execute(Op::decode(&instruction));
+    }
 
-        let opcode_next: u16 = ((self.memory[self.program_counter as usize + 2] as u16) << 8)
-            | self.memory[(self.program_counter + 3) as usize] as u16;
+    // Like fetch, but each address only gets decoded the first time it runs. That's also the only
+    // time it needs marking in the coverage, since nothing clears one without the other and a
+    // write over the instruction drops it from the cache.
+    pub fn fetch_decoded(&mut self) -> Op {
+        let op = match self.decoded.get(self.program_counter) {
+            Some(op) => op,
+            None => {
+                let op = Op::decode(&self.instruction_at(self.program_counter));
+                self.decoded.insert(self.program_counter, op);
+                self.coverage.executed(self.program_counter);
+                op
+            }
+        };
+        self.increment_program_counter(2);
+        op
+    }
 
-        [opcode_previous, opcode_current, opcode_next]
+    // The instruction at an address without running it or moving the program counter
+    pub fn instruction_at(&self, address: u16) -> Instruction {
+        let opcode = ((self.memory[address as usize] as u16) << 8)
+            | self.memory[(address as usize + 1) & 0xFFF] as u16;
+        Instruction::from_opcode(opcode)
     }
 
-    pub fn decode_and_execute(&mut self, instruction: Instruction) {
-        match instruction.instruction {
-            0x0 => {
-                // We need to differenciate between 0x00E0(clear screen) and 0x00EE(return from subroutine)
-                if instruction.y == 0xE && instruction.n != 0xE {
-                    println!("CLS");
-                    self.pixel_buffer = [[false; 64]; 32];
-                }
-                if instruction.n == 0xE {
-                    if let Some(return_address) = self.stack.pop() {
-                        println!("RET {return_address}");
-                        self.set_program_counter(return_address);
-                    } else {
-                        panic!("Tried to return from a subroutine with an empty Stack!");
-                    }
+    // Runs the block starting at the program counter and returns how many instructions that was,
+    // never more than `budget`. Translated code goes first if there is any, then the compiled
+    // block, which only gets compiled once it's run often enough. Tracing and breakpoints need to
+    // see every instruction, so they get one at a time.
+    pub fn run_block(&mut self, budget: u64) -> u64 {
+        if self.trace || !self.breakpoints.is_empty() {
+            let op = self.fetch_decoded();
+            self.execute(op);
+            return 1;
+        }
+        if let Some(blocks) = self.native {
+            return run_native(self, blocks, budget);
+        }
+        let start = self.program_counter;
+        let compiled = match self.blocks.get(start) {
+            Some(block) => Some(block),
+            None if self.blocks.hit(start) => {
+                let block = Rc::new(Block::compile(self, start));
+                self.blocks.insert(start, block.clone()).then_some(block)
+            }
+            None => None,
+        };
+        //Until then, or if it's not worth it, it's cheaper to go through the decode cache
+        let Some(block) = compiled else {
+            return self.run_uncompiled(budget);
+        };
+        block.run(self, budget)
+    }
+
+    //Goes through the decode cache until it jumps to a block that's compiled or ready to be, or
+    //to anything that could end the frame. Stopping at every block instead costs more than running
+    //the short ones through the decode cache saves.
+    fn run_uncompiled(&mut self, budget: u64) -> u64 {
+        let mut executed = 0;
+        while executed < budget {
+            let op = self.fetch_decoded();
+            let next = self.program_counter;
+            self.execute(op);
+            executed += 1;
+            if matches!(op, Op::Draw { .. })
+                || (self.program_counter != next
+                    && (self.should_halt || self.blocks.hit(self.program_counter)))
+            {
+                break;
+            }
+        }
+        executed
+    }
+
+    //Blocks never go through fetch, so they mark what they're about to run with this instead. It
+    //has to happen before the instructions run, so a block that writes over itself gets caught as
+    //self-modifying the same as it would one instruction at a time.
+    pub fn mark_executed(&mut self, start: u16, instructions: usize) {
+        for instruction in 0..instructions as u16 {
+            self.coverage.executed((start + 2 * instruction) & 0xFFF);
+        }
+    }
+
+    pub fn execute(&mut self, op: Op) {
+        if self.trace
+            && let Some(line) = self
+                .symbols
+                .as_ref()
+                .and_then(|symbols| symbols.source_line(self.current_instruction()))
+        {
+            println!("{line}");
+        }
+        match op {
+            Op::Nop => (),
+            Op::ClearScreen => {
+                trace!(self, "CLS");
+                self.pixel_buffer = [[false; 64]; 32];
+            }
+            Op::Return => {
+                if let Some(return_address) = self.stack.pop() {
+                    trace!(self, "RET {}", self.describe(return_address));
+                    self.set_program_counter(return_address);
+                } else {
+                    panic!("Tried to return from a subroutine with an empty Stack!");
                 }
             }
-            0x1 => {
-                println!("JMP {}", instruction.nnn);
+            Op::Jump { nnn } => {
+                trace!(self, "JMP {}", self.describe(nnn));
 
                 //Roms have a tendency to have a "JUMP TO CURRENT INSTRUCTION" at the end of their instructions
                 //They do this because there's no "stop execution" instruction
                 //Here we make sure we're not just looping forever at the end
-                if self.program_counter - 2 == instruction.nnn {
+                if self.program_counter - 2 == nnn {
                     println!("Infinte loop detected, halting execution!");
                     self.should_halt = true;
                 }
 
-                self.set_program_counter(instruction.nnn);
+                self.set_program_counter(nnn);
             }
-            0x2 => {
-                println!("CALL {}", instruction.nnn);
-                self.stack.push(self.program_counter);
-                self.set_program_counter(instruction.nnn);
+            Op::Call { nnn } => {
+                trace!(self, "CALL {}", self.describe(nnn));
+                //Going any deeper would have run over whatever was after the stack
+                if self.stack.len() >= STACK_LEVELS {
+                    println!(
+                        "Stack overflow, more than {STACK_LEVELS} calls deep, halting execution!"
+                    );
+                    self.should_halt = true;
+                } else {
+                    self.stack.push(self.program_counter);
+                    self.set_program_counter(nnn);
+                }
             }
-            0x3 => {
-                println!(
-                    "JMP IF EQUAL V{} {}, {}",
-                    instruction.x, self.v_registers[instruction.x as usize], instruction.nnn
+            Op::SkipIfEqual { x, nn } => {
+                trace!(
+                    self,
+                    "JMP IF EQUAL V{} {}, {}", x, self.v_registers[x as usize], nn
                 );
-                if self.v_registers[instruction.x as usize] == instruction.nn {
+                if self.v_registers[x as usize] == nn {
                     self.increment_program_counter(2);
                 }
             }
-            0x4 => {
-                println!(
-                    "JMP IF NOT EQUAL V{} {}, {}",
-                    instruction.x, self.v_registers[instruction.x as usize], instruction.nnn
+            Op::SkipIfNotEqual { x, nn } => {
+                trace!(
+                    self,
+                    "JMP IF NOT EQUAL V{} {}, {}", x, self.v_registers[x as usize], nn
                 );
-                if self.v_registers[instruction.x as usize] != instruction.nn {
+                if self.v_registers[x as usize] != nn {
                     self.increment_program_counter(2);
                 }
             }
-            0x5 => {
-                println!(
+            Op::SkipIfRegistersEqual { x, y } => {
+                trace!(
+                    self,
                     "JMP IF EQUAL V{} {}, V{} {}",
-                    instruction.x,
-                    self.v_registers[instruction.x as usize],
-                    instruction.y,
-                    self.v_registers[instruction.y as usize]
+                    x,
+                    self.v_registers[x as usize],
+                    y,
+                    self.v_registers[y as usize]
                 );
-                if self.v_registers[instruction.x as usize]
-                    == self.v_registers[instruction.y as usize]
-                {
+                if self.v_registers[x as usize] == self.v_registers[y as usize] {
                     self.increment_program_counter(2);
                 }
             }
-            0x6 => {
-                println!("MOV V{}, {}", instruction.x, instruction.nn);
-                self.v_registers[instruction.x as usize] = instruction.nn;
+            Op::Load { x, nn } => {
+                trace!(self, "MOV V{}, {}", x, nn);
+                self.v_registers[x as usize] = nn;
             }
-            0x7 => {
-                println!("ADD V{}, {}", instruction.x, instruction.nn);
-                self.v_registers[instruction.x as usize] =
-                    self.v_registers[instruction.x as usize].wrapping_add(instruction.nn);
+            Op::Add { x, nn } => {
+                trace!(self, "ADD V{}, {}", x, nn);
+                self.v_registers[x as usize] = self.v_registers[x as usize].wrapping_add(nn);
             }
-            0x8 => match instruction.n {
-                0x0 => {
-                    println!("MOV V{}, V{}", instruction.x, instruction.y);
-                    self.v_registers[instruction.x as usize] =
-                        self.v_registers[instruction.y as usize];
-                }
-                0x1 => {
-                    println!("OR V{}, V{}", instruction.x, instruction.y);
-                    self.v_registers[instruction.x as usize] |=
-                        self.v_registers[instruction.y as usize];
-                }
-                0x2 => {
-                    println!("AND V{}, V{}", instruction.x, instruction.y);
-                    self.v_registers[instruction.x as usize] &=
-                        self.v_registers[instruction.y as usize];
-                }
-                0x3 => {
-                    println!("XOR V{}, V{}", instruction.x, instruction.y);
-                    self.v_registers[instruction.x as usize] ^=
-                        self.v_registers[instruction.y as usize];
-                }
-                0x4 => {
-                    println!("ADD V{}, V{}", instruction.x, instruction.y);
-
-                    //This instruction requires that if there was an overflow, we set the VF register to one
-                    //Otherwise it gets set to zero
-                    let (_, overflowed) = self.v_registers[instruction.x as usize]
-                        .overflowing_add(self.v_registers[instruction.y as usize]);
-                    if overflowed {
-                        self.v_registers[0xF] = 1;
-                    } else {
-                        self.v_registers[0xF] = 0;
-                    }
-
-                    self.v_registers[instruction.x as usize] = self.v_registers
-                        [instruction.x as usize]
-                        .wrapping_add(self.v_registers[instruction.y as usize])
-                }
-                0x5 => {
-                    println!(
-                        "SUB V{}, V{} MINUS V{}",
-                        instruction.x, instruction.x, instruction.y
-                    );
-
-                    // If we're going to underflow, vf should be set to 1
-                    if self.v_registers[instruction.x as usize]
-                        > self.v_registers[instruction.y as usize]
-                    {
-                        self.v_registers[0xF] = 1;
-                    } else if self.v_registers[instruction.x as usize]
-                        < self.v_registers[instruction.y as usize]
-                    {
-                        self.v_registers[0xF] = 0;
-                    }
-
-                    self.v_registers[instruction.x as usize] = self.v_registers
-                        [instruction.x as usize]
-                        .wrapping_sub(self.v_registers[instruction.y as usize]);
-                }
-                0x6 => {
-                    println!(
-                        "MOV V{}, V{} >> 1",
-                        self.v_registers[instruction.x as usize],
-                        self.v_registers[instruction.y as usize]
-                    );
-
-                    //We have to set the VF register to the bit that was shifted out
-                    if self.v_registers[instruction.y as usize] & 1 != 0 {
-                        self.v_registers[0xF] = 1;
-                    } else {
-                        self.v_registers[0xF] = 0;
-                    }
+            Op::Move { x, y } => {
+                trace!(self, "MOV V{}, V{}", x, y);
+                self.v_registers[x as usize] = self.v_registers[y as usize];
+            }
+            Op::Or { x, y } => {
+                trace!(self, "OR V{}, V{}", x, y);
+                self.v_registers[x as usize] |= self.v_registers[y as usize];
+                self.reset_vf_after_logic();
+            }
+            Op::And { x, y } => {
+                trace!(self, "AND V{}, V{}", x, y);
+                self.v_registers[x as usize] &= self.v_registers[y as usize];
+                self.reset_vf_after_logic();
+            }
+            Op::Xor { x, y } => {
+                trace!(self, "XOR V{}, V{}", x, y);
+                self.v_registers[x as usize] ^= self.v_registers[y as usize];
+                self.reset_vf_after_logic();
+            }
+            Op::AddRegisters { x, y } => {
+                trace!(self, "ADD V{}, V{}", x, y);
 
-                    self.v_registers[instruction.x as usize] =
-                        self.v_registers[instruction.y as usize] >> 1;
-                }
-                0x7 => {
-                    println!(
-                        "SUB V{}, V{} MINUS V{}",
-                        instruction.x, instruction.y, instruction.x
-                    );
+                //This instruction requires that if there was an overflow, we set the VF register to one
+                //Otherwise it gets set to zero. VF gets set last so it wins if it was also VX.
+                let (result, overflowed) =
+                    self.v_registers[x as usize].overflowing_add(self.v_registers[y as usize]);
+                self.v_registers[x as usize] = result;
+                self.v_registers[0xF] = overflowed as u8;
+            }
+            Op::Subtract { x, y } => {
+                trace!(self, "SUB V{}, V{} MINUS V{}", x, x, y);
 
-                    // If we're going to underflow, vf should be set to 1
-                    if self.v_registers[instruction.y as usize]
-                        > self.v_registers[instruction.x as usize]
-                    {
-                        self.v_registers[0xF] = 1;
-                    } else if self.v_registers[instruction.y as usize]
-                        < self.v_registers[instruction.x as usize]
-                    {
-                        self.v_registers[0xF] = 0;
-                    }
+                // If we're going to underflow, vf should be set to 0, otherwise it's 1
+                let (result, underflowed) =
+                    self.v_registers[x as usize].overflowing_sub(self.v_registers[y as usize]);
+                self.v_registers[x as usize] = result;
+                self.v_registers[0xF] = !underflowed as u8;
+            }
+            Op::ShiftRight { x, y } => {
+                trace!(
+                    self,
+                    "MOV V{}, V{} >> 1", self.v_registers[x as usize], self.v_registers[y as usize]
+                );
 
-                    self.v_registers[instruction.x as usize] = self.v_registers
-                        [instruction.y as usize]
-                        .wrapping_sub(self.v_registers[instruction.x as usize]);
-                }
-                0xE => {
-                    println!(
-                        "MOV V{}, V{} << 1",
-                        self.v_registers[instruction.x as usize],
-                        self.v_registers[instruction.y as usize]
-                    );
+                //We have to set the VF register to the bit that was shifted out
+                let value = self.shift_source(x, y);
+                self.v_registers[x as usize] = value >> 1;
+                self.v_registers[0xF] = value & 1;
+            }
+            Op::SubtractReversed { x, y } => {
+                trace!(self, "SUB V{}, V{} MINUS V{}", x, y, x);
 
-                    //We have to set the VF register to the bit that was shifted out
-                    if self.v_registers[instruction.y as usize] & 1 != 0 {
-                        self.v_registers[0xF] = 1;
-                    } else {
-                        self.v_registers[0xF] = 0;
-                    }
+                // If we're going to underflow, vf should be set to 0, otherwise it's 1
+                let (result, underflowed) =
+                    self.v_registers[y as usize].overflowing_sub(self.v_registers[x as usize]);
+                self.v_registers[x as usize] = result;
+                self.v_registers[0xF] = !underflowed as u8;
+            }
+            Op::ShiftLeft { x, y } => {
+                trace!(
+                    self,
+                    "MOV V{}, V{} << 1", self.v_registers[x as usize], self.v_registers[y as usize]
+                );
 
-                    self.v_registers[instruction.x as usize] =
-                        self.v_registers[instruction.y as usize] << 1;
-                }
-                _ => (),
-            },
-            0x9 => {
-                println!(
+                //We have to set the VF register to the bit that was shifted out, which is the
+                //top one this time
+                let value = self.shift_source(x, y);
+                self.v_registers[x as usize] = value << 1;
+                self.v_registers[0xF] = value >> 7;
+            }
+            Op::SkipIfRegistersNotEqual { x, y } => {
+                trace!(
+                    self,
                     "JMP IF NOT EQUAL V{} {}, V{} {}",
-                    instruction.x,
-                    self.v_registers[instruction.x as usize],
-                    instruction.y,
-                    self.v_registers[instruction.y as usize]
+                    x,
+                    self.v_registers[x as usize],
+                    y,
+                    self.v_registers[y as usize]
                 );
-                if self.v_registers[instruction.x as usize]
-                    != self.v_registers[instruction.y as usize]
-                {
+                if self.v_registers[x as usize] != self.v_registers[y as usize] {
                     self.increment_program_counter(2);
                 }
             }
-            0xA => {
-                println!("MOV I, {}", instruction.nnn);
-                self.index_register = instruction.nnn;
+            Op::LoadIndex { nnn } => {
+                trace!(self, "MOV I, {}", self.describe(nnn));
+                self.index_register = nnn;
             }
-            0xB => {
-                println!("JMP {}", (instruction.nnn + self.v_registers[0x0] as u16));
-                self.set_program_counter(instruction.nnn + self.v_registers[0x0] as u16);
+            Op::JumpWithOffset { x, nnn } => {
+                //SUPER-CHIP read this as BXNN by accident, so the offset comes from VX instead
+                let offset_register = if self.quirks.jumping { x } else { 0x0 };
+                let address = (nnn + self.v_registers[offset_register as usize] as u16) & 0xFFF;
+                trace!(self, "JMP {}", self.describe(address));
+                self.set_program_counter(address);
             }
-            0xC => {
-                println!("RAND V{}", instruction.x);
-                self.v_registers[instruction.x as usize] =
-                    rand::rng().random::<u8>() & instruction.nn;
+            Op::Random { x, nn } => {
+                trace!(self, "RAND V{}", x);
+                self.v_registers[x as usize] = self.rng.next_u8() & nn;
             }
-            0xD => {
-                println!(
-                    "DRAW FROM X:{} Y:{} for {} rows",
-                    instruction.x, instruction.y, instruction.n
-                );
+            Op::Draw { x: vx, y: vy, n } => {
+                trace!(self, "DRAW FROM X:{} Y:{} for {} rows", vx, vy, n);
+                self.waiting_for_vblank = true;
                 let index = self.index_register;
+                self.coverage.read(index, n as usize);
                 //The Y coordinate doesn't need to be reset, we can initialize it outside the loop
-                let mut y: usize = (self.v_registers[instruction.y as usize] % 32).into();
+                let mut y: usize = (self.v_registers[vy as usize] % 32).into();
                 self.v_registers[0xF] = 0;
 
-                for row in 0..instruction.n {
-                    let sprite_data: u8 = self.memory[(index + row as u16) as usize];
+                for row in 0..n {
+                    let sprite_data: u8 =
+                        self.memory[(index.wrapping_add(row as u16) & 0xFFF) as usize];
                     //The X coordinate should be reset for each row that we do
-                    let mut x: usize = (self.v_registers[instruction.x as usize] % 64).into();
+                    let mut x: usize = (self.v_registers[vx as usize] % 64).into();
                     for bit in (0..8).rev() {
                         if sprite_data & (1 << bit) != 0 && self.pixel_buffer[y][x] {
                             self.v_registers[0xF] = 1
@@ -341,80 +560,195 @@ impl Cpu {
 
                         x += 1;
                         if x > 63 {
+                            //With clipping on, whatever hangs off the edge just isn't drawn
+                            if self.quirks.clipping {
+                                break;
+                            }
                             x = 0
                         }
                     }
                     y += 1;
                     if y > 31 {
+                        if self.quirks.clipping {
+                            break;
+                        }
                         y = 0
                     }
                 }
             }
-            0xE => match instruction.nn {
-                0x9E => {
-                    println!("JMP IF KEY {}", self.v_registers[instruction.x as usize]);
-                    if self.v_registers[instruction.x as usize] == self.current_key {
-                        self.increment_program_counter(2);
-                    }
+            Op::SkipIfKey { x } => {
+                trace!(self, "JMP IF KEY {}", self.v_registers[x as usize]);
+                if self.keypad[(self.v_registers[x as usize] & 0xF) as usize] {
+                    self.increment_program_counter(2);
                 }
-                0xA1 => {
-                    println!(
-                        "JMP IF NOT KEY {}",
-                        self.v_registers[instruction.x as usize]
-                    );
-                    if self.v_registers[instruction.x as usize] != self.current_key {
-                        self.increment_program_counter(2);
-                    }
+            }
+            Op::SkipIfNotKey { x } => {
+                trace!(self, "JMP IF NOT KEY {}", self.v_registers[x as usize]);
+                if !self.keypad[(self.v_registers[x as usize] & 0xF) as usize] {
+                    self.increment_program_counter(2);
                 }
-                _ => (),
-            },
-            0xF => match instruction.nn {
-                0x0A => {
-                    //If we're not pressing a key, just go back and wait for a key to be pressed!
-                    println!("AWAIT KEY");
-                    if self.current_key == 255 {
+            }
+            Op::LoadDelay { x } => {
+                trace!(self, "MOV V{}, DT", x);
+                self.v_registers[x as usize] = self.delay_timer;
+            }
+            Op::WaitForKey { x } => {
+                //Until a key has been pressed and let go again, we just go back and run this
+                //instruction again. The original interpreter waited for the release too.
+                trace!(self, "AWAIT KEY");
+                match self.awaited_key {
+                    Some(key) if !self.keypad[key as usize] => {
+                        self.v_registers[x as usize] = key;
+                        self.awaited_key = None;
+                    }
+                    Some(_) => self.set_program_counter(self.program_counter - 2),
+                    None => {
+                        self.awaited_key = (0..16).find(|key| self.keypad[*key as usize]);
                         self.set_program_counter(self.program_counter - 2);
-                    } else {
-                        self.v_registers[instruction.x as usize] = self.current_key;
                     }
                 }
-                0x1E => {
-                    println!("ADD I, V{}", instruction.x);
-                    self.index_register += self.v_registers[instruction.x as usize] as u16;
-                }
-                0x33 => {
-                    println!("CONV V{}", instruction.x);
-                    //This is probably the coolest instruction, you convert the binary value
-                    //into a decimal and then add all the digits together
-                    let value = self.v_registers[instruction.x as usize];
-                    self.memory[self.index_register as usize] = value / 100;
-                    self.memory[self.index_register as usize + 1] = (value / 10) % 10;
-                    self.memory[self.index_register as usize + 2] = value % 10;
+            }
+            Op::SetDelay { x } => {
+                trace!(self, "MOV DT, V{}", x);
+                self.delay_timer = self.v_registers[x as usize];
+            }
+            Op::SetSound { x } => {
+                trace!(self, "MOV ST, V{}", x);
+                self.sound_timer = self.v_registers[x as usize];
+            }
+            Op::AddToIndex { x } => {
+                trace!(self, "ADD I, V{}", x);
+                self.index_register = self
+                    .index_register
+                    .wrapping_add(self.v_registers[x as usize] as u16);
+            }
+            Op::LoadFont { x } => {
+                trace!(self, "MOV I, FONT V{}", x);
+                //Every character in the font is 5 bytes tall
+                let character = self.v_registers[x as usize] & 0xF;
+                self.index_register = FONT_ADDRESS + character as u16 * 5;
+            }
+            Op::StoreBcd { x } => {
+                trace!(self, "CONV V{}", x);
+                //This is probably the coolest instruction, you convert the binary value
+                //into a decimal and then add all the digits together
+                let value = self.v_registers[x as usize];
+                for (offset, digit) in [value / 100, (value / 10) % 10, value % 10]
+                    .into_iter()
+                    .enumerate()
+                {
+                    self.memory[self.index_address(offset)] = digit;
                 }
-                0x55 => {
-                    println!("MEM SET FROM {} FOR {}", self.index_register, instruction.x);
-                    for register in 0..=instruction.x {
-                        self.memory[(self.index_register as usize) + register as usize] =
-                            self.v_registers[register as usize];
-                    }
+                self.wrote_memory(3);
+            }
+            Op::StoreRegisters { x } => {
+                trace!(self, "MEM SET FROM {} FOR {}", self.index_register, x);
+                for register in 0..=x as usize {
+                    self.memory[self.index_address(register)] = self.v_registers[register];
                 }
-                0x65 => {
-                    println!(
-                        "MEM GRAB FROM {} FOR {}",
-                        self.index_register, instruction.x
-                    );
-                    for register in 0..=instruction.x {
-                        self.v_registers[register as usize] =
-                            self.memory[(self.index_register as usize) + register as usize];
-                    }
+                self.wrote_memory(x as usize + 1);
+                self.increment_index_after_memory(x);
+            }
+            Op::LoadRegisters { x } => {
+                trace!(self, "MEM GRAB FROM {} FOR {}", self.index_register, x);
+                self.coverage.read(self.index_register, x as usize + 1);
+                for register in 0..=x as usize {
+                    self.v_registers[register] = self.memory[self.index_address(register)];
                 }
-                _ => {}
+                self.increment_index_after_memory(x);
+            }
+        }
+    }
+
+    //The original interpreter left I pointing past the registers it saved or loaded, later ones didn't
+    fn increment_index_after_memory(&mut self, x: u8) {
+        if self.quirks.memory {
+            self.index_register = self.index_register.wrapping_add(x as u16 + 1);
+        }
+    }
+
+    //Where `offset` bytes on from I is, memory wraps round at the end the same as it does for the
+    //program counter
+    fn index_address(&self, offset: usize) -> usize {
+        (self.index_register as usize + offset) & 0xFFF
+    }
+
+    //Drops anything decoded or compiled from the `len` bytes at I that were just written over, in
+    //two goes if they ran off the end of memory
+    fn wrote_memory(&mut self, len: usize) {
+        let start = self.index_address(0);
+        let before_end = len.min(self.memory.len() - start);
+        self.decoded.invalidate(start, before_end);
+        self.blocks.invalidate(start, before_end);
+        self.decoded.invalidate(0, len - before_end);
+        self.blocks.invalidate(0, len - before_end);
+        self.coverage
+            .written(self.index_register, len, self.current_instruction());
+    }
+
+    //The VIP's logic instructions went through a routine that clobbered VF on the way
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.vf_reset {
+            self.v_registers[0xF] = 0;
+        }
+    }
+
+    //Originally the shifts put VY shifted into VX, but SUPER-CHIP shifts VX in place
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shifting {
+            self.v_registers[x as usize]
+        } else {
+            self.v_registers[y as usize]
+        }
+    }
+
+    // How many machine cycles (8 clock cycles each) the original COSMAC VIP interpreter spent on an
+    // instruction. These come from measured averages, the real numbers wobble a little depending
+    // on the operands. Skips cost a couple of cycles more when they're taken, so this needs to be
+    // called before the instruction runs.
+    pub fn vip_cycles(&self, instruction: &Instruction) -> u32 {
+        let vx = self.v_registers[instruction.x as usize];
+        let vy = self.v_registers[instruction.y as usize];
+        let skip = |cycles: u32, taken: bool| if taken { cycles + 2 } else { cycles };
+
+        match instruction.instruction {
+            0x0 if instruction.nn == 0xE0 => 24,
+            0x0 | 0x1 | 0x2 | 0xB => 23,
+            0x3 => skip(10, vx == instruction.nn),
+            0x4 => skip(10, vx != instruction.nn),
+            0x5 => skip(14, vx == vy),
+            0x6 => 6,
+            0x7 => 10,
+            0x8 => 44,
+            0x9 => skip(14, vx != vy),
+            0xA => 12,
+            0xC => 36,
+            // Sprites are drawn a byte at a time, and it takes longer when the sprite isn't lined
+            // up with a byte of display memory since every row gets split in two
+            0xD => {
+                let rows = instruction.n as u32;
+                let per_row = if vx.is_multiple_of(8) { 24 } else { 46 };
+                68 + rows * per_row
+            }
+            0xE => skip(
+                14,
+                self.keypad[(vx & 0xF) as usize] == (instruction.nn == 0x9E),
+            ),
+            0xF => match instruction.nn {
+                0x1E => 19,
+                0x29 => 20,
+                0x33 => 204,
+                0x55 | 0x65 => 18 + 14 * (instruction.x as u32 + 1),
+                _ => 10,
             },
-            _ => println!("Instruction Unimplemented"),
+            _ => 10,
         }
-        // Setting this as a safety guard so that we're not keeping any previously held keys in the
-        // cpu memory!
-        self.current_key = 255;
+    }
+
+    // Both timers count down at 60Hz until they hit zero, this should be called once per frame
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
     }
 
     fn increment_program_counter(&mut self, value: u16) {
@@ -440,7 +774,74 @@ impl Cpu {
         }
     }
 
+    //The program counter has already moved past an instruction by the time it runs
+    fn current_instruction(&self) -> u16 {
+        self.program_counter.wrapping_sub(2) & 0xFFF
+    }
+
+    pub fn coverage(&self) -> &Coverage {
+        &self.coverage
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn index_register(&self) -> u16 {
+        self.index_register
+    }
+
+    //Addresses in the trace are named after labels when there are symbols to do it with
+    fn describe(&self, address: u16) -> String {
+        match &self.symbols {
+            Some(symbols) => symbols.name(address),
+            None => address.to_string(),
+        }
+    }
+
+    //Checked before every instruction when there are breakpoints. Stopping at one goes into step
+    //mode and says where we are and how we got there.
+    pub fn hit_breakpoint(&mut self) -> bool {
+        let address = self.program_counter;
+        let resumed_here = self.resumed_at.take() == Some(address);
+        if resumed_here || !self.breakpoints.contains(&address) {
+            return false;
+        }
+        self.step_mode = true;
+        let describe = |address: u16| match &self.symbols {
+            Some(symbols) => format!(
+                "{} ({address:#05X}){}",
+                symbols.name(address),
+                symbols
+                    .source_line(address)
+                    .map_or(String::new(), |line| format!(", {line}"))
+            ),
+            None => format!("{address:#05X}"),
+        };
+        println!("Breakpoint at {}", describe(address));
+        for return_address in self.stack.iter().rev() {
+            println!(
+                "  called from {}",
+                describe(return_address.wrapping_sub(2) & 0xFFF)
+            );
+        }
+        true
+    }
+
+    //The return addresses of every subroutine we're in, oldest first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    //How many subroutines deep we are
+    pub fn stack_depth(&self) -> usize {
+        self.stack.len()
+    }
+
     pub fn set_step_mode(&mut self, value: bool) {
+        if self.step_mode && !value {
+            self.resumed_at = Some(self.program_counter);
+        }
         self.step_mode = value;
     }
 
@@ -448,7 +849,7 @@ impl Cpu {
     //*hopefully* more readable format. I find it useful to compare this to the actual pixel data
     //from the ROM since it looks exactly like the sprite it represents except with 1's and 0's
     //What a cool format.
-    fn _dump_pixel_buffer(&self) {
+    pub fn dump_pixel_buffer(&self) {
         for y in 0..32 {
             print!("{{");
             for x in 0..64 {
@@ -462,3 +863,124 @@ impl Cpu {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn damaged_save_states_are_turned_away_untouched() {
+        let mut cpu = Cpu::new();
+        let good = cpu.save_state();
+        let damage: [fn(&mut SaveState); 4] = [
+            |state| state.program_counter = 0x1000,
+            |state| state.stack = vec![0x200; 17],
+            |state| state.stack = vec![0x1000],
+            |state| state.awaited_key = Some(16),
+        ];
+        for damage in damage {
+            let mut state = good.clone();
+            state.v_registers[0] = 1;
+            damage(&mut state);
+            assert_eq!(
+                cpu.load_state(&state),
+                Err("That save state is damaged".to_string())
+            );
+            assert_eq!(cpu.save_state(), good);
+        }
+    }
+
+    fn load(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_program_into_memory(&mut &program[..]).unwrap();
+        cpu
+    }
+
+    fn run(cpu: &mut Cpu, instructions: usize) {
+        for _ in 0..instructions {
+            let op = cpu.fetch_decoded();
+            cpu.execute(op);
+        }
+    }
+
+    #[test]
+    fn calls_stop_at_the_stack_limit() {
+        // 200: call 200
+        let mut cpu = load(&[0x22, 0x00]);
+        run(&mut cpu, STACK_LEVELS);
+        assert!(!cpu.should_halt);
+        run(&mut cpu, 1);
+        assert!(cpu.should_halt);
+        assert_eq!(cpu.stack_depth(), STACK_LEVELS);
+
+        // So a state saved from the deepest a ROM can go loads back fine
+        let state = cpu.save_state();
+        assert_eq!(cpu.load_state(&state), Ok(()));
+    }
+
+    #[test]
+    fn memory_wraps_round_past_i() {
+        let mut cpu = load(&[
+            0x60, 0xFE, // 200: V0 = 254
+            0xF0, 0x33, // 202: BCD of V0 at I
+            0xF1, 0x65, // 204: V0 to V1 = memory at I
+            0xF0, 0x1E, // 206: I += V0
+        ]);
+        let mut state = cpu.save_state();
+        state.index_register = 0xFFE;
+        cpu.load_state(&state).unwrap();
+
+        run(&mut cpu, 3);
+        assert_eq!(&cpu.memory()[0xFFE..], [2, 5]);
+        assert_eq!(cpu.memory()[0], 4);
+        assert_eq!(cpu.v_registers[..2], [2, 5]);
+
+        // A state with I past the end of memory is one a ROM can get itself into, so it has to load
+        cpu.index_register = 0xFFFF;
+        cpu.program_counter = 0x206;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.index_register(), 1);
+        let mut state = cpu.save_state();
+        state.index_register = 0x1234;
+        assert_eq!(cpu.load_state(&state), Ok(()));
+    }
+
+    #[test]
+    fn opcodes_either_side_of_the_ends_of_memory() {
+        let mut cpu = load(&[0x12, 0x34]);
+        cpu.memory[0xFFE] = 0xAB;
+        cpu.memory[0xFFF] = 0xCD;
+        cpu.program_counter = 0x200;
+        assert_eq!(cpu.fetch_opcodes(), [0, 0x1234, 0]);
+        cpu.program_counter = 0;
+        assert_eq!(cpu.fetch_opcodes()[0], 0xABCD);
+        cpu.program_counter = 0xFFE;
+        assert_eq!(cpu.fetch_opcodes(), [0, 0xABCD, 0]);
+        cpu.program_counter = 0xFFF;
+        assert_eq!(cpu.fetch_opcodes()[1], 0xCD00);
+    }
+
+    #[test]
+    fn vip_cycles_follow_the_measured_costs() {
+        let mut cpu = Cpu::new();
+        cpu.v_registers[0x1] = 8;
+        cpu.v_registers[0x2] = 3;
+        cpu.keypad[0x3] = true;
+        let cycles = |cpu: &Cpu, opcode| cpu.vip_cycles(&Instruction::from_opcode(opcode));
+
+        assert_eq!(cycles(&cpu, 0x00E0), 24);
+        assert_eq!(cycles(&cpu, 0x1200), 23);
+        assert_eq!(cycles(&cpu, 0x6105), 6);
+        assert_eq!(cycles(&cpu, 0x8124), 44);
+        assert_eq!(cycles(&cpu, 0xF233), 204);
+        assert_eq!(cycles(&cpu, 0xF355), 18 + 14 * 4);
+        //Skips cost a little more when they're taken
+        assert_eq!(cycles(&cpu, 0x3108), 12);
+        assert_eq!(cycles(&cpu, 0x3109), 10);
+        assert_eq!(cycles(&cpu, 0xE29E), 16);
+        assert_eq!(cycles(&cpu, 0xE19E), 14);
+        //A sprite that isn't lined up with a byte costs nearly twice as much a row
+        assert_eq!(cycles(&cpu, 0xD125), 68 + 5 * 24);
+        assert_eq!(cycles(&cpu, 0xD215), 68 + 5 * 46);
+    }
+}
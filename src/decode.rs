@@ -0,0 +1,218 @@
+use crate::cpu::Instruction;
+
+// An instruction worked out once, down to exactly which operation it is. Executing one of these is
+// a single flat match, where going from an Instruction means matching the top nibble and then
+// usually another one under it, every time the instruction runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    // 0NNN called machine code on the VIP, there's no machine code here so it does nothing
+    Nop,
+    ClearScreen,
+    Return,
+    Jump { nnn: u16 },
+    Call { nnn: u16 },
+    SkipIfEqual { x: u8, nn: u8 },
+    SkipIfNotEqual { x: u8, nn: u8 },
+    SkipIfRegistersEqual { x: u8, y: u8 },
+    SkipIfRegistersNotEqual { x: u8, y: u8 },
+    Load { x: u8, nn: u8 },
+    Add { x: u8, nn: u8 },
+    Move { x: u8, y: u8 },
+    Or { x: u8, y: u8 },
+    And { x: u8, y: u8 },
+    Xor { x: u8, y: u8 },
+    AddRegisters { x: u8, y: u8 },
+    Subtract { x: u8, y: u8 },
+    ShiftRight { x: u8, y: u8 },
+    SubtractReversed { x: u8, y: u8 },
+    ShiftLeft { x: u8, y: u8 },
+    LoadIndex { nnn: u16 },
+    // Which register gets added depends on the jumping quirk, so both are kept
+    JumpWithOffset { x: u8, nnn: u16 },
+    Random { x: u8, nn: u8 },
+    Draw { x: u8, y: u8, n: u8 },
+    SkipIfKey { x: u8 },
+    SkipIfNotKey { x: u8 },
+    LoadDelay { x: u8 },
+    WaitForKey { x: u8 },
+    SetDelay { x: u8 },
+    SetSound { x: u8 },
+    AddToIndex { x: u8 },
+    LoadFont { x: u8 },
+    StoreBcd { x: u8 },
+    StoreRegisters { x: u8 },
+    LoadRegisters { x: u8 },
+}
+
+impl Op {
+    // Anything that isn't a real instruction becomes a Nop, the same as the interpreter has always
+    // skipped over them
+    pub fn decode(instruction: &Instruction) -> Op {
+        let Instruction {
+            x, y, n, nn, nnn, ..
+        } = *instruction;
+        match instruction.instruction {
+            // These are told apart by their low nibbles only, which is how it's always been read
+            0x0 if n == 0xE => Op::Return,
+            0x0 if y == 0xE => Op::ClearScreen,
+            0x0 => Op::Nop,
+            0x1 => Op::Jump { nnn },
+            0x2 => Op::Call { nnn },
+            0x3 => Op::SkipIfEqual { x, nn },
+            0x4 => Op::SkipIfNotEqual { x, nn },
+            0x5 => Op::SkipIfRegistersEqual { x, y },
+            0x6 => Op::Load { x, nn },
+            0x7 => Op::Add { x, nn },
+            0x8 => match n {
+                0x0 => Op::Move { x, y },
+                0x1 => Op::Or { x, y },
+                0x2 => Op::And { x, y },
+                0x3 => Op::Xor { x, y },
+                0x4 => Op::AddRegisters { x, y },
+                0x5 => Op::Subtract { x, y },
+                0x6 => Op::ShiftRight { x, y },
+                0x7 => Op::SubtractReversed { x, y },
+                0xE => Op::ShiftLeft { x, y },
+                _ => Op::Nop,
+            },
+            0x9 => Op::SkipIfRegistersNotEqual { x, y },
+            0xA => Op::LoadIndex { nnn },
+            0xB => Op::JumpWithOffset { x, nnn },
+            0xC => Op::Random { x, nn },
+            0xD => Op::Draw { x, y, n },
+            0xE => match nn {
+                0x9E => Op::SkipIfKey { x },
+                0xA1 => Op::SkipIfNotKey { x },
+                _ => Op::Nop,
+            },
+            _ => match nn {
+                0x07 => Op::LoadDelay { x },
+                0x0A => Op::WaitForKey { x },
+                0x15 => Op::SetDelay { x },
+                0x18 => Op::SetSound { x },
+                0x1E => Op::AddToIndex { x },
+                0x29 => Op::LoadFont { x },
+                0x33 => Op::StoreBcd { x },
+                0x55 => Op::StoreRegisters { x },
+                0x65 => Op::LoadRegisters { x },
+                _ => Op::Nop,
+            },
+        }
+    }
+
+    // Anything that moves the program counter, could write over code, or has to be seen by
+    // whoever is running frames (drawing waits for the display). Compiled code can run everything
+    // else back to back without keeping the program counter up to date.
+    pub fn ends_block(self) -> bool {
+        matches!(
+            self,
+            Op::Return
+                | Op::Jump { .. }
+                | Op::Call { .. }
+                | Op::SkipIfEqual { .. }
+                | Op::SkipIfNotEqual { .. }
+                | Op::SkipIfRegistersEqual { .. }
+                | Op::SkipIfRegistersNotEqual { .. }
+                | Op::JumpWithOffset { .. }
+                | Op::Draw { .. }
+                | Op::SkipIfKey { .. }
+                | Op::SkipIfNotKey { .. }
+                | Op::WaitForKey { .. }
+                | Op::StoreBcd { .. }
+                | Op::StoreRegisters { .. }
+        )
+    }
+}
+
+// Every address's decoded instruction, filled in the first time the address gets run. Programs
+// very rarely write over their own code, but when they do (with FX33 or FX55) the entries covering
+// those bytes are thrown away so they get decoded again.
+pub struct DecodeCache {
+    entries: Vec<Option<Op>>,
+}
+
+impl DecodeCache {
+    pub fn new(memory_size: usize) -> Self {
+        DecodeCache {
+            entries: vec![None; memory_size],
+        }
+    }
+
+    pub fn get(&self, address: u16) -> Option<Op> {
+        self.entries[address as usize]
+    }
+
+    pub fn insert(&mut self, address: u16, op: Op) {
+        self.entries[address as usize] = Some(op);
+    }
+
+    // An instruction is two bytes, so the one starting just before the first byte is stale too
+    pub fn invalidate(&mut self, start: usize, len: usize) {
+        let from = start.saturating_sub(1);
+        let to = (start + len).min(self.entries.len());
+        for entry in &mut self.entries[from..to] {
+            *entry = None;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.fill(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    // Runs the program through the decode cache for this many instructions
+    fn run(program: &[u8], instructions: usize) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_program_into_memory(&mut &program[..]).unwrap();
+        for _ in 0..instructions {
+            let op = cpu.fetch_decoded();
+            cpu.execute(op);
+        }
+        cpu
+    }
+
+    #[test]
+    fn store_registers_drops_what_it_wrote_over() {
+        let program = [
+            0x62, 0x01, // 200: V2 = 1, then V2 = 7 once it's been written over
+            0x60, 0x62, // 202: V0 = 62
+            0x61, 0x07, // 204: V1 = 07
+            0xA2, 0x00, // 206: I = 200
+            0xF1, 0x55, // 208: store V0 and V1 over 200
+            0x12, 0x00, // 20A: jump 200
+        ];
+        let cpu = run(&program, 7);
+        assert_eq!(cpu.v_registers[2], 7);
+    }
+
+    #[test]
+    fn store_bcd_drops_the_instruction_it_wrote_half_of() {
+        let program = [
+            0x62, 0x01, // 200: V2 = 1, then V2 = 0 once its second byte has been written over
+            0x63, 0x05, // 202: V3 = 5
+            0xA2, 0x01, // 204: I = 201
+            0xF3, 0x33, // 206: store V3 as decimal over 201, 00 00 05
+            0x12, 0x00, // 208: jump 200
+        ];
+        let cpu = run(&program, 6);
+        assert_eq!(cpu.v_registers[2], 0);
+    }
+
+    #[test]
+    fn invalidate_only_drops_instructions_touching_the_range() {
+        let mut cache = DecodeCache::new(4096);
+        for address in 0x200..0x210 {
+            cache.insert(address, Op::Nop);
+        }
+        cache.invalidate(0x205, 3);
+        for address in 0x200..0x210 {
+            let dropped = (0x204..0x208).contains(&address);
+            assert_eq!(cache.get(address).is_none(), dropped, "{address:#05X}");
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use crate::coverage::CoverageExport;
+use crate::cpu::Instruction;
+use crate::symbols::Symbols;
+
+pub const PROGRAM_START: u16 = 0x200;
+
+// Where execution can go after an instruction, which is all we need to know to follow the code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    // Carries on to the next instruction
+    Next,
+    // Carries on to the next instruction, or skips over it
+    Skip,
+    Jump(u16),
+    // Goes off to a subroutine which comes back to the next instruction
+    Call(u16),
+    Return,
+    // BNNN, we can't know where this goes without running it
+    ComputedJump,
+}
+
+pub fn flow(opcode: u16) -> Flow {
+    let instruction = Instruction::from_opcode(opcode);
+    match instruction.instruction {
+        0x0 if opcode == 0x00EE => Flow::Return,
+        0x1 => Flow::Jump(instruction.nnn),
+        0x2 => Flow::Call(instruction.nnn),
+        0x3 | 0x4 | 0x5 | 0x9 => Flow::Skip,
+        0xB => Flow::ComputedJump,
+        0xE if instruction.nn == 0x9E || instruction.nn == 0xA1 => Flow::Skip,
+        _ => Flow::Next,
+    }
+}
+
+// Uses the common Cowgod style mnemonics
+pub fn mnemonic(opcode: u16) -> String {
+    let i = Instruction::from_opcode(opcode);
+    let (x, y, n, nn, nnn) = (i.x, i.y, i.n, i.nn, i.nnn);
+    match i.instruction {
+        0x0 => match opcode {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            _ => format!("SYS {nnn:#05X}"),
+        },
+        0x1 => format!("JP {nnn:#05X}"),
+        0x2 => format!("CALL {nnn:#05X}"),
+        0x3 => format!("SE V{x:X}, {nn:#04X}"),
+        0x4 => format!("SNE V{x:X}, {nn:#04X}"),
+        0x5 if n == 0 => format!("SE V{x:X}, V{y:X}"),
+        0x6 => format!("LD V{x:X}, {nn:#04X}"),
+        0x7 => format!("ADD V{x:X}, {nn:#04X}"),
+        0x8 => match n {
+            0x0 => format!("LD V{x:X}, V{y:X}"),
+            0x1 => format!("OR V{x:X}, V{y:X}"),
+            0x2 => format!("AND V{x:X}, V{y:X}"),
+            0x3 => format!("XOR V{x:X}, V{y:X}"),
+            0x4 => format!("ADD V{x:X}, V{y:X}"),
+            0x5 => format!("SUB V{x:X}, V{y:X}"),
+            0x6 => format!("SHR V{x:X}, V{y:X}"),
+            0x7 => format!("SUBN V{x:X}, V{y:X}"),
+            0xE => format!("SHL V{x:X}, V{y:X}"),
+            _ => unknown(opcode),
+        },
+        0x9 if n == 0 => format!("SNE V{x:X}, V{y:X}"),
+        0xA => format!("LD I, {nnn:#05X}"),
+        0xB => format!("JP V0, {nnn:#05X}"),
+        0xC => format!("RND V{x:X}, {nn:#04X}"),
+        0xD => format!("DRW V{x:X}, V{y:X}, {n}"),
+        0xE => match nn {
+            0x9E => format!("SKP V{x:X}"),
+            0xA1 => format!("SKNP V{x:X}"),
+            _ => unknown(opcode),
+        },
+        0xF => match nn {
+            0x07 => format!("LD V{x:X}, DT"),
+            0x0A => format!("LD V{x:X}, K"),
+            0x15 => format!("LD DT, V{x:X}"),
+            0x18 => format!("LD ST, V{x:X}"),
+            0x1E => format!("ADD I, V{x:X}"),
+            0x29 => format!("LD F, V{x:X}"),
+            0x33 => format!("LD B, V{x:X}"),
+            0x55 => format!("LD [I], V{x:X}"),
+            0x65 => format!("LD V{x:X}, [I]"),
+            _ => unknown(opcode),
+        },
+        _ => unknown(opcode),
+    }
+}
+
+fn unknown(opcode: u16) -> String {
+    format!("DW {opcode:#06X}")
+}
+
+fn opcode_at(rom: &[u8], offset: usize) -> Option<u16> {
+    if offset + 1 < rom.len() {
+        Some(((rom[offset] as u16) << 8) | rom[offset + 1] as u16)
+    } else {
+        None
+    }
+}
+
+// Follows every path through the program from the start, marking the bytes where an instruction
+// begins. Anything that's never reached is most likely sprites or other data. BNNN jumps can't be
+// followed without running the program, so code only reachable through one won't be found.
+pub fn find_code(rom: &[u8]) -> Vec<bool> {
+    follow_code(rom, vec![PROGRAM_START], &vec![false; rom.len()])
+}
+
+// Uses what a run saved with --coverage as well. Anything that ran is code, and gets followed from
+// too, which finds what's only reachable through BNNN. Anything that was read as data and never
+// ran is data, even where following the code would have run into it.
+pub fn find_code_with_coverage(rom: &[u8], coverage: &CoverageExport) -> Vec<bool> {
+    let mut ran = vec![false; rom.len()];
+    let mut starts = vec![PROGRAM_START];
+    for &address in &coverage.instructions {
+        if let Some(offset) = rom_offset(rom, address) {
+            ran[offset] = true;
+            if offset + 1 < ran.len() {
+                ran[offset + 1] = true;
+            }
+            starts.push(address);
+        }
+    }
+    let mut data = vec![false; rom.len()];
+    for &address in &coverage.read {
+        if let Some(offset) = rom_offset(rom, address) {
+            data[offset] = !ran[offset];
+        }
+    }
+    follow_code(rom, starts, &data)
+}
+
+fn rom_offset(rom: &[u8], address: u16) -> Option<usize> {
+    address
+        .checked_sub(PROGRAM_START)
+        .map(|o| o as usize)
+        .filter(|offset| *offset < rom.len())
+}
+
+fn follow_code(rom: &[u8], mut pending: Vec<u16>, data: &[bool]) -> Vec<bool> {
+    let mut is_code = vec![false; rom.len()];
+    while let Some(address) = pending.pop() {
+        let Some(offset) = address.checked_sub(PROGRAM_START).map(|o| o as usize) else {
+            continue;
+        };
+        let Some(opcode) = opcode_at(rom, offset) else {
+            continue;
+        };
+        if is_code[offset] || data[offset] || data[offset + 1] {
+            continue;
+        }
+        is_code[offset] = true;
+
+        let next = address + 2;
+        match flow(opcode) {
+            Flow::Next => pending.push(next),
+            Flow::Skip => {
+                pending.push(next);
+                pending.push(next + 2);
+            }
+            Flow::Jump(target) => pending.push(target),
+            Flow::Call(target) => {
+                pending.push(target);
+                pending.push(next);
+            }
+            Flow::Return | Flow::ComputedJump => {}
+        }
+    }
+    is_code
+}
+
+// A run of code that's only ever entered at the top and only leaves from the bottom
+pub struct BasicBlock {
+    pub start: u16,
+    pub opcodes: Vec<u16>,
+}
+
+impl BasicBlock {
+    // The address just past the last instruction
+    pub fn end(&self) -> u16 {
+        self.start + 2 * self.opcodes.len() as u16
+    }
+}
+
+// Drawing can end a frame, waiting for a key goes back over itself and writing to memory can
+// change the code after it, so as well as anything that changes where execution goes, these end a
+// block too
+fn ends_block(opcode: u16) -> bool {
+    let instruction = Instruction::from_opcode(opcode);
+    flow(opcode) != Flow::Next
+        || instruction.instruction == 0xD
+        || (instruction.instruction == 0xF && matches!(instruction.nn, 0x0A | 0x33 | 0x55))
+}
+
+// Splits the code find_code turns up into basic blocks, in address order. A block starts wherever
+// something jumps, calls, skips or returns to, and after anything that ends one.
+pub fn find_blocks(rom: &[u8]) -> Vec<BasicBlock> {
+    let is_code = find_code(rom);
+    let mut leaders = vec![false; rom.len()];
+    let mut mark = |address: u16| {
+        if let Some(offset) = address.checked_sub(PROGRAM_START).map(|o| o as usize)
+            && offset < leaders.len()
+        {
+            leaders[offset] = true;
+        }
+    };
+    mark(PROGRAM_START);
+    for offset in (0..rom.len()).filter(|offset| is_code[*offset]) {
+        let Some(opcode) = opcode_at(rom, offset) else {
+            continue;
+        };
+        let next = PROGRAM_START + offset as u16 + 2;
+        match flow(opcode) {
+            Flow::Skip => {
+                mark(next);
+                mark(next + 2);
+            }
+            Flow::Jump(target) | Flow::Call(target) => {
+                mark(target);
+                mark(next);
+            }
+            _ if ends_block(opcode) => mark(next),
+            _ => {}
+        }
+    }
+
+    let mut blocks = Vec::new();
+    for start in (0..rom.len()).filter(|offset| leaders[*offset] && is_code[*offset]) {
+        let mut opcodes = Vec::new();
+        let mut offset = start;
+        while let Some(opcode) = opcode_at(rom, offset)
+            && is_code[offset]
+            && (offset == start || !leaders[offset])
+        {
+            opcodes.push(opcode);
+            offset += 2;
+            if ends_block(opcode) {
+                break;
+            }
+        }
+        blocks.push(BasicBlock {
+            start: PROGRAM_START + start as u16,
+            opcodes,
+        });
+    }
+    blocks
+}
+
+// One line per instruction, and one per byte of data. Data gets drawn out in binary as well
+// since it's usually a sprite. With a coverage map, anything the program wrote over after it had
+// run gets pointed out. With symbols, labels get a line of their own the way Octo writes them,
+// addresses in instructions get named and each instruction says which source line it came from.
+pub fn disassemble(
+    rom: &[u8],
+    linear: bool,
+    coverage: Option<&CoverageExport>,
+    symbols: Option<&Symbols>,
+) -> Vec<String> {
+    let is_code = match coverage {
+        _ if linear => (0..rom.len()).map(|offset| offset % 2 == 0).collect(),
+        Some(coverage) => find_code_with_coverage(rom, coverage),
+        None => find_code(rom),
+    };
+    let mut modified_by: Vec<Option<u16>> = vec![None; rom.len()];
+    for change in coverage.map_or(&[][..], |coverage| &coverage.self_modifications[..]) {
+        if let Some(offset) = rom_offset(rom, change.address) {
+            modified_by[offset] = Some(change.written_by);
+        }
+    }
+    let note = |offset: usize, len: usize, target: Option<u16>| {
+        let address = PROGRAM_START + offset as u16;
+        let mut notes = Vec::new();
+        if let Some(symbols) = symbols {
+            notes.extend(target.map(|target| symbols.name(target)));
+            notes.extend(symbols.source_line(address));
+        }
+        notes.extend(
+            modified_by[offset..(offset + len).min(rom.len())]
+                .iter()
+                .find_map(|by| *by)
+                .map(|by| format!("self-modifying, written by {by:#05X}")),
+        );
+        notes
+            .iter()
+            .map(|note| format!("  ; {note}"))
+            .collect::<String>()
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = PROGRAM_START as usize + offset;
+        if let Some(label) = symbols.and_then(|symbols| symbols.label_at(address as u16)) {
+            lines.push(format!(": {label}"));
+        }
+        match opcode_at(rom, offset) {
+            Some(opcode) if is_code[offset] => {
+                let instruction = Instruction::from_opcode(opcode);
+                let target = matches!(instruction.instruction, 0x1 | 0x2 | 0xA | 0xB)
+                    .then_some(instruction.nnn);
+                lines.push(format!(
+                    "{address:#05X}  {opcode:04X}  {}{}",
+                    mnemonic(opcode),
+                    note(offset, 2, target)
+                ));
+                offset += 2;
+            }
+            _ => {
+                let byte = rom[offset];
+                let sprite: String = (0..8)
+                    .rev()
+                    .map(|bit| if byte & (1 << bit) != 0 { '#' } else { '.' })
+                    .collect();
+                lines.push(format!(
+                    "{address:#05X}  {byte:02X}    DB {byte:#04X}  ; {sprite}{}",
+                    note(offset, 1, None)
+                ));
+                offset += 1;
+            }
+        }
+    }
+    lines
+}
@@ -0,0 +1,452 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::backend::{AudioSink, DisplaySink, FrameView, HostEvent, InputSource};
+use crate::buzzer::Buzzer;
+use crate::cli::RunOptions;
+use crate::commands::{
+    create_cpu, finish_recording, read_rom, resolve_timing, start_movie, start_recording,
+};
+use crate::config::Config;
+use crate::cpu::{Cpu, Instruction};
+use crate::disasm::PROGRAM_START;
+use crate::movie::{MovieSession, pack_keypad, unpack_keypad};
+use crate::palette::{Palette, load_palettes, pick_palette};
+use crate::phosphor::Phosphor;
+use crate::profiler::Profiler;
+use crate::recorder::{Recorder, RecordingSettings};
+use crate::romdb::RomInfo;
+use crate::savestate::{SaveState, state_path};
+use crate::timing::{FrameClock, FrameRunner, SpeedControl, SpeedMeter, TimingMode};
+use crate::watcher::{FileWatcher, ReloadMode};
+
+// Everything about a run that isn't the frontend: the cpu, the frame clock, and all the things
+// that hang off it like sound, recording, movies and save states. The main loop lives here and
+// talks to whatever frontend it's given through the traits in backend.rs.
+pub struct Emulator {
+    cpu: Cpu,
+    // Kept for reloading the ROM, with the seed filled in if a replay needs one
+    options: RunOptions,
+    rom_info: Option<RomInfo>,
+    clock: FrameClock,
+    runner: FrameRunner,
+    speed: SpeedMeter,
+    speed_control: SpeedControl,
+    paused: bool,
+    // Set by a frame advance, the one frame to run while paused
+    advance_frame: bool,
+    buzzer: Buzzer,
+    wav_path: Option<PathBuf>,
+    phosphor: Phosphor,
+    palettes: Vec<Palette>,
+    palette_index: usize,
+    recorder: Option<Recorder>,
+    recording_settings: RecordingSettings,
+    movie: Option<MovieSession>,
+    // Held on to until the end so a bad replay still fails the run
+    replay_error: Option<io::Error>,
+    save_path: Option<PathBuf>,
+    opcodes: [u16; 3],
+    events: Vec<HostEvent>,
+    timing_mode: TimingMode,
+    watcher: Option<FileWatcher>,
+    // The keypad on every frame since the ROM was loaded, for ReloadMode::Replay
+    input_log: Vec<u16>,
+    profile_path: Option<PathBuf>,
+    coverage_path: Option<PathBuf>,
+}
+
+impl Emulator {
+    pub fn new(options: &RunOptions, config: &Config) -> io::Result<Self> {
+        let (mut cpu, rom_info) = create_cpu(options)?;
+        let mut timing = resolve_timing(config, options, rom_info.as_ref());
+        let movie = start_movie(options, &mut cpu, &mut timing)?;
+
+        let mut options = options.clone();
+        let watcher = options
+            .watch
+            .then(|| FileWatcher::new(Path::new(&options.rom)));
+        // Replaying into the new code only ends up in the same place if CXNN does too
+        if options.watch && options.reload_mode == ReloadMode::Replay && options.seed.is_none() {
+            let seed = rand::random();
+            options.seed = Some(seed);
+            cpu.seed_rng(seed);
+        }
+
+        let mut palettes = load_palettes(config);
+        let database_colours = rom_info.as_ref().and_then(|info| info.colours);
+        let palette_index = pick_palette(&mut palettes, &options, config, database_colours)?;
+
+        let recorder = match &options.record {
+            Some(path) => Some(start_recording(path, config)?),
+            None => None,
+        };
+        let mut buzzer = Buzzer::from_config(config);
+        let wav_path = config.get("audio", "wav").map(PathBuf::from);
+        if wav_path.is_some() {
+            buzzer.start_recording();
+        }
+
+        let mut runner = FrameRunner::new(timing.mode);
+        if options.profile.is_some() {
+            runner.profiler = Some(Profiler::new());
+        }
+
+        Ok(Emulator {
+            cpu,
+            rom_info,
+            clock: FrameClock::new(timing.max_catch_up_frames),
+            runner,
+            speed: SpeedMeter::new(),
+            speed_control: SpeedControl::new(),
+            paused: false,
+            advance_frame: false,
+            buzzer,
+            wav_path,
+            phosphor: Phosphor::from_config(config),
+            palettes,
+            palette_index,
+            recorder,
+            recording_settings: RecordingSettings::from_config(config),
+            movie,
+            replay_error: None,
+            save_path: state_path(&options.rom),
+            opcodes: Default::default(),
+            events: Vec::new(),
+            timing_mode: timing.mode,
+            watcher,
+            input_log: Vec::new(),
+            profile_path: options.profile.clone(),
+            coverage_path: options.coverage.clone(),
+            options,
+        })
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn rom_info(&self) -> Option<&RomInfo> {
+        self.rom_info.as_ref()
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.movie.as_ref().is_some_and(|movie| movie.is_playing())
+    }
+
+    // Runs in real time until the frontend wants to stop
+    pub fn run<F: InputSource + DisplaySink>(
+        &mut self,
+        frontend: &mut F,
+        audio: &mut impl AudioSink,
+    ) -> io::Result<()> {
+        while !frontend.should_quit() {
+            let frames = self.speed_control.scale(self.clock.frames_due());
+            self.tick(frontend, audio, frames)?;
+        }
+        Ok(())
+    }
+
+    // One pass of the main loop: read the input, run however many frames are due, then hand the
+    // frontend the sound and the picture
+    pub fn tick<F: InputSource + DisplaySink>(
+        &mut self,
+        frontend: &mut F,
+        audio: &mut impl AudioSink,
+        frames: u32,
+    ) -> io::Result<()> {
+        if let Some(watcher) = self.watcher.as_mut()
+            && watcher.changed()
+        {
+            // A ROM that doesn't load, e.g. one that's grown too big, leaves the old one running
+            match self.reload(self.options.reload_mode) {
+                Ok(()) => println!("Reloaded {}", self.options.rom),
+                Err(err) => eprintln!("Couldn't reload {}: {err}", self.options.rom),
+            }
+        }
+
+        let mut events = std::mem::take(&mut self.events);
+        frontend.poll(&mut self.cpu.keypad, &mut events);
+        for event in events.drain(..) {
+            self.handle_event(event)?;
+        }
+        self.events = events;
+        let frames = if self.paused {
+            std::mem::take(&mut self.advance_frame) as u32
+        } else {
+            frames
+        };
+
+        // Every emulated frame does the same amount of work and then ticks the timers once, so the
+        // game runs at the same speed however often we actually get to draw
+        for _ in 0..frames {
+            self.run_frame()?;
+        }
+
+        let wanted = audio.samples_wanted();
+        if wanted > 0 {
+            audio.play(&self.buzzer.take_samples(wanted));
+        }
+
+        frontend.present(&FrameView {
+            cpu: &self.cpu,
+            pixels: self.phosphor.intensities(),
+            palette: &self.palettes[self.palette_index],
+            opcodes: self.opcodes,
+            frames_per_second: self.speed.frames_per_second,
+            instructions_per_second: self.speed.instructions_per_second,
+            paused: self.paused,
+            speed: self.speed_control.multiplier(),
+        });
+        Ok(())
+    }
+
+    fn run_frame(&mut self) -> io::Result<()> {
+        let cpu = &mut self.cpu;
+        let mut running = !cpu.step_mode && !cpu.should_halt;
+        if running
+            && let Some(session) = self.movie.as_mut()
+            && !session.feed_input(cpu)
+        {
+            // The replay has run out, so check it and stop on the last frame
+            if let Some(finished) = self.movie.take()
+                && let Err(err) = finished.finish(cpu)
+            {
+                self.replay_error = Some(err);
+            }
+            cpu.should_halt = true;
+            running = false;
+        }
+
+        let mut executed: u64 = 0;
+        if running {
+            if self.watcher.is_some() {
+                self.input_log.push(pack_keypad(&cpu.keypad));
+            }
+            executed = self.runner.run_frame(cpu);
+            self.opcodes = cpu.fetch_opcodes();
+        }
+        self.buzzer.render_frame(running && cpu.sound_timer > 0);
+        self.phosphor.update(&cpu.pixel_buffer);
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.add_frame(
+                self.phosphor.intensities(),
+                &self.palettes[self.palette_index],
+            )?;
+        }
+        self.speed.record_frame(executed);
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: HostEvent) -> io::Result<()> {
+        match event {
+            HostEvent::ToggleMute => self.buzzer.muted = !self.buzzer.muted,
+            HostEvent::NextPalette => {
+                self.palette_index = (self.palette_index + 1) % self.palettes.len();
+            }
+            HostEvent::NextPersistence => self.phosphor.cycle_mode(),
+            HostEvent::ToggleRecording => match self.recorder.take() {
+                Some(finished) => finish_recording(finished)?,
+                None => {
+                    let settings = &self.recording_settings;
+                    let path = settings.new_path(&self.options.rom);
+                    self.recorder = Some(Recorder::start(&path, settings.format, settings.scale)?);
+                    println!("Recording to {}", path.display());
+                }
+            },
+            HostEvent::SaveState => {
+                if let Some(path) = &self.save_path {
+                    match self.cpu.save_state().write(path) {
+                        Ok(()) => println!("Saved state to {}", path.display()),
+                        Err(err) => eprintln!("Couldn't save state to {}: {err}", path.display()),
+                    }
+                }
+            }
+            // Loading a state in the middle of a movie would throw the replay off
+            HostEvent::LoadState if self.movie.is_none() => {
+                if let Some(path) = &self.save_path {
+                    let loaded = SaveState::read(path)
+                        .map_err(|err| err.to_string())
+                        .and_then(|state| self.cpu.load_state(&state));
+                    match loaded {
+                        Ok(()) => println!("Loaded state from {}", path.display()),
+                        Err(err) => {
+                            eprintln!("Couldn't load state from {}: {err}", path.display())
+                        }
+                    }
+                }
+            }
+            // Single steps aren't part of a movie either
+            HostEvent::Step
+                if self.cpu.step_mode && !self.cpu.should_halt && self.movie.is_none() =>
+            {
+                self.opcodes = self.cpu.fetch_opcodes();
+                let instruction: Instruction = self.cpu.fetch();
+                self.cpu.decode_and_execute(instruction);
+            }
+            HostEvent::TogglePause => self.paused = !self.paused,
+            HostEvent::FrameAdvance => {
+                self.paused = true;
+                self.advance_frame = true;
+            }
+            HostEvent::ToggleStepMode => self.cpu.set_step_mode(!self.cpu.step_mode),
+            // A reset is a fresh start, which a movie can't follow either
+            HostEvent::Reset if self.movie.is_none() => match self.reload(ReloadMode::Reset) {
+                Ok(()) => println!("Reset {}", self.options.rom),
+                Err(err) => eprintln!("Couldn't reset {}: {err}", self.options.rom),
+            },
+            HostEvent::Faster => self.speed_control.faster(),
+            HostEvent::Slower => self.speed_control.slower(),
+            HostEvent::LoadState | HostEvent::Step | HostEvent::Reset => {}
+        }
+        Ok(())
+    }
+
+    // Starts the ROM again from the file on disk with a fresh cpu, then puts it back where it was
+    // as far as the reload mode allows
+    fn reload(&mut self, mode: ReloadMode) -> io::Result<()> {
+        let (mut cpu, _) = create_cpu(&self.options)?;
+        cpu.set_step_mode(self.cpu.step_mode);
+        let mut runner = FrameRunner::new(self.timing_mode);
+
+        match mode {
+            ReloadMode::Reset => self.input_log.clear(),
+            ReloadMode::State => {
+                if let Some(path) = &self.save_path
+                    && path.exists()
+                {
+                    let mut state = SaveState::read(path)?;
+                    // The state's copy of the program is the old one, so the new one goes over it
+                    let rom = read_rom(&self.options.rom)?;
+                    let start = PROGRAM_START as usize;
+                    if let Some(program) = state.memory.get_mut(start..start + rom.len()) {
+                        program.copy_from_slice(&rom);
+                    }
+                    state.rom_sha1 = cpu.rom_sha1.clone();
+                    cpu.load_state(&state)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                }
+            }
+            ReloadMode::Replay => {
+                for &keys in &self.input_log {
+                    if cpu.should_halt {
+                        break;
+                    }
+                    cpu.keypad = unpack_keypad(keys);
+                    runner.run_frame(&mut cpu);
+                }
+            }
+        }
+
+        // Replayed frames aren't part of the profile, but everything before the reload still is
+        runner.profiler = self.runner.profiler.take();
+        self.cpu = cpu;
+        self.runner = runner;
+        self.opcodes = self.cpu.fetch_opcodes();
+        Ok(())
+    }
+
+    // Saves anything that was being recorded, and fails if a replay didn't match
+    pub fn finish(self) -> io::Result<()> {
+        if let Some(recorder) = self.recorder {
+            finish_recording(recorder)?;
+        }
+        if let Some(session) = self.movie {
+            session.finish(&self.cpu)?;
+        }
+        if let Some(path) = self.wav_path {
+            self.buzzer.save_recording(&path)?;
+            println!("Saved session audio to {}", path.display());
+        }
+        if let (Some(mut profiler), Some(path)) = (self.runner.profiler, self.profile_path) {
+            for line in profiler.report() {
+                println!("{line}");
+            }
+            profiler.write_trace(&path)?;
+            println!("Saved the call trace to {}", path.display());
+        }
+        // Only what ran since the last reset or reload, the same as the memory viewer shows
+        if let Some(path) = self.coverage_path {
+            let coverage = self.cpu.coverage();
+            for change in coverage.self_modifications() {
+                println!(
+                    "Self-modifying code: {:#05X} wrote over {:#05X} after it had run",
+                    change.written_by, change.address
+                );
+            }
+            coverage.export(&self.cpu.rom_sha1).write(&path)?;
+            println!("Saved the coverage map to {}", path.display());
+        }
+        match self.replay_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::NullBackend;
+    use crate::cli::{Command, parse_args};
+    use crate::movie::state_hash;
+
+    // Counts in V2 while key 0 is held and adds up random numbers in V3, so a replay only ends up
+    // in the same place if it gets the keys and the seed right
+    const ROM: [u8; 12] = [
+        0xC0, 0xFF, // 200: V0 = random
+        0xE1, 0x9E, // 202: skip if key V1 is down
+        0x12, 0x08, // 204: jump 208
+        0x72, 0x01, // 206: V2 += 1
+        0x83, 0x04, // 208: V3 += V0
+        0x12, 0x00, // 20A: jump 200
+    ];
+
+    fn headless(args: &[&str]) -> RunOptions {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        match parse_args(&args) {
+            Ok(Command::Run(options)) => options,
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn a_replayed_movie_ends_where_the_recording_did() {
+        let temp = std::env::temp_dir();
+        let rom = temp.join(format!("chipp-test-{}.ch8", std::process::id()));
+        let movie = temp.join(format!("chipp-test-{}.movie", std::process::id()));
+        std::fs::write(&rom, ROM).unwrap();
+        let rom = rom.to_str().unwrap();
+        let movie_arg = movie.to_str().unwrap();
+        let config = Config::parse("");
+
+        let options = headless(&[rom, "--headless", "--record-movie", movie_arg]);
+        let mut recording = Emulator::new(&options, &config).unwrap();
+        let mut frontend = NullBackend::default();
+        let mut audio = NullBackend::default();
+        for frame in 0..30 {
+            frontend.keypad[0] = frame % 7 < 3;
+            recording.tick(&mut frontend, &mut audio, 1).unwrap();
+        }
+        let recorded = state_hash(recording.cpu());
+        assert_ne!(recording.cpu().v_registers[2], 0);
+        recording.finish().unwrap();
+
+        // Nothing held down this time, the keys have to come from the movie
+        let options = headless(&[rom, "--headless", "--play-movie", movie_arg]);
+        let mut replay = Emulator::new(&options, &config).unwrap();
+        let mut frontend = NullBackend::default();
+        assert!(replay.is_replaying());
+        // The tick after the last recorded frame checks the hash and halts
+        while !replay.cpu().should_halt {
+            replay.tick(&mut frontend, &mut audio, 1).unwrap();
+        }
+        assert_eq!(frontend.frames_presented, 31);
+        assert_eq!(state_hash(replay.cpu()), recorded);
+        let finished = replay.finish();
+
+        std::fs::remove_file(rom).unwrap();
+        std::fs::remove_file(&movie).unwrap();
+        finished.unwrap();
+    }
+}
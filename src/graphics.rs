@@ -1,230 +1,434 @@
 use raylib::prelude::*;
 
+use crate::coverage::{EXECUTED, READ, SELF_MODIFIED, WRITTEN};
 use crate::cpu::Cpu;
+use crate::keymap::KEYPAD_LAYOUT;
+use crate::palette::Palette;
+use crate::phosphor::Intensities;
 
-pub const WINDOW_WIDTH: i32 = 1250;
-pub const WINDOW_HEIGHT: i32 = 500;
+// Everything inside a debug panel is laid out relative to its top left corner, so these are the
+// only sizes the layout needs to know about
+const PANEL_WIDTH: i32 = 305;
+const PANEL_HEIGHT: i32 = 460;
+// The memory viewer shows all 4096 bytes as a 64x64 grid of these
+const MEMORY_CELL_SIZE: i32 = 4;
+// Coverage is drawn over the memory in the same colours whatever the palette, so they always mean
+// the same thing. Later ones win where a byte is more than one.
+const COVERAGE_COLOURS: [(u8, &str, Color); 4] = [
+    (READ, "read", Color::new(80, 140, 255, 255)),
+    (EXECUTED, "executed", Color::new(60, 200, 90, 255)),
+    (WRITTEN, "written", Color::new(255, 170, 40, 255)),
+    (SELF_MODIFIED, "self-modified", Color::new(255, 50, 50, 255)),
+];
+// Room under the game screen for the speed readout
+const STATUS_HEIGHT: i32 = 40;
+const MIN_BORDER_HEIGHT: i32 = 90;
+// Below this the game gets too small to play next to the panels, so they move underneath it
+const MIN_SIDE_BY_SIDE_SCALE: i32 = 4;
 
-const GAME_WIDTH: f32 = 640.0;
-const GAME_HEIGHT: f32 = 320.0;
-const GAME_PIXEL_SCALE_FACTOR: i32 = 10;
+pub fn window_size(scale: i32) -> (i32, i32) {
+    let width = PANEL_WIDTH * 2 + 64 * scale;
+    let height = (PANEL_HEIGHT + STATUS_HEIGHT).max(32 * scale + MIN_BORDER_HEIGHT * 2);
+    (width, height)
+}
+
+// The smallest window we let anyone resize down to, which still fits the game at 1x
+pub const MIN_WINDOW_SIZE: (i32, i32) = (64, 32);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Area {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
 
-pub fn draw_game_pixels(d: &mut RaylibDrawHandle, cpu: &Cpu) {
-    d.clear_background(Color::DARKBLUE);
-    for y in 0..32 {
-        for x in 0..64 {
-            if cpu.pixel_buffer[y][x] {
+impl Area {
+    fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Area {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+// Where everything goes for the current window size. The game screen is always scaled by a whole
+// number so every CHIP-8 pixel is the same size, and it gets whatever room the panels leave.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub game: Area,
+    pub scale: i32,
+    // The CPU state on the left and the keypad on the right, None when they're hidden
+    pub cpu_panel: Option<Area>,
+    pub keypad_panel: Option<Area>,
+    pub status: Option<Area>,
+}
+
+impl Layout {
+    pub fn compute(window_width: i32, window_height: i32, show_debug: bool) -> Self {
+        if show_debug {
+            // Panels either side of the game, the way it's always looked
+            let middle_width = window_width - PANEL_WIDTH * 2;
+            let middle_height = window_height - STATUS_HEIGHT;
+            if fit_scale(middle_width, middle_height) >= MIN_SIDE_BY_SIDE_SCALE
+                && window_height >= PANEL_HEIGHT
+            {
+                let middle = Area::new(PANEL_WIDTH, 0, middle_width, middle_height);
+                return Layout::with_panels(
+                    middle,
+                    Area::new(0, 0, PANEL_WIDTH, PANEL_HEIGHT),
+                    Area::new(PANEL_WIDTH + middle_width, 0, PANEL_WIDTH, PANEL_HEIGHT),
+                );
+            }
+
+            // Too narrow, so the panels go side by side underneath the game instead
+            let top_height = window_height - PANEL_HEIGHT - STATUS_HEIGHT;
+            if window_width >= PANEL_WIDTH * 2 && fit_scale(window_width, top_height) >= 1 {
+                let panels_x = (window_width - PANEL_WIDTH * 2) / 2;
+                let panels_y = top_height + STATUS_HEIGHT;
+                return Layout::with_panels(
+                    Area::new(0, 0, window_width, top_height),
+                    Area::new(panels_x, panels_y, PANEL_WIDTH, PANEL_HEIGHT),
+                    Area::new(panels_x + PANEL_WIDTH, panels_y, PANEL_WIDTH, PANEL_HEIGHT),
+                );
+            }
+            // And if they don't fit there either the window is just too small for them
+        }
+
+        let (game, scale) = centre_game(Area::new(0, 0, window_width, window_height));
+        Layout {
+            game,
+            scale,
+            cpu_panel: None,
+            keypad_panel: None,
+            status: None,
+        }
+    }
+
+    fn with_panels(game_space: Area, cpu_panel: Area, keypad_panel: Area) -> Self {
+        let (game, scale) = centre_game(game_space);
+        Layout {
+            game,
+            scale,
+            cpu_panel: Some(cpu_panel),
+            keypad_panel: Some(keypad_panel),
+            status: Some(Area::new(
+                game_space.x,
+                game_space.y + game_space.height,
+                game_space.width,
+                STATUS_HEIGHT,
+            )),
+        }
+    }
+}
+
+// The biggest whole number scale the 64x32 screen fits into
+fn fit_scale(width: i32, height: i32) -> i32 {
+    (width / 64).min(height / 32)
+}
+
+fn centre_game(space: Area) -> (Area, i32) {
+    let scale = fit_scale(space.width, space.height).max(1);
+    let game = Area::new(
+        space.x + (space.width - 64 * scale) / 2,
+        space.y + (space.height - 32 * scale) / 2,
+        64 * scale,
+        32 * scale,
+    );
+    (game, scale)
+}
+
+// Draws what comes out of the phosphor stage rather than the raw pixel buffer, so pixels that are
+// fading out get a colour part way to the background
+pub fn draw_game_pixels(
+    d: &mut RaylibDrawHandle,
+    pixels: &Intensities,
+    layout: &Layout,
+    palette: &Palette,
+) {
+    let game = layout.game;
+    let scale = layout.scale;
+    d.draw_rectangle(
+        game.x,
+        game.y,
+        game.width,
+        game.height,
+        palette.background(),
+    );
+    for (y, row) in pixels.iter().enumerate() {
+        for (x, &intensity) in row.iter().enumerate() {
+            if intensity > 0.0 {
                 d.draw_rectangle(
-                    (x as i32 * GAME_PIXEL_SCALE_FACTOR) + (WINDOW_WIDTH / 2)
-                        - (GAME_WIDTH as i32 / 2),
-                    (y as i32 * GAME_PIXEL_SCALE_FACTOR) + (WINDOW_HEIGHT / 2)
-                        - (GAME_HEIGHT as i32 / 2),
-                    GAME_PIXEL_SCALE_FACTOR,
-                    GAME_PIXEL_SCALE_FACTOR,
-                    Color::LIGHTBLUE,
+                    game.x + x as i32 * scale,
+                    game.y + y as i32 * scale,
+                    scale,
+                    scale,
+                    palette.shade(intensity),
                 );
             }
         }
     }
 }
 
-pub fn draw_ui_elements(d: &mut RaylibDrawHandle, cpu: &mut Cpu, opcode_strings: [u16; 3]) {
-    d.draw_rectangle(0, 0, 305, WINDOW_HEIGHT, Color::BLACK); // Left Black Background
-    d.draw_rectangle(945, 0, 320, WINDOW_HEIGHT, Color::BLACK); // Right Black Background
-    d.draw_rectangle(305, 0, 640, 90, Color::BLACK); // Top Border
-    d.draw_rectangle(305, WINDOW_HEIGHT - 90, 640, 100, Color::BLACK); // Bottom Border
+// Sits underneath the game screen, says whether we're running and if we're keeping up
+pub fn draw_status_bar(d: &mut RaylibDrawHandle, layout: &Layout, text: &str, palette: &Palette) {
+    let Some(status) = layout.status else {
+        return;
+    };
+    d.draw_text(text, status.x + 15, status.y + 10, 20, palette.ui_text);
+}
+
+pub fn draw_ui_elements(
+    d: &mut RaylibDrawHandle,
+    cpu: &Cpu,
+    layout: &Layout,
+    opcode_strings: [u16; 3],
+    palette: &Palette,
+    show_memory: bool,
+) {
+    d.clear_background(palette.ui_background);
+    if let Some(panel) = layout.cpu_panel {
+        draw_cpu_panel(d, cpu, panel, opcode_strings, palette);
+    }
+    if let Some(panel) = layout.keypad_panel {
+        if show_memory {
+            draw_memory_panel(d, cpu, panel, palette);
+        } else {
+            draw_keypad_panel(d, &cpu.keypad, panel, palette);
+        }
+    }
+}
+
+fn draw_cpu_panel(
+    d: &mut RaylibDrawHandle,
+    cpu: &Cpu,
+    panel: Area,
+    opcode_strings: [u16; 3],
+    palette: &Palette,
+) {
+    let (x, y) = (panel.x, panel.y);
     // Current, next and previous instruction list
-    d.draw_rectangle_lines(115, 35, 60, 120, Color::WHITE); // Instruction List Box
-    d.draw_rectangle_lines(115, 75, 60, 40, Color::WHITE); // Current Instruction Box
+    d.draw_rectangle_lines(x + 115, y + 35, 60, 120, palette.ui_text); // Instruction List Box
+    d.draw_rectangle_lines(x + 115, y + 75, 60, 40, palette.ui_text); // Current Instruction Box
     d.draw_text(
         &format!("{:04X}", &opcode_strings[0]),
-        122,
-        45,
+        x + 122,
+        y + 45,
         20,
-        Color::WHITE,
+        palette.ui_text,
     ); // Prev Instruction
     d.draw_text(
         &format!("{:04X}", &opcode_strings[1]),
-        122,
-        85,
+        x + 122,
+        y + 85,
         20,
-        Color::WHITE,
+        palette.ui_text,
     ); //Current Instruction
     d.draw_text(
         &format!("{:04X}", &opcode_strings[2]),
-        122,
-        125,
+        x + 122,
+        y + 125,
         20,
-        Color::WHITE,
+        palette.ui_text,
     ); // Next Instruction
     // Current, next and previous instruction list
-    d.draw_text("PC", 50, 50, 20, Color::WHITE); // Program Counter Label
-    d.draw_rectangle_lines(35, 75, 60, 40, Color::WHITE); // Program Counter Box
+    d.draw_text("PC", x + 50, y + 50, 20, palette.ui_text); // Program Counter Label
+    d.draw_rectangle_lines(x + 35, y + 75, 60, 40, palette.ui_text); // Program Counter Box
     d.draw_text(
         &format!("{}", cpu.program_counter),
-        50,
-        86,
+        x + 50,
+        y + 86,
         20,
-        Color::WHITE,
+        palette.ui_text,
     ); // Program Counter Value
+    draw_call_stack(d, cpu, x + 190, y + 35, palette);
+    // Where we are in the source, when the ROM came with symbols
+    if let Some(symbols) = &cpu.symbols {
+        let address = cpu.program_counter;
+        d.draw_text(
+            &fit(&symbols.name(address), 38),
+            x + 20,
+            y + 162,
+            15,
+            palette.ui_text,
+        );
+        if let Some(line) = symbols.source_line(address) {
+            d.draw_text(&fit(&line, 38), x + 20, y + 180, 15, palette.ui_text);
+        }
+    }
     // V REGISTER RENDERING //
     let mut row: i32 = 0;
-    let mut count: i32 = 1;
     let mut offset: i32 = 0;
     for register in 0..=15 {
-        d.draw_rectangle_lines(20 + offset, 225 + row, 40, 40, Color::WHITE); // V Register Box
+        d.draw_rectangle_lines(x + 20 + offset, y + 225 + row, 40, 40, palette.ui_text); // V Register Box
         d.draw_text(
             &format!("{:02X}", cpu.v_registers[register]),
-            28 + offset,
-            236 + row,
+            x + 28 + offset,
+            y + 236 + row,
             20,
-            Color::WHITE,
+            palette.ui_text,
         ); // V Register Value
         d.draw_text(
             &format!("V{register:01X}",),
-            25 + offset,
-            205 + row,
+            x + 25 + offset,
+            y + 205 + row,
             20,
-            Color::WHITE,
+            palette.ui_text,
         ); // V Register Label
         offset += 70;
-        if count % 4 == 0 {
+        if (register + 1) % 4 == 0 {
             row += 70;
             offset = 0;
         }
-        count += 1;
     }
     // V REGISTER RENDERING //
+}
 
-    // KEYPAD //
-    // ONE //
-    if d.is_key_up(KeyboardKey::KEY_ONE) {
-        d.draw_rectangle_lines(970, 50, 65, 65, Color::WHITE);
-        d.draw_text("1", 995, 60, 50, Color::WHITE);
-    } else {
-        cpu.current_key = 1;
-        d.draw_rectangle(970, 45, 65, 65, Color::WHITE);
-        d.draw_text("1", 995, 60, 50, Color::BLACK);
-    }
-    // TWO //
-    if d.is_key_up(KeyboardKey::KEY_TWO) {
-        d.draw_rectangle_lines(1035, 50, 65, 65, Color::WHITE);
-        d.draw_text("2", 1060, 60, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1035, 45, 65, 65, Color::WHITE);
-        d.draw_text("2", 1060, 60, 50, Color::BLACK);
-    }
-    // THREE //
-    if d.is_key_up(KeyboardKey::KEY_THREE) {
-        d.draw_rectangle_lines(1100, 50, 65, 65, Color::WHITE);
-        d.draw_text("3", 1125, 60, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1100, 45, 65, 65, Color::WHITE);
-        d.draw_text("3", 1125, 60, 50, Color::BLACK);
-    }
-    // C //
-    if d.is_key_up(KeyboardKey::KEY_FOUR) {
-        d.draw_rectangle_lines(1165, 50, 65, 65, Color::WHITE);
-        d.draw_text("C", 1190, 60, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1165, 45, 65, 65, Color::WHITE);
-        d.draw_text("C", 1190, 60, 50, Color::BLACK);
-    }
-    // FOUR //
-    if d.is_key_up(KeyboardKey::KEY_Q) {
-        d.draw_rectangle_lines(970, 115, 65, 65, Color::WHITE);
-        d.draw_text("4", 995, 125, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(970, 110, 65, 65, Color::WHITE);
-        d.draw_text("4", 995, 125, 50, Color::BLACK);
-    }
-    // FIVE //
-    if d.is_key_up(KeyboardKey::KEY_W) {
-        d.draw_rectangle_lines(1035, 115, 65, 65, Color::WHITE);
-        d.draw_text("5", 1060, 125, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1035, 110, 65, 65, Color::WHITE);
-        d.draw_text("5", 1060, 125, 50, Color::BLACK);
-    }
-    // SIX //
-    if d.is_key_up(KeyboardKey::KEY_E) {
-        d.draw_rectangle_lines(1100, 115, 65, 65, Color::WHITE);
-        d.draw_text("6", 1125, 125, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1100, 110, 65, 65, Color::WHITE);
-        d.draw_text("6", 1125, 125, 50, Color::BLACK);
-    }
-    // D //
-    if d.is_key_up(KeyboardKey::KEY_R) {
-        d.draw_rectangle_lines(1165, 115, 65, 65, Color::WHITE);
-        d.draw_text("D", 1190, 125, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1165, 110, 65, 65, Color::WHITE);
-        d.draw_text("D", 1190, 125, 50, Color::BLACK);
-    }
-    // SEVEN //
-    if d.is_key_up(KeyboardKey::KEY_A) {
-        d.draw_rectangle_lines(970, 180, 65, 65, Color::WHITE);
-        d.draw_text("7", 995, 190, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(970, 175, 65, 65, Color::WHITE);
-        d.draw_text("7", 995, 190, 50, Color::BLACK);
+// The subroutines we're in, innermost first, named by where they were called from
+fn draw_call_stack(d: &mut RaylibDrawHandle, cpu: &Cpu, x: i32, y: i32, palette: &Palette) {
+    const SHOWN: usize = 5;
+    d.draw_text("Stack", x, y, 15, palette.ui_text);
+    let stack = cpu.stack();
+    for (row, return_address) in stack.iter().rev().take(SHOWN).enumerate() {
+        let call = return_address.wrapping_sub(2) & 0xFFF;
+        let name = match &cpu.symbols {
+            Some(symbols) => symbols.name(call),
+            None => format!("{call:#05X}"),
+        };
+        d.draw_text(
+            &fit(&name, 13),
+            x,
+            y + 20 + row as i32 * 18,
+            15,
+            palette.ui_text,
+        );
     }
-    // EIGHT //
-    if d.is_key_up(KeyboardKey::KEY_S) {
-        d.draw_rectangle_lines(1035, 180, 65, 65, Color::WHITE);
-        d.draw_text("8", 1060, 190, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1035, 175, 65, 65, Color::WHITE);
-        d.draw_text("8", 1060, 190, 50, Color::BLACK);
+    if stack.len() > SHOWN {
+        let more = format!("+{} more", stack.len() - SHOWN);
+        d.draw_text(&more, x, y + 20 + SHOWN as i32 * 18, 15, palette.ui_text);
     }
-    // NINE //
-    if d.is_key_up(KeyboardKey::KEY_D) {
-        d.draw_rectangle_lines(1100, 180, 65, 65, Color::WHITE);
-        d.draw_text("9", 1125, 190, 50, Color::WHITE);
+}
+
+// Cuts text down to fit a panel, with an ellipsis to show something's missing
+fn fit(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        text.to_string()
     } else {
-        d.draw_rectangle(1100, 175, 65, 65, Color::WHITE);
-        d.draw_text("9", 1125, 190, 50, Color::BLACK);
+        let kept: String = text.chars().take(max_chars - 3).collect();
+        format!("{kept}...")
     }
-    // E //
-    if d.is_key_up(KeyboardKey::KEY_F) {
-        d.draw_rectangle_lines(1165, 180, 65, 65, Color::WHITE);
-        d.draw_text("E", 1190, 190, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1165, 175, 65, 65, Color::WHITE);
-        d.draw_text("E", 1190, 190, 50, Color::BLACK);
+}
+
+fn draw_keypad_panel(
+    d: &mut RaylibDrawHandle,
+    keypad: &[bool; 16],
+    panel: Area,
+    palette: &Palette,
+) {
+    // KEYPAD //
+    // The keypad is drawn in the same 4x4 arrangement as the original hardware, each box lights up
+    // while the key is held down. That's the cpu's keypad rather than the keyboard, so a movie
+    // replay shows its own key presses
+    for (position, chip8_key) in KEYPAD_LAYOUT.iter().enumerate() {
+        let x = panel.x + 25 + (position as i32 % 4) * 65;
+        let y = panel.y + 50 + (position as i32 / 4) * 65;
+        let label = format!("{chip8_key:X}");
+        if keypad[*chip8_key as usize] {
+            d.draw_rectangle(x, y - 5, 65, 65, palette.ui_text);
+            d.draw_text(&label, x + 25, y + 10, 50, palette.ui_background);
+        } else {
+            d.draw_rectangle_lines(x, y, 65, 65, palette.ui_text);
+            d.draw_text(&label, x + 25, y + 10, 50, palette.ui_text);
+        }
     }
-    // A //
-    if d.is_key_up(KeyboardKey::KEY_Z) {
-        d.draw_rectangle_lines(970, 245, 65, 65, Color::WHITE);
-        d.draw_text("A", 995, 255, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(970, 240, 65, 65, Color::WHITE);
-        d.draw_text("A", 995, 255, 50, Color::BLACK);
+    // KEYPAD //
+}
+
+// Every byte of memory, brighter the more bits are set, with what the program has done to it on
+// top. The program counter and I are outlined, and hovering over a byte says what it is.
+fn draw_memory_panel(d: &mut RaylibDrawHandle, cpu: &Cpu, panel: Area, palette: &Palette) {
+    let (left, top) = (panel.x + 25, panel.y + 50);
+    let coverage = cpu.coverage();
+    d.draw_text("Memory", left, top - 35, 20, palette.ui_text);
+    for (address, &byte) in cpu.memory().iter().enumerate() {
+        let x = left + (address as i32 % 64) * MEMORY_CELL_SIZE;
+        let y = top + (address as i32 / 64) * MEMORY_CELL_SIZE;
+        if byte != 0 {
+            let brightness = 0.2 + byte.count_ones() as f32 / 10.0;
+            d.draw_rectangle(
+                x,
+                y,
+                MEMORY_CELL_SIZE,
+                MEMORY_CELL_SIZE,
+                see_through(palette.ui_text, brightness),
+            );
+        }
+        let flags = coverage.flags(address as u16);
+        if let Some((_, _, colour)) = COVERAGE_COLOURS
+            .iter()
+            .rev()
+            .find(|(flag, _, _)| flags & flag != 0)
+        {
+            d.draw_rectangle(
+                x,
+                y,
+                MEMORY_CELL_SIZE,
+                MEMORY_CELL_SIZE,
+                see_through(*colour, 0.75),
+            );
+        }
     }
-    // ZERO //
-    if d.is_key_up(KeyboardKey::KEY_X) {
-        d.draw_rectangle_lines(1035, 245, 65, 65, Color::WHITE);
-        d.draw_text("0", 1060, 255, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1035, 240, 65, 65, Color::WHITE);
-        d.draw_text("0", 1060, 255, 50, Color::BLACK);
+    let size = 64 * MEMORY_CELL_SIZE;
+    d.draw_rectangle_lines(left - 1, top - 1, size + 2, size + 2, palette.ui_text);
+    for (address, len) in [(cpu.program_counter, 2), (cpu.index_register(), 1)] {
+        let x = left + (address as i32 % 64) * MEMORY_CELL_SIZE;
+        let y = top + (address as i32 / 64) * MEMORY_CELL_SIZE;
+        d.draw_rectangle_lines(
+            x - 1,
+            y - 1,
+            MEMORY_CELL_SIZE * len + 2,
+            MEMORY_CELL_SIZE + 2,
+            palette.ui_text,
+        );
     }
-    // B //
-    if d.is_key_up(KeyboardKey::KEY_C) {
-        d.draw_rectangle_lines(1100, 245, 65, 65, Color::WHITE);
-        d.draw_text("B", 1125, 255, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1100, 240, 65, 65, Color::WHITE);
-        d.draw_text("B", 1125, 255, 50, Color::BLACK);
+
+    // The key, two to a row
+    for (position, (_, name, colour)) in COVERAGE_COLOURS.iter().enumerate() {
+        let x = left + (position as i32 % 2) * 130;
+        let y = top + size + 15 + (position as i32 / 2) * 25;
+        d.draw_rectangle(x, y + 3, 12, 12, *colour);
+        d.draw_text(name, x + 20, y, 20, palette.ui_text);
     }
-    // F //
-    if d.is_key_up(KeyboardKey::KEY_V) {
-        d.draw_rectangle_lines(1165, 245, 65, 65, Color::WHITE);
-        d.draw_text("F", 1190, 255, 50, Color::WHITE);
-    } else {
-        d.draw_rectangle(1165, 240, 65, 65, Color::WHITE);
-        d.draw_text("F", 1190, 255, 50, Color::BLACK);
+
+    let mouse = d.get_mouse_position();
+    let (column, row) = (
+        (mouse.x as i32 - left).div_euclid(MEMORY_CELL_SIZE),
+        (mouse.y as i32 - top).div_euclid(MEMORY_CELL_SIZE),
+    );
+    if (0..64).contains(&column) && (0..64).contains(&row) {
+        let address = (row * 64 + column) as u16;
+        let flags = coverage.flags(address);
+        let names: Vec<&str> = COVERAGE_COLOURS
+            .iter()
+            .filter(|(flag, _, _)| flags & flag != 0)
+            .map(|(_, name, _)| *name)
+            .collect();
+        d.draw_text(
+            &format!(
+                "{address:#05X}: {:02X} {}",
+                cpu.memory()[address as usize],
+                names.join(", ")
+            ),
+            left,
+            top + size + 70,
+            20,
+            palette.ui_text,
+        );
     }
-    // KEYPAD //
+}
+
+fn see_through(colour: Color, alpha: f32) -> Color {
+    Color::new(colour.r, colour.g, colour.b, (alpha * 255.0) as u8)
 }
@@ -1,70 +1,17 @@
-use crate::cpu::Cpu;
+use crate::keymap::{KEYPAD_LAYOUT, Keymap};
 use raylib::prelude::*;
 
-// This really sucks but I cannot update input state in the draw phase as it's only called at 60 FPS
-// I have to create a separate function that can be called at the CPUs clock speed
-pub fn poll_input(rl: &mut RaylibHandle, cpu: &mut Cpu) {
-    if !rl.is_key_up(KeyboardKey::KEY_ONE) {
-        cpu.current_key = 1;
-    }
-    // TWO //
-    if !rl.is_key_up(KeyboardKey::KEY_TWO) {
-        cpu.current_key = 2;
-    }
-    // THREE //
-    if !rl.is_key_up(KeyboardKey::KEY_THREE) {
-        cpu.current_key = 3;
-    }
-    // C //
-    if !rl.is_key_up(KeyboardKey::KEY_FOUR) {
-        cpu.current_key = 0xC;
-    }
-    // FOUR //
-    if !rl.is_key_up(KeyboardKey::KEY_Q) {
-        cpu.current_key = 4;
-    }
-    // FIVE //
-    if !rl.is_key_up(KeyboardKey::KEY_W) {
-        cpu.current_key = 5;
-    }
-    // SIX //
-    if !rl.is_key_up(KeyboardKey::KEY_E) {
-        cpu.current_key = 6;
-    }
-    // D //
-    if !rl.is_key_up(KeyboardKey::KEY_R) {
-        cpu.current_key = 0xD;
-    }
-    // SEVEN //
-    if !rl.is_key_up(KeyboardKey::KEY_A) {
-        cpu.current_key = 7;
-    }
-    // EIGHT //
-    if !rl.is_key_up(KeyboardKey::KEY_S) {
-        cpu.current_key = 8;
-    }
-    // NINE //
-    if !rl.is_key_up(KeyboardKey::KEY_D) {
-        cpu.current_key = 9;
-    }
-    // E //
-    if !rl.is_key_up(KeyboardKey::KEY_F) {
-        cpu.current_key = 0xE;
-    }
-    // A //
-    if !rl.is_key_up(KeyboardKey::KEY_Z) {
-        cpu.current_key = 0xA;
-    }
-    // ZERO //
-    if !rl.is_key_up(KeyboardKey::KEY_X) {
-        cpu.current_key = 0;
-    }
-    // B //
-    if !rl.is_key_up(KeyboardKey::KEY_C) {
-        cpu.current_key = 0xB;
-    }
-    // F //
-    if rl.is_key_down(KeyboardKey::KEY_V) {
-        cpu.current_key = 0xF;
+// Copies which keys are held into the cpu's keypad. This happens once at the start of every frame,
+// so all of the instructions in a frame see the same keypad, like they would on the real thing.
+pub fn poll_input(rl: &RaylibHandle, keypad: &mut [bool; 16], keymap: &Keymap) {
+    for chip8_key in KEYPAD_LAYOUT {
+        keypad[chip8_key as usize] = is_chip8_key_down(rl, keymap, chip8_key);
     }
 }
+
+fn is_chip8_key_down(rl: &RaylibHandle, keymap: &Keymap, chip8_key: u8) -> bool {
+    keymap
+        .bindings(chip8_key)
+        .iter()
+        .any(|key| rl.is_key_down(*key))
+}
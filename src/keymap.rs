@@ -0,0 +1,423 @@
+use std::path::Path;
+
+use raylib::prelude::KeyboardKey;
+
+use crate::backend::HostEvent;
+use crate::config::Config;
+
+// Where each key sits on the original COSMAC VIP hex keypad, read left to right, top to bottom.
+// The UI draws the keypad in this order.
+pub const KEYPAD_LAYOUT: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC, //
+    0x4, 0x5, 0x6, 0xD, //
+    0x7, 0x8, 0x9, 0xE, //
+    0xA, 0x0, 0xB, 0xF, //
+];
+
+// The left hand 4x4 block of a QWERTY keyboard, lined up with KEYPAD_LAYOUT above
+const DEFAULT_BINDINGS: [KeyboardKey; 16] = [
+    KeyboardKey::KEY_ONE,
+    KeyboardKey::KEY_TWO,
+    KeyboardKey::KEY_THREE,
+    KeyboardKey::KEY_FOUR,
+    KeyboardKey::KEY_Q,
+    KeyboardKey::KEY_W,
+    KeyboardKey::KEY_E,
+    KeyboardKey::KEY_R,
+    KeyboardKey::KEY_A,
+    KeyboardKey::KEY_S,
+    KeyboardKey::KEY_D,
+    KeyboardKey::KEY_F,
+    KeyboardKey::KEY_Z,
+    KeyboardKey::KEY_X,
+    KeyboardKey::KEY_C,
+    KeyboardKey::KEY_V,
+];
+
+// Maps each of the 16 CHIP-8 keys to the keyboard keys that press it. Raylib key codes describe
+// where a key physically is (named after a US layout) rather than the character printed on it,
+// so AZERTY and Dvorak users end up with the same 4x4 block under their fingers.
+//
+// Bindings come from the [keymap] section of the config, then the ROM database can add the arrow
+// keys and a couple of buttons for the game, and a [keymap.<rom file name>] section can override
+// some of them for a single game, e.g.
+//
+//     [keymap.pong.ch8]
+//     1 = UP
+//     4 = DOWN
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: [Vec<KeyboardKey>; 16],
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let mut bindings: [Vec<KeyboardKey>; 16] = Default::default();
+        for (position, chip8_key) in KEYPAD_LAYOUT.iter().enumerate() {
+            bindings[*chip8_key as usize] = vec![DEFAULT_BINDINGS[position]];
+        }
+        Keymap { bindings }
+    }
+}
+
+impl Keymap {
+    pub fn load(config: &Config, rom_path: &str, database_keys: &[(String, u8)]) -> Self {
+        let mut keymap = Keymap::default();
+        keymap.apply_section(config, "keymap");
+        keymap.apply_database_keys(database_keys, &Hotkeys::from_config(config));
+
+        if let Some(rom_name) = Path::new(rom_path).file_name() {
+            let section = format!("keymap.{}", rom_name.to_string_lossy());
+            keymap.apply_section(config, &section);
+        }
+        keymap
+    }
+
+    // The database names what each CHIP-8 key does in the game, e.g. `up = 5`. These get added on
+    // top of the normal bindings so the keypad keys keep working too. A key that's already a hotkey
+    // is left out, otherwise pressing it would do both.
+    fn apply_database_keys(&mut self, keys: &[(String, u8)], hotkeys: &Hotkeys) {
+        for (name, chip8_key) in keys {
+            let key = match name.as_str() {
+                "up" => KeyboardKey::KEY_UP,
+                "down" => KeyboardKey::KEY_DOWN,
+                "left" => KeyboardKey::KEY_LEFT,
+                "right" => KeyboardKey::KEY_RIGHT,
+                "a" => KeyboardKey::KEY_ENTER,
+                "b" => KeyboardKey::KEY_LEFT_SHIFT,
+                // Player two and anything else keeps to the keypad
+                _ => continue,
+            };
+            if hotkeys.uses(key) {
+                eprintln!("Not binding the game's {name} button to {key:?}, it's already a hotkey");
+                continue;
+            }
+            if let Some(bindings) = self.bindings.get_mut(*chip8_key as usize)
+                && !bindings.contains(&key)
+            {
+                bindings.push(key);
+            }
+        }
+    }
+
+    fn apply_section(&mut self, config: &Config, section: &str) {
+        let Some(entries) = config.section(section) else {
+            return;
+        };
+        for (chip8_key, key_names) in entries {
+            let Ok(chip8_key) = u8::from_str_radix(chip8_key, 16) else {
+                eprintln!("[{section}] {chip8_key} isn't a CHIP-8 key, expected 0-F");
+                continue;
+            };
+            if chip8_key > 0xF {
+                eprintln!("[{section}] {chip8_key:X} isn't a CHIP-8 key, expected 0-F");
+                continue;
+            }
+
+            // A key can be bound to more than one keyboard key, e.g. `5 = W, UP`
+            let keys: Vec<KeyboardKey> = key_names
+                .split(',')
+                .filter_map(|name| {
+                    let key = key_from_name(name.trim());
+                    if key.is_none() {
+                        eprintln!("[{section}] Unknown key name {}", name.trim());
+                    }
+                    key
+                })
+                .collect();
+            if !keys.is_empty() {
+                self.bindings[chip8_key as usize] = keys;
+            }
+        }
+    }
+
+    pub fn bindings(&self, chip8_key: u8) -> &[KeyboardKey] {
+        &self.bindings[chip8_key as usize]
+    }
+}
+
+// Which keyboard key does what, outside of the CHIP-8 keypad. Each frontend reads the keyboard its
+// own way but they all agree on these.
+pub struct Hotkeys {
+    pub fullscreen: KeyboardKey,
+    pub game_only: KeyboardKey,
+    // Swaps the keypad panel for the memory viewer
+    pub memory_viewer: KeyboardKey,
+    // Back to the ROM launcher, only the window has one
+    pub launcher: KeyboardKey,
+    // Handed straight to the emulator
+    pub events: Vec<(KeyboardKey, HostEvent)>,
+}
+
+impl Hotkeys {
+    pub fn from_config(config: &Config) -> Self {
+        let key = |section: &str, name: &str, default: KeyboardKey| {
+            config
+                .get(section, name)
+                .and_then(key_from_name)
+                .unwrap_or(default)
+        };
+        Hotkeys {
+            fullscreen: key("display", "fullscreen_key", KeyboardKey::KEY_F11),
+            game_only: key("display", "game_only_key", KeyboardKey::KEY_TAB),
+            memory_viewer: key("display", "memory_viewer_key", KeyboardKey::KEY_F4),
+            launcher: key("display", "launcher_key", KeyboardKey::KEY_F1),
+            events: vec![
+                (
+                    key("audio", "mute_key", KeyboardKey::KEY_M),
+                    HostEvent::ToggleMute,
+                ),
+                (
+                    key("display", "palette_key", KeyboardKey::KEY_F2),
+                    HostEvent::NextPalette,
+                ),
+                (
+                    key("display", "persistence_key", KeyboardKey::KEY_F3),
+                    HostEvent::NextPersistence,
+                ),
+                (
+                    key("recording", "record_key", KeyboardKey::KEY_F9),
+                    HostEvent::ToggleRecording,
+                ),
+                (
+                    key("display", "save_state_key", KeyboardKey::KEY_F5),
+                    HostEvent::SaveState,
+                ),
+                (
+                    key("display", "load_state_key", KeyboardKey::KEY_F8),
+                    HostEvent::LoadState,
+                ),
+                (
+                    key("controls", "step_key", KeyboardKey::KEY_SPACE),
+                    HostEvent::Step,
+                ),
+                (
+                    key("controls", "pause_key", KeyboardKey::KEY_P),
+                    HostEvent::TogglePause,
+                ),
+                (
+                    key("controls", "frame_advance_key", KeyboardKey::KEY_N),
+                    HostEvent::FrameAdvance,
+                ),
+                (
+                    key("controls", "step_mode_key", KeyboardKey::KEY_T),
+                    HostEvent::ToggleStepMode,
+                ),
+                (
+                    key("controls", "reset_key", KeyboardKey::KEY_BACKSPACE),
+                    HostEvent::Reset,
+                ),
+                (
+                    key("controls", "faster_key", KeyboardKey::KEY_EQUAL),
+                    HostEvent::Faster,
+                ),
+                (
+                    key("controls", "slower_key", KeyboardKey::KEY_MINUS),
+                    HostEvent::Slower,
+                ),
+            ],
+        }
+    }
+
+    pub fn uses(&self, key: KeyboardKey) -> bool {
+        [
+            self.fullscreen,
+            self.game_only,
+            self.memory_viewer,
+            self.launcher,
+        ]
+        .contains(&key)
+            || self.events.iter().any(|(hotkey, _)| *hotkey == key)
+    }
+}
+
+// Accepts raylib's own names with or without the KEY_ prefix, so `KEY_UP`, `UP` and `up` all work
+pub fn key_from_name(name: &str) -> Option<KeyboardKey> {
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("KEY_").unwrap_or(&name);
+
+    let key = match name {
+        "0" | "ZERO" => KeyboardKey::KEY_ZERO,
+        "1" | "ONE" => KeyboardKey::KEY_ONE,
+        "2" | "TWO" => KeyboardKey::KEY_TWO,
+        "3" | "THREE" => KeyboardKey::KEY_THREE,
+        "4" | "FOUR" => KeyboardKey::KEY_FOUR,
+        "5" | "FIVE" => KeyboardKey::KEY_FIVE,
+        "6" | "SIX" => KeyboardKey::KEY_SIX,
+        "7" | "SEVEN" => KeyboardKey::KEY_SEVEN,
+        "8" | "EIGHT" => KeyboardKey::KEY_EIGHT,
+        "9" | "NINE" => KeyboardKey::KEY_NINE,
+        "A" => KeyboardKey::KEY_A,
+        "B" => KeyboardKey::KEY_B,
+        "C" => KeyboardKey::KEY_C,
+        "D" => KeyboardKey::KEY_D,
+        "E" => KeyboardKey::KEY_E,
+        "F" => KeyboardKey::KEY_F,
+        "G" => KeyboardKey::KEY_G,
+        "H" => KeyboardKey::KEY_H,
+        "I" => KeyboardKey::KEY_I,
+        "J" => KeyboardKey::KEY_J,
+        "K" => KeyboardKey::KEY_K,
+        "L" => KeyboardKey::KEY_L,
+        "M" => KeyboardKey::KEY_M,
+        "N" => KeyboardKey::KEY_N,
+        "O" => KeyboardKey::KEY_O,
+        "P" => KeyboardKey::KEY_P,
+        "Q" => KeyboardKey::KEY_Q,
+        "R" => KeyboardKey::KEY_R,
+        "S" => KeyboardKey::KEY_S,
+        "T" => KeyboardKey::KEY_T,
+        "U" => KeyboardKey::KEY_U,
+        "V" => KeyboardKey::KEY_V,
+        "W" => KeyboardKey::KEY_W,
+        "X" => KeyboardKey::KEY_X,
+        "Y" => KeyboardKey::KEY_Y,
+        "Z" => KeyboardKey::KEY_Z,
+        "F1" => KeyboardKey::KEY_F1,
+        "F2" => KeyboardKey::KEY_F2,
+        "F3" => KeyboardKey::KEY_F3,
+        "F4" => KeyboardKey::KEY_F4,
+        "F5" => KeyboardKey::KEY_F5,
+        "F6" => KeyboardKey::KEY_F6,
+        "F7" => KeyboardKey::KEY_F7,
+        "F8" => KeyboardKey::KEY_F8,
+        "F9" => KeyboardKey::KEY_F9,
+        "F10" => KeyboardKey::KEY_F10,
+        "F11" => KeyboardKey::KEY_F11,
+        "F12" => KeyboardKey::KEY_F12,
+        "UP" => KeyboardKey::KEY_UP,
+        "DOWN" => KeyboardKey::KEY_DOWN,
+        "LEFT" => KeyboardKey::KEY_LEFT,
+        "RIGHT" => KeyboardKey::KEY_RIGHT,
+        "SPACE" => KeyboardKey::KEY_SPACE,
+        "ENTER" => KeyboardKey::KEY_ENTER,
+        "TAB" => KeyboardKey::KEY_TAB,
+        "BACKSPACE" => KeyboardKey::KEY_BACKSPACE,
+        "LEFT_SHIFT" => KeyboardKey::KEY_LEFT_SHIFT,
+        "RIGHT_SHIFT" => KeyboardKey::KEY_RIGHT_SHIFT,
+        "LEFT_CONTROL" => KeyboardKey::KEY_LEFT_CONTROL,
+        "RIGHT_CONTROL" => KeyboardKey::KEY_RIGHT_CONTROL,
+        "COMMA" => KeyboardKey::KEY_COMMA,
+        "MINUS" => KeyboardKey::KEY_MINUS,
+        "EQUAL" => KeyboardKey::KEY_EQUAL,
+        "PERIOD" => KeyboardKey::KEY_PERIOD,
+        "SLASH" => KeyboardKey::KEY_SLASH,
+        "SEMICOLON" => KeyboardKey::KEY_SEMICOLON,
+        "APOSTROPHE" => KeyboardKey::KEY_APOSTROPHE,
+        "KP_0" => KeyboardKey::KEY_KP_0,
+        "KP_1" => KeyboardKey::KEY_KP_1,
+        "KP_2" => KeyboardKey::KEY_KP_2,
+        "KP_3" => KeyboardKey::KEY_KP_3,
+        "KP_4" => KeyboardKey::KEY_KP_4,
+        "KP_5" => KeyboardKey::KEY_KP_5,
+        "KP_6" => KeyboardKey::KEY_KP_6,
+        "KP_7" => KeyboardKey::KEY_KP_7,
+        "KP_8" => KeyboardKey::KEY_KP_8,
+        "KP_9" => KeyboardKey::KEY_KP_9,
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buttons() -> Vec<(String, u8)> {
+        vec![
+            ("up".to_string(), 0x5),
+            ("a".to_string(), 0x6),
+            ("b".to_string(), 0x4),
+        ]
+    }
+
+    #[test]
+    fn database_keys_go_on_top_of_the_keypad() {
+        let keymap = Keymap::load(&Config::parse(""), "game.ch8", &buttons());
+        assert_eq!(
+            keymap.bindings(0x5),
+            [KeyboardKey::KEY_W, KeyboardKey::KEY_UP]
+        );
+        assert_eq!(
+            keymap.bindings(0x6),
+            [KeyboardKey::KEY_E, KeyboardKey::KEY_ENTER]
+        );
+        assert_eq!(
+            keymap.bindings(0x4),
+            [KeyboardKey::KEY_Q, KeyboardKey::KEY_LEFT_SHIFT]
+        );
+    }
+
+    #[test]
+    fn database_keys_never_land_on_a_hotkey() {
+        let hotkeys = Hotkeys::from_config(&Config::parse(""));
+        for name in ["up", "down", "left", "right", "a", "b"] {
+            let keymap = Keymap::load(&Config::parse(""), "game.ch8", &[(name.to_string(), 0)]);
+            let added = keymap.bindings(0).last().unwrap();
+            assert!(!hotkeys.uses(*added), "{name} is bound to {added:?}");
+        }
+    }
+
+    #[test]
+    fn a_hotkey_keeps_its_key_from_the_database() {
+        let config = Config::parse("[controls]\nstep_key = ENTER\n");
+        let keymap = Keymap::load(&config, "game.ch8", &buttons());
+        assert_eq!(keymap.bindings(0x6), [KeyboardKey::KEY_E]);
+        assert!(
+            Hotkeys::from_config(&config)
+                .events
+                .contains(&(KeyboardKey::KEY_ENTER, HostEvent::Step))
+        );
+    }
+
+    #[test]
+    fn the_keymap_section_replaces_default_bindings() {
+        let config = Config::parse("[keymap]\n5 = UP, I\nC = KEY_P\n");
+        let keymap = Keymap::load(&config, "game.ch8", &[]);
+        assert_eq!(
+            keymap.bindings(0x5),
+            [KeyboardKey::KEY_UP, KeyboardKey::KEY_I]
+        );
+        assert_eq!(keymap.bindings(0xC), [KeyboardKey::KEY_P]);
+        // Anything not mentioned keeps its place on the 4x4 block
+        assert_eq!(keymap.bindings(0x1), [KeyboardKey::KEY_ONE]);
+        assert_eq!(keymap.bindings(0x0), [KeyboardKey::KEY_X]);
+    }
+
+    #[test]
+    fn a_roms_own_section_wins() {
+        let config = Config::parse(
+            "[keymap]\n5 = I\n6 = O\n\
+             [keymap.pong.ch8]\n5 = UP\n",
+        );
+        let keymap = Keymap::load(&config, "roms/pong.ch8", &buttons());
+        assert_eq!(keymap.bindings(0x5), [KeyboardKey::KEY_UP]);
+        assert_eq!(
+            keymap.bindings(0x6),
+            [KeyboardKey::KEY_O, KeyboardKey::KEY_ENTER]
+        );
+
+        // Other ROMs only get the global bindings
+        let keymap = Keymap::load(&config, "roms/tetris.ch8", &[]);
+        assert_eq!(keymap.bindings(0x5), [KeyboardKey::KEY_I]);
+    }
+
+    #[test]
+    fn bad_entries_leave_bindings_alone() {
+        let config = Config::parse("[keymap]\n10 = UP\nG = UP\n5 = NOT_A_KEY\n6 = NOPE, K\n");
+        let keymap = Keymap::load(&config, "game.ch8", &[]);
+        assert_eq!(keymap.bindings(0x5), [KeyboardKey::KEY_W]);
+        assert_eq!(keymap.bindings(0x6), [KeyboardKey::KEY_K]);
+        assert!((0..16).all(|key| !keymap.bindings(key).contains(&KeyboardKey::KEY_UP)));
+    }
+
+    #[test]
+    fn key_names_work_with_or_without_the_prefix() {
+        for name in ["KEY_UP", "UP", "up", "Key_Up"] {
+            assert_eq!(key_from_name(name), Some(KeyboardKey::KEY_UP), "{name}");
+        }
+        assert_eq!(key_from_name("7"), Some(KeyboardKey::KEY_SEVEN));
+        assert_eq!(key_from_name("SEVEN"), Some(KeyboardKey::KEY_SEVEN));
+        assert_eq!(key_from_name("UPP"), None);
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use raylib::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, config_dir};
+use crate::palette::Palette;
+use crate::romdb::{RomDatabase, sha1_hex};
+
+// What the browser lists as ROMs, anything else besides folders is hidden
+const ROM_EXTENSIONS: [&str; 5] = ["ch8", "c8", "sc8", "xo8", "8o"];
+const MAX_RECENT: usize = 15;
+// Nothing bigger than this is a CHIP-8 ROM, so it isn't worth hashing
+const MAX_ROM_SIZE: u64 = 64 * 1024;
+const ROW_HEIGHT: i32 = 26;
+const FONT_SIZE: i32 = 20;
+
+// The recent files and favourites, kept in launcher.json in the config directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct History {
+    recent: Vec<PathBuf>,
+    favourites: Vec<PathBuf>,
+}
+
+impl History {
+    fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("launcher.json"))
+    }
+
+    fn load() -> Self {
+        let Some(path) = History::path() else {
+            return History::default();
+        };
+        match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                eprintln!("Couldn't read {}: {err}", path.display());
+                History::default()
+            }),
+            Err(_) => History::default(),
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = History::path() else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| serde_json::to_string_pretty(self).map_err(io::Error::other))
+            .and_then(|json| fs::write(&path, json));
+        if let Err(err) = result {
+            eprintln!("Couldn't save {}: {err}", path.display());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tab {
+    Browse,
+    Recent,
+    Favourites,
+}
+
+impl Tab {
+    fn next(self) -> Self {
+        match self {
+            Tab::Browse => Tab::Recent,
+            Tab::Recent => Tab::Favourites,
+            Tab::Favourites => Tab::Browse,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Tab::Browse => "Browse",
+            Tab::Recent => "Recent",
+            Tab::Favourites => "Favourites",
+        }
+    }
+}
+
+struct Entry {
+    path: PathBuf,
+    // The title from the ROM database if it knows the ROM, otherwise the file name
+    label: String,
+    is_dir: bool,
+}
+
+// The screen the window starts on when no ROM was given, and comes back to with the launcher key.
+// It browses folders, keeps the recent files and favourites, and takes ROMs dropped on the window.
+pub struct Launcher {
+    tab: Tab,
+    dir: PathBuf,
+    entries: Vec<Entry>,
+    selected: usize,
+    history: History,
+    database: RomDatabase,
+    // Titles by path, so a folder only gets hashed the first time it's opened
+    titles: HashMap<PathBuf, String>,
+    // Shown at the bottom, e.g. when a ROM wouldn't load
+    pub message: Option<String>,
+}
+
+impl Launcher {
+    pub fn new(config: &Config) -> Self {
+        let history = History::load();
+        // Start where the config says, otherwise where the last ROM was
+        let dir = config
+            .get("launcher", "directory")
+            .map(PathBuf::from)
+            .or_else(|| history.recent.first()?.parent().map(Path::to_path_buf))
+            .filter(|dir| dir.is_dir())
+            .or_else(|| env::current_dir().ok())
+            .map(|dir| fs::canonicalize(&dir).unwrap_or(dir))
+            .unwrap_or_default();
+
+        let mut launcher = Launcher {
+            tab: Tab::Browse,
+            dir,
+            entries: Vec::new(),
+            selected: 0,
+            history,
+            database: RomDatabase::load(),
+            titles: HashMap::new(),
+            message: None,
+        };
+        launcher.refresh();
+        launcher
+    }
+
+    // Call whenever a ROM gets started, however it was picked
+    pub fn add_recent(&mut self, rom: &Path) {
+        let rom = fs::canonicalize(rom).unwrap_or_else(|_| rom.to_path_buf());
+        self.history.recent.retain(|path| *path != rom);
+        self.history.recent.insert(0, rom);
+        self.history.recent.truncate(MAX_RECENT);
+        self.history.save();
+        if self.tab == Tab::Recent {
+            self.refresh();
+        }
+    }
+
+    // Handles this frame's input, and hands back a ROM once one has been picked
+    pub fn update(&mut self, rl: &RaylibHandle) -> Option<PathBuf> {
+        if let Some(rom) = dropped_rom(rl) {
+            return Some(rom);
+        }
+
+        let pressed = |key| rl.is_key_pressed(key) || rl.is_key_pressed_repeat(key);
+        let page = (rl.get_screen_height() / ROW_HEIGHT - 4).max(1) as usize;
+        let last = self.entries.len().saturating_sub(1);
+        if pressed(KeyboardKey::KEY_DOWN) {
+            self.selected = (self.selected + 1).min(last);
+        }
+        if pressed(KeyboardKey::KEY_UP) {
+            self.selected = self.selected.saturating_sub(1);
+        }
+        if pressed(KeyboardKey::KEY_PAGE_DOWN) {
+            self.selected = (self.selected + page).min(last);
+        }
+        if pressed(KeyboardKey::KEY_PAGE_UP) {
+            self.selected = self.selected.saturating_sub(page);
+        }
+        if rl.is_key_pressed(KeyboardKey::KEY_TAB) {
+            self.tab = self.tab.next();
+            self.refresh();
+        }
+        if rl.is_key_pressed(KeyboardKey::KEY_F) {
+            self.toggle_favourite();
+        }
+        if rl.is_key_pressed(KeyboardKey::KEY_BACKSPACE)
+            && self.tab == Tab::Browse
+            && let Some(parent) = self.dir.parent()
+        {
+            let child = self.dir.clone();
+            self.dir = parent.to_path_buf();
+            self.refresh();
+            // Land back on the folder we just came out of
+            self.selected = self
+                .entries
+                .iter()
+                .position(|entry| entry.path == child)
+                .unwrap_or(0);
+        }
+        if rl.is_key_pressed(KeyboardKey::KEY_ENTER) {
+            let entry = self.entries.get(self.selected)?;
+            if entry.is_dir {
+                self.dir = entry.path.clone();
+                self.refresh();
+            } else {
+                return Some(entry.path.clone());
+            }
+        }
+        None
+    }
+
+    fn toggle_favourite(&mut self) {
+        let Some(entry) = self
+            .entries
+            .get(self.selected)
+            .filter(|entry| !entry.is_dir)
+        else {
+            return;
+        };
+        let path = fs::canonicalize(&entry.path).unwrap_or_else(|_| entry.path.clone());
+        let favourites = &mut self.history.favourites;
+        match favourites.iter().position(|favourite| *favourite == path) {
+            Some(index) => {
+                favourites.remove(index);
+            }
+            None => favourites.push(path),
+        }
+        self.history.save();
+        if self.tab == Tab::Favourites {
+            self.refresh();
+        }
+    }
+
+    // Rebuilds the list for the current tab
+    fn refresh(&mut self) {
+        let paths: Vec<PathBuf> = match self.tab {
+            Tab::Browse => match list_dir(&self.dir) {
+                Ok(paths) => paths,
+                Err(err) => {
+                    self.message = Some(format!("Couldn't open {}: {err}", self.dir.display()));
+                    Vec::new()
+                }
+            },
+            Tab::Recent => self.history.recent.clone(),
+            Tab::Favourites => self.history.favourites.clone(),
+        };
+        self.entries = paths
+            .into_iter()
+            .map(|path| {
+                let is_dir = path.is_dir();
+                let label = if is_dir {
+                    format!("{}/", file_name(&path))
+                } else {
+                    self.title(&path)
+                };
+                Entry {
+                    path,
+                    label,
+                    is_dir,
+                }
+            })
+            .collect();
+        self.selected = self.selected.min(self.entries.len().saturating_sub(1));
+    }
+
+    fn title(&mut self, path: &Path) -> String {
+        if let Some(title) = self.titles.get(path) {
+            return title.clone();
+        }
+        let from_database = fs::metadata(path)
+            .ok()
+            .filter(|metadata| metadata.len() <= MAX_ROM_SIZE)
+            .and_then(|_| fs::read(path).ok())
+            .and_then(|rom| self.database.lookup(&sha1_hex(&rom)))
+            .map(|info| format!("{} ({})", info.title, file_name(path)));
+        let title = from_database.unwrap_or_else(|| file_name(path));
+        self.titles.insert(path.to_path_buf(), title.clone());
+        title
+    }
+
+    pub fn draw(&self, d: &mut RaylibDrawHandle, palette: &Palette) {
+        d.clear_background(palette.ui_background);
+        let width = d.get_screen_width();
+        let height = d.get_screen_height();
+
+        // Tabs along the top, the current one lit up
+        let mut x = 20;
+        for tab in [Tab::Browse, Tab::Recent, Tab::Favourites] {
+            let tab_width = tab.name().len() as i32 * 12 + 20;
+            if tab == self.tab {
+                d.draw_rectangle(x, 10, tab_width, ROW_HEIGHT, palette.ui_text);
+                d.draw_text(tab.name(), x + 10, 13, FONT_SIZE, palette.ui_background);
+            } else {
+                d.draw_rectangle_lines(x, 10, tab_width, ROW_HEIGHT, palette.ui_text);
+                d.draw_text(tab.name(), x + 10, 13, FONT_SIZE, palette.ui_text);
+            }
+            x += tab_width + 10;
+        }
+        if self.tab == Tab::Browse {
+            let dir = self.dir.display().to_string();
+            d.draw_text(&dir, 20, 45, FONT_SIZE, palette.ui_text);
+        }
+
+        // As many rows as fit, scrolled to keep the selection on screen
+        let top = 75;
+        let rows = ((height - top - 2 * ROW_HEIGHT) / ROW_HEIGHT).max(1) as usize;
+        let first = self.selected.saturating_sub(rows - 1);
+        if self.entries.is_empty() {
+            let empty = match self.tab {
+                Tab::Browse => "No ROMs in this folder",
+                Tab::Recent => "Nothing played yet",
+                Tab::Favourites => "No favourites yet, press F on a ROM to add it",
+            };
+            d.draw_text(empty, 20, top, FONT_SIZE, palette.ui_text);
+        }
+        for (row, (index, entry)) in self
+            .entries
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(rows)
+            .enumerate()
+        {
+            let y = top + row as i32 * ROW_HEIGHT;
+            let favourite = !entry.is_dir && self.is_favourite(&entry.path);
+            let label = format!("{} {}", if favourite { "*" } else { " " }, entry.label);
+            if index == self.selected {
+                d.draw_rectangle(10, y - 3, width - 20, ROW_HEIGHT, palette.ui_text);
+                d.draw_text(&label, 20, y, FONT_SIZE, palette.ui_background);
+            } else {
+                d.draw_text(&label, 20, y, FONT_SIZE, palette.ui_text);
+            }
+        }
+
+        let footer = self.message.as_deref().unwrap_or(
+            "Enter: open  Backspace: up a folder  Tab: switch list  F: favourite  or drop a ROM here",
+        );
+        d.draw_text(
+            footer,
+            20,
+            height - ROW_HEIGHT - 5,
+            FONT_SIZE,
+            palette.ui_text,
+        );
+    }
+
+    fn is_favourite(&self, path: &Path) -> bool {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.history.favourites.contains(&path)
+    }
+}
+
+// A ROM dropped on the window, if there was one this frame. Works in the launcher and mid game.
+pub fn dropped_rom(rl: &RaylibHandle) -> Option<PathBuf> {
+    if !rl.is_file_dropped() {
+        return None;
+    }
+    let dropped = rl.load_dropped_files();
+    dropped.paths().first().map(PathBuf::from)
+}
+
+// Folders first, then ROMs, each sorted by name
+fn list_dir(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    let mut roms = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = file_name(&path).starts_with('.');
+        if path.is_dir() && !hidden {
+            dirs.push(path);
+        } else if is_rom(&path) {
+            roms.push(path);
+        }
+    }
+    dirs.sort();
+    roms.sort();
+    dirs.extend(roms);
+    Ok(dirs)
+}
+
+fn is_rom(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| ROM_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
@@ -1,70 +1,115 @@
-use raylib::prelude::*;
-use std::fs::File;
-use std::time::{Duration, Instant};
+use std::env;
+use std::process::ExitCode;
+
+mod backend;
+
+mod bench;
+
+mod buzzer;
+
+mod cfg;
+
+mod cli;
+use crate::cli::{Command, USAGE, parse_args};
+
+mod commands;
+
+mod config;
+use crate::config::Config;
+
+mod coverage;
 
 mod cpu;
-use crate::cpu::{Cpu, Instruction};
+
+mod decode;
+
+mod disasm;
+
+mod emulator;
 
 mod input;
-use crate::input::poll_input;
+
+mod keymap;
+
+mod launcher;
 
 mod graphics;
-use crate::graphics::{WINDOW_HEIGHT, WINDOW_WIDTH, draw_game_pixels, draw_ui_elements};
 
-mod util;
-use crate::util::validate_args;
+mod movie;
 
-const FPS: u32 = 60;
-fn main() -> std::io::Result<()> {
-    let mut cpu = Cpu::new();
+mod octo;
 
-    match validate_args() {
-        Ok(value) => {
-            let mut program = File::open(value)?;
-            cpu.load_program_into_memory(&mut program);
-        }
+mod palette;
+
+mod phosphor;
+
+mod prng;
+
+mod profiler;
+
+mod quirks;
+
+mod recompiler;
+
+mod recorder;
+
+mod romdb;
+
+mod savestate;
+
+mod symbols;
+
+mod terminal;
+use crate::terminal::run_terminal;
+
+mod timing;
+
+mod transpile;
+
+mod watcher;
+
+mod window;
+use crate::window::run_window;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
         Err(err) => {
-            panic!("{err}");
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::from(2);
         }
-    }
+    };
 
-    let (mut rl, thread) = raylib::init()
-        .width(WINDOW_WIDTH)
-        .height(WINDOW_HEIGHT)
-        .title("C8-Emu")
-        .build();
-    rl.set_target_fps(FPS);
-    rl.set_trace_log(TraceLogLevel::LOG_NONE);
-
-    cpu.set_step_mode(false);
-    let mut opcode_strings: [u16; 3] = Default::default();
-    let mut timer = Instant::now();
-
-    while !rl.window_should_close() {
-        // A basic implementation of a cylce speed, this is about 2Mhz
-        if timer.elapsed() >= Duration::from_millis(10) && !cpu.step_mode && !cpu.should_halt {
-            timer = Instant::now();
-            for _ in 0..70 {
-                opcode_strings = cpu.fetch_opcodes();
-                let instruction: Instruction = cpu.fetch();
-                cpu.decode_and_execute(instruction);
-                poll_input(&mut rl, &mut cpu);
-                if cpu.should_halt {
-                    break;
-                }
+    let config = Config::load();
+    let result = match command {
+        Command::Help => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        Command::Run(options) => {
+            if options.headless {
+                commands::run_headless(&options, &config)
+            } else if options.terminal {
+                run_terminal(&options, &config)
+            } else {
+                run_window(&options, &config)
             }
         }
+        Command::Disasm(options) => commands::disasm(&options),
+        Command::Cfg(options) => commands::control_flow(&options),
+        Command::Asm(options) => commands::assemble(&options),
+        Command::Info(options) => commands::info(&options),
+        Command::Transpile(options) => commands::transpile_rom(&options),
+        Command::Lockstep(options) => commands::lockstep(&options, &config),
+        Command::Bench(options) => commands::bench(&options),
+    };
 
-        if cpu.step_mode && !cpu.should_halt && rl.is_key_pressed(KeyboardKey::KEY_SPACE) {
-            poll_input(&mut rl, &mut cpu);
-            opcode_strings = cpu.fetch_opcodes();
-            let instruction: Instruction = cpu.fetch();
-            cpu.decode_and_execute(instruction);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
         }
-
-        let mut d = rl.begin_drawing(&thread);
-        draw_ui_elements(&mut d, &mut cpu, opcode_strings);
-        draw_game_pixels(&mut d, &cpu);
     }
-    Ok(())
 }
@@ -0,0 +1,162 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cpu::Cpu;
+use crate::quirks::Quirks;
+use crate::romdb::sha1_hex;
+use crate::timing::TimingMode;
+
+// A recording of everything that went into a run rather than what came out of it: the keys held
+// down on every frame, plus whatever else could change how the ROM behaves. Playing it back on
+// the same ROM gives exactly the same run, which makes for bug reports anyone can reproduce and
+// regression tests built from real play sessions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Movie {
+    pub rom_sha1: String,
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub timing: TimingMode,
+    // The keypad on each frame, bit N is set while key N is held down
+    pub frames: Vec<u16>,
+    // Hash of the cpu's save state after the last frame, which a replay has to match
+    pub final_state: String,
+}
+
+impl Movie {
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string(self).map_err(io::Error::other)?;
+        fs::write(path, json)
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let json = fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+// Two runs that end up with the same hash went the same way, down to the random number generator
+pub fn state_hash(cpu: &Cpu) -> String {
+    let json = serde_json::to_vec(&cpu.save_state()).unwrap_or_default();
+    sha1_hex(&json)
+}
+
+pub fn pack_keypad(keypad: &[bool; 16]) -> u16 {
+    keypad
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (key, &down)| bits | ((down as u16) << key))
+}
+
+pub fn unpack_keypad(bits: u16) -> [bool; 16] {
+    std::array::from_fn(|key| bits & (1 << key) != 0)
+}
+
+pub enum MovieSession {
+    Recording { movie: Movie, path: PathBuf },
+    Playing { movie: Movie, next_frame: usize },
+}
+
+impl MovieSession {
+    // Starts recording from the cpu's current setup. The seed has to be one the cpu is already
+    // using, otherwise the movie won't replay.
+    pub fn record(path: &Path, cpu: &Cpu, seed: u64, timing: TimingMode) -> Self {
+        MovieSession::Recording {
+            movie: Movie {
+                rom_sha1: cpu.rom_sha1.clone(),
+                seed,
+                quirks: cpu.quirks,
+                timing,
+                frames: Vec::new(),
+                final_state: String::new(),
+            },
+            path: path.to_path_buf(),
+        }
+    }
+
+    // Sets the cpu up the way it was when the movie was recorded, and hands back the timing to use
+    pub fn play(path: &Path, cpu: &mut Cpu) -> io::Result<(Self, TimingMode)> {
+        let movie = Movie::read(path)?;
+        if movie.rom_sha1 != cpu.rom_sha1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} was recorded with a different ROM", path.display()),
+            ));
+        }
+        cpu.seed_rng(movie.seed);
+        cpu.quirks = movie.quirks;
+        let timing = movie.timing;
+        Ok((
+            MovieSession::Playing {
+                movie,
+                next_frame: 0,
+            },
+            timing,
+        ))
+    }
+
+    // Call before running each frame. While recording this takes note of the keypad, while playing
+    // it replaces the keypad with the recorded one. Returns false once a movie has run out.
+    pub fn feed_input(&mut self, cpu: &mut Cpu) -> bool {
+        match self {
+            MovieSession::Recording { movie, .. } => {
+                movie.frames.push(pack_keypad(&cpu.keypad));
+                true
+            }
+            MovieSession::Playing { movie, next_frame } => match movie.frames.get(*next_frame) {
+                Some(&bits) => {
+                    cpu.keypad = unpack_keypad(bits);
+                    *next_frame += 1;
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(self, MovieSession::Playing { .. })
+    }
+
+    // Saves a recording, or checks a replay ended up where the recording did
+    pub fn finish(self, cpu: &Cpu) -> io::Result<()> {
+        match self {
+            MovieSession::Recording { mut movie, path } => {
+                movie.final_state = state_hash(cpu);
+                movie.write(&path)?;
+                println!(
+                    "Saved a {} frame movie to {}",
+                    movie.frames.len(),
+                    path.display()
+                );
+                Ok(())
+            }
+            MovieSession::Playing { movie, next_frame } => {
+                let hash = state_hash(cpu);
+                if next_frame < movie.frames.len() {
+                    println!(
+                        "Stopped the replay after {next_frame} of {} frames",
+                        movie.frames.len()
+                    );
+                    Ok(())
+                } else if hash == movie.final_state {
+                    println!(
+                        "Replay of {} frames matched the recording",
+                        movie.frames.len()
+                    );
+                    Ok(())
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Replay didn't match the recording, expected state {} but got {hash}",
+                            movie.final_state
+                        ),
+                    ))
+                }
+            }
+        }
+    }
+}
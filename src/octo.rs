@@ -0,0 +1,965 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::disasm::PROGRAM_START;
+use crate::symbols::Symbols;
+
+// Programs get loaded at 0x200 and can take up the rest of memory
+const MEMORY_END: u16 = 0x1000;
+
+// Stops a macro that uses itself from going round forever
+const MAX_EXPANSIONS: usize = 10_000;
+
+// Words that mean something after other words, and would only turn into a confusing call to a
+// label that doesn't exist if they turned up on their own
+const KEYWORDS: [&str; 7] = ["then", "begin", "key", "-key", "hex", "bighex", "random"];
+
+// An assembled program, and the labels and source lines to debug it with
+pub struct Program {
+    pub bytes: Vec<u8>,
+    pub symbols: Symbols,
+}
+
+// Octo source is just whitespace separated words, each of which needs to know its line for errors
+// and the symbols
+struct Token {
+    text: String,
+    line: usize,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<String>,
+}
+
+// Structured flow that's still waiting for its end
+enum Block {
+    // The jump over the `begin` half, which else or end fills in
+    If(u16),
+    // The jump over the `else` half
+    Else(u16),
+    // Where again goes back to, and the jumps out of the loop from every while in it
+    Loop { start: u16, exits: Vec<u16> },
+}
+
+// The address in the low 12 bits of the two bytes at `at`, for a label that hadn't turned up yet
+struct Pending {
+    at: u16,
+    label: String,
+    line: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+// A line of 0 is for a problem with the program as a whole
+struct AsmError {
+    line: usize,
+    message: String,
+}
+
+type Result<T> = std::result::Result<T, AsmError>;
+
+// Assembles an Octo source file. The labels and which line every instruction came from go in the
+// symbols, so a .8o gets debugged at the source level without a .sym.
+pub fn assemble_file(path: &Path) -> io::Result<Program> {
+    let source = fs::read_to_string(path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("Couldn't read {}: {err}", path.display()),
+        )
+    })?;
+    let mut assembler = Assembler::new(&source);
+    if let Err(err) = assembler.run() {
+        let message = match err.line {
+            0 => format!("{}: {}", path.display(), err.message),
+            line => format!("{}:{line}: {}", path.display(), err.message),
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    let symbols = Symbols::new(
+        assembler.labels,
+        assembler.lines,
+        Some(path.to_string_lossy().into_owned()),
+        &source,
+    );
+    Ok(Program {
+        bytes: assembler.rom,
+        symbols,
+    })
+}
+
+pub fn is_source(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("8o"))
+}
+
+// Octo numbers are decimal, 0x hex or 0b binary, and any of them can be negative
+fn number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        && !KEYWORDS.contains(&text)
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    rom: Vec<u8>,
+    here: u16,
+    // The line of the token we're on, for errors and the line map
+    line: usize,
+    // Whether anything's been put at 0x200 yet, see start
+    started: bool,
+    labels: BTreeMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    pending: Vec<Pending>,
+    // With the line each one started on, for when it never ends
+    blocks: Vec<(Block, usize)>,
+    lines: BTreeMap<u16, usize>,
+    expansions: usize,
+}
+
+impl Assembler {
+    fn new(source: &str) -> Self {
+        let tokens = source
+            .lines()
+            .enumerate()
+            .flat_map(|(index, line)| {
+                let code = line.split('#').next().unwrap_or("");
+                code.split_whitespace().map(move |text| Token {
+                    text: text.to_string(),
+                    line: index + 1,
+                })
+            })
+            .collect();
+        Assembler {
+            tokens,
+            rom: Vec::new(),
+            here: PROGRAM_START,
+            line: 0,
+            started: false,
+            labels: BTreeMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            pending: Vec::new(),
+            blocks: Vec::new(),
+            lines: BTreeMap::new(),
+            expansions: 0,
+        }
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T> {
+        Err(AsmError {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn run(&mut self) -> Result<()> {
+        while let Some(token) = self.tokens.pop_front() {
+            self.line = token.line;
+            self.statement(&token.text)?;
+        }
+        if let Some((block, line)) = self.blocks.pop() {
+            self.line = line;
+            return match block {
+                Block::Loop { .. } => self.error("This loop never gets its again"),
+                _ => self.error("This if never gets its end"),
+            };
+        }
+        if !self.labels.contains_key("main") {
+            self.line = 0;
+            return self.error("There's no `: main` for the program to start at");
+        }
+        for pending in std::mem::take(&mut self.pending) {
+            self.line = pending.line;
+            let Some(&address) = self.labels.get(&pending.label) else {
+                return self.error(format!("There's no label called {}", pending.label));
+            };
+            let offset = (pending.at - PROGRAM_START) as usize;
+            self.rom[offset] |= (address >> 8) as u8;
+            self.rom[offset + 1] = address as u8;
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<String> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token.text)
+            }
+            None => self.error("The program ends partway through a statement"),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, wanted: &str) -> Result<()> {
+        let token = self.next()?;
+        if token == wanted {
+            Ok(())
+        } else {
+            self.error(format!("Expected {wanted}, got {token}"))
+        }
+    }
+
+    fn statement(&mut self, token: &str) -> Result<()> {
+        if let Some(x) = self.register_index(token) {
+            return self.register_statement(x);
+        }
+        match token {
+            ":" => self.label(),
+            ":const" => {
+                let name = self.new_name()?;
+                let token = self.next()?;
+                let value = self.value(&token)?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":alias" => {
+                let name = self.new_name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+                Ok(())
+            }
+            ":macro" => self.define_macro(),
+            ":calc" => {
+                let name = self.new_name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":byte" => {
+                let byte = if self.peek() == Some("{") {
+                    self.next()?;
+                    let value = self.calc()?;
+                    self.fit_byte(value, "The calculation")?
+                } else {
+                    self.byte()?
+                };
+                self.emit(byte)
+            }
+            ":org" => {
+                let token = self.next()?;
+                let value = self.value(&token)?;
+                if !(PROGRAM_START as f64..MEMORY_END as f64).contains(&value) {
+                    return self.error(format!("{token} isn't somewhere a program can go"));
+                }
+                self.start()?;
+                self.here = value as u16;
+                Ok(())
+            }
+            // Two bytes of data, a nibble and then an address, usually to be loaded into an i :=
+            ":unpack" => {
+                let nibble = self.nibble()?;
+                let token = self.next()?;
+                let address = self.address(&token)?;
+                self.emit((nibble << 4) | (address >> 8) as u8)?;
+                self.emit(address as u8)
+            }
+            ":call" => self.address_instruction(0x2000),
+            "clear" => self.instruction(0x00E0),
+            "return" | ";" => self.instruction(0x00EE),
+            "jump" => self.address_instruction(0x1000),
+            "jump0" => self.address_instruction(0xB000),
+            "native" => self.address_instruction(0x0000),
+            "bcd" => self.register_instruction(0xF033),
+            "save" => self.register_instruction(0xF055),
+            "load" => self.register_instruction(0xF065),
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.nibble()? as u16;
+                self.instruction(0xD000 | (x << 8) | (y << 4) | n)
+            }
+            "delay" => {
+                self.expect(":=")?;
+                self.register_instruction(0xF015)
+            }
+            "buzzer" => {
+                self.expect(":=")?;
+                self.register_instruction(0xF018)
+            }
+            "i" => self.index_statement(),
+            "if" => self.begin_if(),
+            "else" => match self.blocks.pop() {
+                Some((Block::If(jump), line)) => {
+                    let skip = self.here;
+                    self.instruction(0x1000)?;
+                    self.patch(jump, self.here);
+                    self.blocks.push((Block::Else(skip), line));
+                    Ok(())
+                }
+                _ => self.error("else without an if ... begin"),
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::If(jump) | Block::Else(jump), _)) => {
+                    self.patch(jump, self.here);
+                    Ok(())
+                }
+                _ => self.error("end without an if ... begin"),
+            },
+            "loop" => {
+                self.start()?;
+                self.blocks.push((
+                    Block::Loop {
+                        start: self.here,
+                        exits: Vec::new(),
+                    },
+                    self.line,
+                ));
+                Ok(())
+            }
+            "while" => self.exit_loop(),
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop { start, exits }, _)) => {
+                    self.instruction(0x1000 | start)?;
+                    for exit in exits {
+                        self.patch(exit, self.here);
+                    }
+                    Ok(())
+                }
+                _ => self.error("again without a loop"),
+            },
+            _ if self.macros.contains_key(token) => self.expand(token),
+            // A label on its own is a call
+            _ if self.labels.contains_key(token) => self.call(token),
+            _ => match self.value_of(token) {
+                Some(value) => {
+                    let byte = self.fit_byte(value, token)?;
+                    self.emit(byte)
+                }
+                // Most likely a label further down
+                None if is_name(token) => self.call(token),
+                None => self.error(format!("Didn't expect {token} here")),
+            },
+        }
+    }
+
+    // Octo starts running at 0x200, so unless the program starts with `: main` the first two bytes
+    // jump to it
+    fn start(&mut self) -> Result<()> {
+        if !self.started {
+            self.started = true;
+            self.pending.push(Pending {
+                at: self.here,
+                label: "main".to_string(),
+                line: self.line,
+            });
+            self.emit(0x10)?;
+            self.emit(0x00)?;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<()> {
+        self.start()?;
+        if self.here >= MEMORY_END {
+            return self.error("The program doesn't fit in memory");
+        }
+        let offset = (self.here - PROGRAM_START) as usize;
+        if offset >= self.rom.len() {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn instruction(&mut self, opcode: u16) -> Result<()> {
+        self.start()?;
+        self.lines.insert(self.here, self.line);
+        self.emit((opcode >> 8) as u8)?;
+        self.emit(opcode as u8)
+    }
+
+    // Points a jump that was put down before we knew where it went at the target
+    fn patch(&mut self, at: u16, target: u16) {
+        let offset = (at - PROGRAM_START) as usize;
+        self.rom[offset] = 0x10 | (target >> 8) as u8;
+        self.rom[offset + 1] = target as u8;
+    }
+
+    fn label(&mut self) -> Result<()> {
+        let name = self.new_name()?;
+        // Nothing's been put down yet, so main can go right at the start without a jump to it
+        if name == "main" && !self.started {
+            self.started = true;
+        } else {
+            self.start()?;
+        }
+        self.labels.insert(name, self.here);
+        Ok(())
+    }
+
+    // A name for a label, constant or alias, which can't already mean something else
+    fn new_name(&mut self) -> Result<String> {
+        let name = self.next()?;
+        if !is_name(&name) || self.register_index(&name).is_some() {
+            return self.error(format!("{name} can't be used as a name"));
+        }
+        if self.labels.contains_key(&name)
+            || self.constants.contains_key(&name)
+            || self.aliases.contains_key(&name)
+            || self.macros.contains_key(&name)
+        {
+            return self.error(format!("{name} has already been defined"));
+        }
+        Ok(name)
+    }
+
+    fn value_of(&self, text: &str) -> Option<f64> {
+        number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .or_else(|| self.labels.get(text).map(|address| *address as f64))
+    }
+
+    fn value(&self, token: &str) -> Result<f64> {
+        match self.value_of(token) {
+            Some(value) => Ok(value),
+            None => self.error(format!(
+                "{token} isn't a number or something defined before it"
+            )),
+        }
+    }
+
+    fn fit_byte(&self, value: f64, what: &str) -> Result<u8> {
+        if (-128.0..=255.0).contains(&value) {
+            Ok((value as i64 & 0xFF) as u8)
+        } else {
+            self.error(format!("{what} doesn't fit in a byte"))
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let token = self.next()?;
+        let value = self.value(&token)?;
+        self.fit_byte(value, &token)
+    }
+
+    fn nibble(&mut self) -> Result<u8> {
+        let token = self.next()?;
+        let value = self.value(&token)?;
+        if (0.0..16.0).contains(&value) {
+            Ok(value as u8)
+        } else {
+            self.error(format!("{token} doesn't fit in a nibble"))
+        }
+    }
+
+    // Labels can be used before they're defined, those get filled in once everything's assembled
+    fn address(&mut self, token: &str) -> Result<u16> {
+        match self.value_of(token) {
+            Some(value) if (0.0..MEMORY_END as f64).contains(&value) => Ok(value as u16),
+            Some(_) => self.error(format!("{token} isn't an address")),
+            None if is_name(token) => {
+                self.start()?;
+                self.pending.push(Pending {
+                    at: self.here,
+                    label: token.to_string(),
+                    line: self.line,
+                });
+                Ok(0)
+            }
+            None => self.error(format!("{token} isn't an address")),
+        }
+    }
+
+    fn address_instruction(&mut self, opcode: u16) -> Result<()> {
+        let token = self.next()?;
+        let address = self.address(&token)?;
+        self.instruction(opcode | address)
+    }
+
+    fn call(&mut self, label: &str) -> Result<()> {
+        let address = self.address(label)?;
+        self.instruction(0x2000 | address)
+    }
+
+    fn register_index(&self, text: &str) -> Option<u8> {
+        if let Some(digit) = text.strip_prefix(['v', 'V'])
+            && digit.len() == 1
+        {
+            u8::from_str_radix(digit, 16).ok()
+        } else {
+            self.aliases.get(text).copied()
+        }
+    }
+
+    fn register(&mut self) -> Result<u8> {
+        let token = self.next()?;
+        match self.register_index(&token) {
+            Some(register) => Ok(register),
+            None => self.error(format!("Expected a register, got {token}")),
+        }
+    }
+
+    fn register_instruction(&mut self, opcode: u16) -> Result<()> {
+        let x = self.register()? as u16;
+        self.instruction(opcode | (x << 8))
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        let token = self.next()?;
+        match self.register_index(&token) {
+            Some(register) => Ok(Operand::Register(register)),
+            None => {
+                let value = self.value(&token)?;
+                Ok(Operand::Byte(self.fit_byte(value, &token)?))
+            }
+        }
+    }
+
+    fn register_operand(&mut self, op: &str) -> Result<u16> {
+        match self.operand()? {
+            Operand::Register(y) => Ok((y as u16) << 4),
+            Operand::Byte(_) => self.error(format!("{op} only works between two registers")),
+        }
+    }
+
+    // Everything that starts with a register, `v0 := 5`, `v1 += v2` and so on
+    fn register_statement(&mut self, x: u8) -> Result<()> {
+        let x = (x as u16) << 8;
+        let op = self.next()?;
+        match op.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.byte()? as u16;
+                    self.instruction(0xC000 | x | mask)
+                }
+                Some("delay") => {
+                    self.next()?;
+                    self.instruction(0xF007 | x)
+                }
+                Some("key") => {
+                    self.next()?;
+                    self.instruction(0xF00A | x)
+                }
+                _ => match self.operand()? {
+                    Operand::Register(y) => self.instruction(0x8000 | x | ((y as u16) << 4)),
+                    Operand::Byte(nn) => self.instruction(0x6000 | x | nn as u16),
+                },
+            },
+            "+=" => match self.operand()? {
+                Operand::Register(y) => self.instruction(0x8004 | x | ((y as u16) << 4)),
+                Operand::Byte(nn) => self.instruction(0x7000 | x | nn as u16),
+            },
+            // There's no subtracting a constant, but adding its negative does the same apart from vf
+            "-=" => match self.operand()? {
+                Operand::Register(y) => self.instruction(0x8005 | x | ((y as u16) << 4)),
+                Operand::Byte(nn) => self.instruction(0x7000 | x | nn.wrapping_neg() as u16),
+            },
+            "=-" => {
+                let y = self.register_operand(&op)?;
+                self.instruction(0x8007 | x | y)
+            }
+            "|=" | "&=" | "^=" | ">>=" | "<<=" => {
+                let y = self.register_operand(&op)?;
+                let n = match op.as_str() {
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    ">>=" => 0x6,
+                    _ => 0xE,
+                };
+                self.instruction(0x8000 | x | y | n)
+            }
+            _ => self.error(format!("Unknown operator {op}")),
+        }
+    }
+
+    fn index_statement(&mut self) -> Result<()> {
+        let op = self.next()?;
+        match op.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    self.register_instruction(0xF029)
+                }
+                Some("bighex" | "long") => {
+                    self.error("Only plain CHIP-8 can be assembled, not SCHIP or XO-CHIP")
+                }
+                _ => self.address_instruction(0xA000),
+            },
+            "+=" => self.register_instruction(0xF01E),
+            _ => self.error(format!("Expected := or += after i, got {op}")),
+        }
+    }
+
+    // Puts down anything the condition needs worked out first, and gives back the skip that's taken
+    // when it's true and the one that's taken when it's false
+    fn condition(&mut self) -> Result<(u16, u16)> {
+        let register = self.register()?;
+        let x = (register as u16) << 8;
+        let op = self.next()?;
+        match op.as_str() {
+            "key" => return Ok((0xE09E | x, 0xE0A1 | x)),
+            "-key" => return Ok((0xE0A1 | x, 0xE09E | x)),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {}
+            _ => return self.error(format!("Unknown comparison {op}")),
+        }
+        let operand = self.operand()?;
+        let (equal, not_equal) = match operand {
+            Operand::Register(y) => (
+                0x5000 | x | ((y as u16) << 4),
+                0x9000 | x | ((y as u16) << 4),
+            ),
+            Operand::Byte(nn) => (0x3000 | x | nn as u16, 0x4000 | x | nn as u16),
+        };
+        match op.as_str() {
+            "==" => return Ok((equal, not_equal)),
+            "!=" => return Ok((not_equal, equal)),
+            _ => {}
+        }
+        // The rest get worked out in vf the way Octo does it, which leaves vf with nowhere to go
+        if register == 0xF {
+            return self.error(format!(
+                "vf can't be compared with {op}, it's needed to work it out"
+            ));
+        }
+        match operand {
+            Operand::Register(y) => self.instruction(0x8F00 | ((y as u16) << 4))?,
+            Operand::Byte(nn) => self.instruction(0x6F00 | nn as u16)?,
+        }
+        // vf -= vx leaves 1 when the operand >= vx, vf =- vx leaves 1 when vx >= the operand
+        let x_in_y = (register as u16) << 4;
+        match op.as_str() {
+            ">" => self.instruction(0x8F05 | x_in_y)?,
+            "<=" => self.instruction(0x8F05 | x_in_y)?,
+            _ => self.instruction(0x8F07 | x_in_y)?,
+        }
+        match op.as_str() {
+            ">" | "<" => Ok((0x3F00, 0x4F00)),
+            _ => Ok((0x4F00, 0x3F00)),
+        }
+    }
+
+    // `if c then` skips the next statement when c is false. `if c begin` skips over a jump past
+    // the block when it's true, which else or end points at the right place.
+    fn begin_if(&mut self) -> Result<()> {
+        let line = self.line;
+        let (when_true, when_false) = self.condition()?;
+        let token = self.next()?;
+        match token.as_str() {
+            "then" => self.instruction(when_false),
+            "begin" => {
+                self.instruction(when_true)?;
+                let jump = self.here;
+                self.instruction(0x1000)?;
+                self.blocks.push((Block::If(jump), line));
+                Ok(())
+            }
+            _ => self.error(format!(
+                "Expected then or begin after the condition, got {token}"
+            )),
+        }
+    }
+
+    // `while c` leaves the innermost loop when c is false, by jumping past its again
+    fn exit_loop(&mut self) -> Result<()> {
+        if !self
+            .blocks
+            .iter()
+            .any(|(block, _)| matches!(block, Block::Loop { .. }))
+        {
+            return self.error("while outside a loop");
+        }
+        let (when_true, _) = self.condition()?;
+        self.instruction(when_true)?;
+        let exit = self.here;
+        self.instruction(0x1000)?;
+        if let Some(exits) = self
+            .blocks
+            .iter_mut()
+            .rev()
+            .find_map(|(block, _)| match block {
+                Block::Loop { exits, .. } => Some(exits),
+                _ => None,
+            })
+        {
+            exits.push(exit);
+        }
+        Ok(())
+    }
+
+    // `:macro name args { body }`, braces inside the body have to pair up
+    fn define_macro(&mut self) -> Result<()> {
+        let name = self.new_name()?;
+        let mut args = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "{" {
+                break;
+            }
+            args.push(token);
+        }
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next()?;
+            match token.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+        self.macros.insert(name, Macro { args, body });
+        Ok(())
+    }
+
+    // Swaps the arguments into the body and puts it back in front of whatever comes next. It's all
+    // on the line the macro was used on, since that's the line worth pointing at.
+    fn expand(&mut self, name: &str) -> Result<()> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return self.error(format!(
+                "Too many macros inside macros, does {name} use itself?"
+            ));
+        }
+        let line = self.line;
+        let arg_count = self.macros[name].args.len();
+        let values = (0..arg_count)
+            .map(|_| self.next())
+            .collect::<Result<Vec<_>>>()?;
+        let macro_ = &self.macros[name];
+        let body: Vec<Token> = macro_
+            .body
+            .iter()
+            .map(|text| Token {
+                text: macro_
+                    .args
+                    .iter()
+                    .position(|arg| arg == text)
+                    .map_or(text.clone(), |index| values[index].clone()),
+                line,
+            })
+            .collect();
+        for token in body.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    // Everything up to the closing brace. Like Octo there's no precedence, it's worked out right to
+    // left, and every number, operator and bracket needs spaces round it.
+    fn calc(&mut self) -> Result<f64> {
+        let mut expression = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "}" {
+                break;
+            }
+            expression.push(token);
+        }
+        let mut position = 0;
+        let value = self.expression(&expression, &mut position)?;
+        match expression.get(position) {
+            Some(token) => self.error(format!("Didn't expect {token} in the calculation")),
+            None => Ok(value),
+        }
+    }
+
+    fn expression(&self, tokens: &[String], position: &mut usize) -> Result<f64> {
+        let left = self.term(tokens, position)?;
+        let op = match tokens.get(*position) {
+            Some(op) if op != ")" => op.as_str(),
+            _ => return Ok(left),
+        };
+        *position += 1;
+        let right = self.expression(tokens, position)?;
+        let (a, b) = (left as i64, right as i64);
+        Ok(match op {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => self.shift(i64::checked_shl, a, b)?,
+            ">>" => self.shift(i64::checked_shr, a, b)?,
+            _ => return self.error(format!("{op} isn't an operator")),
+        })
+    }
+
+    // Shifting a 64 bit number by 64 or more, or by a negative amount, doesn't mean anything
+    fn shift(&self, shift: fn(i64, u32) -> Option<i64>, a: i64, b: i64) -> Result<f64> {
+        match u32::try_from(b).ok().and_then(|b| shift(a, b)) {
+            Some(value) => Ok(value as f64),
+            None => self.error(format!("Can't shift by {b}, it has to be from 0 to 63")),
+        }
+    }
+
+    fn term(&self, tokens: &[String], position: &mut usize) -> Result<f64> {
+        let Some(token) = tokens.get(*position) else {
+            return self.error("The calculation stops partway through");
+        };
+        *position += 1;
+        let unary: fn(f64) -> f64 = match token.as_str() {
+            "(" => {
+                let value = self.expression(tokens, position)?;
+                if tokens.get(*position).map(String::as_str) != Some(")") {
+                    return self.error("The calculation is missing a )");
+                }
+                *position += 1;
+                return Ok(value);
+            }
+            "HERE" => return Ok(self.here as f64),
+            "PI" => return Ok(std::f64::consts::PI),
+            "E" => return Ok(std::f64::consts::E),
+            "-" => |value| -value,
+            "~" => |value| !(value as i64) as f64,
+            "!" => |value| (value == 0.0) as u8 as f64,
+            "abs" => f64::abs,
+            "sqrt" => f64::sqrt,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            "exp" => f64::exp,
+            "log" => f64::ln,
+            "sign" => f64::signum,
+            "ceil" => f64::ceil,
+            "floor" => f64::floor,
+            _ => return self.value(token),
+        };
+        Ok(unary(self.term(tokens, position)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(source: &str) -> std::result::Result<Vec<u8>, (usize, String)> {
+        let mut assembler = Assembler::new(source);
+        match assembler.run() {
+            Ok(()) => Ok(assembler.rom),
+            Err(err) => Err((err.line, err.message)),
+        }
+    }
+
+    #[test]
+    fn if_else_jumps_over_whichever_half_doesnt_run() {
+        let source = "
+            : main
+              if v0 == 5 begin
+                v1 := 2
+              else
+                v1 := 3
+              end
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                0x30, 0x05, // 200: skip if V0 == 5
+                0x12, 0x08, // 202: jump to the else half
+                0x61, 0x02, // 204: V1 = 2
+                0x12, 0x0A, // 206: jump to the end
+                0x61, 0x03, // 208: V1 = 3
+            ]
+        );
+    }
+
+    #[test]
+    fn while_leaves_the_loop_past_its_again() {
+        let source = "
+            : main
+              loop
+                v0 += 1
+                while v0 != 10
+              again
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                0x70, 0x01, // 200: V0 += 1
+                0x40, 0x0A, // 202: skip if V0 != 10
+                0x12, 0x08, // 204: jump out
+                0x12, 0x00, // 206: back to the start
+            ]
+        );
+    }
+
+    #[test]
+    fn macros_and_calc() {
+        let source = "
+            :macro add-twice reg n { reg += n reg += n }
+            :calc big { 3 << 4 }
+            : main
+              add-twice v2 3
+              :byte big
+              :byte { big + 1 }
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [0x72, 0x03, 0x72, 0x03, 0x30, 0x31]
+        );
+    }
+
+    #[test]
+    fn code_before_main_jumps_to_it() {
+        let source = "
+            : sub
+              return
+            : main
+              sub
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]
+        );
+    }
+
+    #[test]
+    fn shifting_too_far_is_an_error() {
+        let source = "
+            : main
+              :calc big { 1 << 64 }
+        ";
+        assert_eq!(
+            assemble(source),
+            Err((
+                3,
+                "Can't shift by 64, it has to be from 0 to 63".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use std::io;
+
+use raylib::prelude::Color;
+
+use crate::cli::{PaletteArg, RunOptions};
+use crate::config::Config;
+
+// Everything that gets drawn takes its colours from the current palette
+#[derive(Debug, Clone)]
+pub struct Palette {
+    pub name: String,
+    // Background, plane 1, plane 2 and both planes. XO-CHIP draws on two planes and a pixel gets
+    // one of these four colours depending on which planes it's set in. Until we have planes only
+    // the first two get used.
+    pub pixels: [Color; 4],
+    pub ui_background: Color,
+    pub ui_text: Color,
+}
+
+impl Palette {
+    // The original look, blue pixels with a black and white debug UI
+    fn classic() -> Self {
+        Palette {
+            name: "classic".to_string(),
+            pixels: [Color::DARKBLUE, Color::LIGHTBLUE, Color::GOLD, Color::WHITE],
+            ui_background: Color::BLACK,
+            ui_text: Color::WHITE,
+        }
+    }
+
+    // Made up from just a foreground and background, e.g. from --palette or the ROM database
+    pub fn from_colours(name: &str, foreground: [u8; 3], background: [u8; 3]) -> Self {
+        let mut palette = Palette::classic();
+        palette.name = name.to_string();
+        palette.pixels[0] = rgb(background);
+        palette.pixels[1] = rgb(foreground);
+        palette
+    }
+
+    pub fn background(&self) -> Color {
+        self.pixels[0]
+    }
+
+    pub fn foreground(&self) -> Color {
+        self.pixels[1]
+    }
+
+    // Part way between the background and the foreground, for pixels that are fading out
+    pub fn shade(&self, intensity: f32) -> Color {
+        let (background, foreground) = (self.background(), self.foreground());
+        let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * intensity) as u8;
+        Color::new(
+            mix(background.r, foreground.r),
+            mix(background.g, foreground.g),
+            mix(background.b, foreground.b),
+            255,
+        )
+    }
+}
+
+fn rgb([r, g, b]: [u8; 3]) -> Color {
+    Color::new(r, g, b, 255)
+}
+
+fn built_in_palettes() -> Vec<Palette> {
+    vec![
+        Palette::classic(),
+        // A green phosphor CRT
+        Palette {
+            name: "green".to_string(),
+            pixels: [
+                rgb([0x0A, 0x1A, 0x0A]),
+                rgb([0x33, 0xFF, 0x66]),
+                rgb([0x1E, 0x7F, 0x3A]),
+                rgb([0xB3, 0xFF, 0xC6]),
+            ],
+            ui_background: rgb([0x05, 0x0D, 0x05]),
+            ui_text: rgb([0x33, 0xFF, 0x66]),
+        },
+        // An amber monochrome monitor
+        Palette {
+            name: "amber".to_string(),
+            pixels: [
+                rgb([0x1A, 0x10, 0x00]),
+                rgb([0xFF, 0xB0, 0x00]),
+                rgb([0x99, 0x5C, 0x00]),
+                rgb([0xFF, 0xE0, 0x99]),
+            ],
+            ui_background: rgb([0x0D, 0x08, 0x00]),
+            ui_text: rgb([0xFF, 0xB0, 0x00]),
+        },
+        Palette {
+            name: "mono".to_string(),
+            pixels: [
+                Color::BLACK,
+                Color::WHITE,
+                rgb([0x80, 0x80, 0x80]),
+                rgb([0xC0, 0xC0, 0xC0]),
+            ],
+            ui_background: Color::BLACK,
+            ui_text: Color::WHITE,
+        },
+        // The plane colours come from the Okabe-Ito set, which stay distinct for the common kinds
+        // of colour blindness
+        Palette {
+            name: "high-contrast".to_string(),
+            pixels: [
+                Color::BLACK,
+                Color::WHITE,
+                rgb([0xE6, 0x9F, 0x00]),
+                rgb([0x56, 0xB4, 0xE9]),
+            ],
+            ui_background: Color::BLACK,
+            ui_text: Color::WHITE,
+        },
+    ]
+}
+
+// The built in palettes followed by any from the config. A [palette.<name>] section defines one,
+// and using the name of a built in palette replaces it, e.g.
+//
+//     [palette.gameboy]
+//     pixels = 0F380F, 9BBC0F, 306230, 8BAC0F
+//     ui_background = 0F380F
+//     ui_text = 9BBC0F
+//
+// `pixels` needs at least the background and the foreground, the plane colours are optional.
+pub fn load_palettes(config: &Config) -> Vec<Palette> {
+    let mut palettes = built_in_palettes();
+
+    let mut names: Vec<&str> = config
+        .section_names()
+        .filter_map(|section| section.strip_prefix("palette."))
+        .collect();
+    names.sort();
+
+    for name in names {
+        let section = format!("palette.{name}");
+        let Some(palette) = palette_from_config(config, &section, name) else {
+            continue;
+        };
+        match palettes.iter_mut().find(|existing| existing.name == name) {
+            Some(existing) => *existing = palette,
+            None => palettes.push(palette),
+        }
+    }
+    palettes
+}
+
+fn palette_from_config(config: &Config, section: &str, name: &str) -> Option<Palette> {
+    let colour = |key: &str| -> Option<Color> {
+        let value = config.get(section, key)?;
+        let colour = parse_hex_colour(value).map(rgb);
+        if colour.is_none() {
+            eprintln!("[{section}] {key} isn't a colour, expected something like FF8800");
+        }
+        colour
+    };
+
+    let mut palette = Palette::classic();
+    palette.name = name.to_string();
+
+    let pixels: Vec<&str> = config
+        .get(section, "pixels")
+        .map(|value| value.split(',').map(str::trim).collect())
+        .unwrap_or_default();
+    if pixels.len() < 2 {
+        eprintln!("[{section}] pixels needs at least a background and a foreground colour");
+        return None;
+    }
+    for (slot, value) in palette.pixels.iter_mut().zip(&pixels) {
+        let Some(colour) = parse_hex_colour(value) else {
+            eprintln!("[{section}] {value} isn't a colour, expected something like FF8800");
+            return None;
+        };
+        *slot = rgb(colour);
+    }
+
+    if let Some(background) = colour("ui_background") {
+        palette.ui_background = background;
+    }
+    if let Some(text) = colour("ui_text") {
+        palette.ui_text = text;
+    }
+    Some(palette)
+}
+
+// Which palette to start with. --palette wins, then [display] palette from the config, then the
+// colours from the ROM database. Colours that don't belong to a named palette get added to the
+// front of the list so F2 can still cycle back to them.
+pub fn pick_palette(
+    palettes: &mut Vec<Palette>,
+    options: &RunOptions,
+    config: &Config,
+    database_colours: Option<([u8; 3], [u8; 3])>,
+) -> io::Result<usize> {
+    let name = match &options.palette {
+        Some(PaletteArg::Colours(foreground, background)) => {
+            palettes.insert(0, Palette::from_colours("custom", *foreground, *background));
+            return Ok(0);
+        }
+        Some(PaletteArg::Named(name)) => Some(name.as_str()),
+        None => config.get("display", "palette"),
+    };
+
+    if let Some(name) = name {
+        return palettes
+            .iter()
+            .position(|palette| palette.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let names: Vec<&str> = palettes.iter().map(|p| p.name.as_str()).collect();
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Unknown palette {name}, expected one of {}",
+                        names.join(", ")
+                    ),
+                )
+            });
+    }
+    if let Some((foreground, background)) = database_colours {
+        palettes.insert(0, Palette::from_colours("rom", foreground, background));
+    }
+    Ok(0)
+}
+
+// Accepts RRGGBB with or without a leading #
+pub fn parse_hex_colour(hex: &str) -> Option<[u8; 3]> {
+    let hex = hex.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
@@ -0,0 +1,252 @@
+use crate::config::Config;
+
+// CHIP-8 games move sprites by XORing them off and drawing them again, so a sprite spends part of
+// every frame erased and flickers like mad on a modern display. The CRTs these were played on had
+// phosphor that kept glowing for a little while after the beam moved on, which hid most of it.
+// This sits between the cpu's pixel buffer and the renderer and fakes that glow.
+//
+// Everything here works on whole frames and keeps no state besides what's passed in, the
+// Phosphor struct just holds on to what it needs between frames.
+
+pub type FrameBuffer = [[bool; 64]; 32];
+// How brightly each pixel is lit, from 0.0 (background) to 1.0 (foreground)
+pub type Intensities = [[f32; 64]; 32];
+// How many frames ago each pixel was last lit
+pub type Ages = [[u32; 64]; 32];
+
+// How dim the exponential curve gets on the last frame before a pixel goes out
+const CUTOFF: f32 = 1.0 / 32.0;
+const DEFAULT_DECAY_FRAMES: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    // Fades by the same amount every frame
+    Linear,
+    // Fades quickly at first and then lingers, closer to how real phosphor behaves
+    Exponential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Persistence {
+    Off,
+    // Pixels that switch off fade out over this many frames
+    Decay { frames: u32, curve: Curve },
+    // A pixel is lit if it was lit in this frame or the one before
+    Blend,
+}
+
+impl Persistence {
+    // Used by the hotkey to step through the modes
+    pub fn next(self, decay_frames: u32, curve: Curve) -> Self {
+        match self {
+            Persistence::Off => Persistence::Decay {
+                frames: decay_frames,
+                curve,
+            },
+            Persistence::Decay { .. } => Persistence::Blend,
+            Persistence::Blend => Persistence::Off,
+        }
+    }
+}
+
+pub fn off(frame: &FrameBuffer) -> Intensities {
+    frame.map(|row| row.map(|lit| if lit { 1.0 } else { 0.0 }))
+}
+
+// Called every frame whatever the persistence is, so switching to decay carries on smoothly
+pub fn age(ages: &mut Ages, frame: &FrameBuffer) {
+    for y in 0..32 {
+        for x in 0..64 {
+            ages[y][x] = if frame[y][x] {
+                0
+            } else {
+                ages[y][x].saturating_add(1)
+            };
+        }
+    }
+}
+
+pub fn decay(ages: &Ages, frames: u32, curve: Curve) -> Intensities {
+    ages.map(|row| row.map(|age| fade(age, frames, curve)))
+}
+
+// Brightness is worked out from whole frames rather than taken off a bit at a time, so a pixel is
+// gone after exactly `frames` frames and rounding can't keep it around for one more
+fn fade(age: u32, frames: u32, curve: Curve) -> f32 {
+    let frames = frames.max(1);
+    if age >= frames {
+        return 0.0;
+    }
+    let progress = age as f32 / frames as f32;
+    match curve {
+        Curve::Linear => 1.0 - progress,
+        Curve::Exponential => CUTOFF.powf(progress),
+    }
+}
+
+pub fn blend(previous: &FrameBuffer, frame: &FrameBuffer) -> Intensities {
+    let mut result = [[0.0; 64]; 32];
+    for y in 0..32 {
+        for x in 0..64 {
+            if frame[y][x] || previous[y][x] {
+                result[y][x] = 1.0;
+            }
+        }
+    }
+    result
+}
+
+pub struct Phosphor {
+    pub persistence: Persistence,
+    pub decay_frames: u32,
+    pub curve: Curve,
+    intensities: Intensities,
+    ages: Ages,
+    previous_frame: FrameBuffer,
+}
+
+impl Phosphor {
+    // Reads `persistence` (off, decay or blend), `decay_frames` and `decay_curve` (linear or
+    // exponential) from the [display] section
+    pub fn from_config(config: &Config) -> Self {
+        let decay_frames = config
+            .get_parsed("display", "decay_frames")
+            .unwrap_or(DEFAULT_DECAY_FRAMES)
+            .max(1);
+        let curve = match config.get("display", "decay_curve") {
+            Some("linear") => Curve::Linear,
+            Some("exponential") | None => Curve::Exponential,
+            Some(other) => {
+                eprintln!("[display] Unknown decay_curve {other}, expected linear or exponential");
+                Curve::Exponential
+            }
+        };
+        let persistence = match config.get("display", "persistence") {
+            Some("off") | None => Persistence::Off,
+            Some("decay") => Persistence::Decay {
+                frames: decay_frames,
+                curve,
+            },
+            Some("blend") => Persistence::Blend,
+            Some(other) => {
+                eprintln!("[display] Unknown persistence {other}, expected off, decay or blend");
+                Persistence::Off
+            }
+        };
+
+        Phosphor {
+            persistence,
+            decay_frames,
+            curve,
+            intensities: [[0.0; 64]; 32],
+            ages: [[u32::MAX; 64]; 32],
+            previous_frame: [[false; 64]; 32],
+        }
+    }
+
+    pub fn cycle_mode(&mut self) {
+        self.persistence = self.persistence.next(self.decay_frames, self.curve);
+    }
+
+    // Call once per emulated frame so the fade runs at the same speed however fast we draw
+    pub fn update(&mut self, frame: &FrameBuffer) {
+        age(&mut self.ages, frame);
+        self.intensities = match self.persistence {
+            Persistence::Off => off(frame),
+            Persistence::Decay { frames, curve } => decay(&self.ages, frames, curve),
+            Persistence::Blend => blend(&self.previous_frame, frame),
+        };
+        self.previous_frame = *frame;
+    }
+
+    pub fn intensities(&self) -> &Intensities {
+        &self.intensities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single_pixel(lit: bool) -> FrameBuffer {
+        let mut frame = [[false; 64]; 32];
+        frame[3][5] = lit;
+        frame
+    }
+
+    // How many frames a pixel keeps glowing for after it's switched off
+    fn frames_to_fade(frames: u32, curve: Curve) -> u32 {
+        let mut ages = [[u32::MAX; 64]; 32];
+        age(&mut ages, &single_pixel(true));
+        let mut intensities = decay(&ages, frames, curve);
+        let mut faded = 0;
+        while intensities[3][5] > 0.0 {
+            let previous = intensities[3][5];
+            age(&mut ages, &single_pixel(false));
+            intensities = decay(&ages, frames, curve);
+            assert!(intensities[3][5] < previous);
+            faded += 1;
+        }
+        faded
+    }
+
+    #[test]
+    fn decay_is_gone_after_its_frames() {
+        for frames in 1..=64 {
+            assert_eq!(
+                frames_to_fade(frames, Curve::Linear),
+                frames,
+                "linear over {frames}"
+            );
+            assert_eq!(
+                frames_to_fade(frames, Curve::Exponential),
+                frames,
+                "exponential over {frames}"
+            );
+        }
+    }
+
+    #[test]
+    fn decay_lights_drawn_pixels_fully() {
+        let mut ages = [[1; 64]; 32];
+        age(&mut ages, &single_pixel(true));
+        let intensities = decay(&ages, 4, Curve::Linear);
+        assert_eq!(intensities[3][5], 1.0);
+        assert_eq!(intensities[0][0], 0.5);
+    }
+
+    #[test]
+    fn exponential_decay_ends_at_the_cutoff() {
+        assert_eq!(fade(0, 4, Curve::Exponential), 1.0);
+        assert_eq!(fade(3, 4, Curve::Exponential), CUTOFF.powf(0.75));
+        assert_eq!(fade(4, 4, Curve::Exponential), 0.0);
+        assert_eq!(fade(u32::MAX, 4, Curve::Exponential), 0.0);
+    }
+
+    #[test]
+    fn switching_to_decay_fades_what_was_already_off() {
+        let mut phosphor = Phosphor::from_config(&Config::parse(""));
+        phosphor.update(&single_pixel(true));
+        phosphor.update(&single_pixel(false));
+        phosphor.persistence = Persistence::Decay {
+            frames: 4,
+            curve: Curve::Linear,
+        };
+        phosphor.update(&single_pixel(false));
+        assert_eq!(phosphor.intensities()[3][5], 0.5);
+        assert_eq!(phosphor.intensities()[0][0], 0.0);
+    }
+
+    #[test]
+    fn blend_lights_either_frame() {
+        let mut previous = single_pixel(true);
+        previous[0][0] = true;
+        let mut frame = single_pixel(false);
+        frame[31][63] = true;
+        let intensities = blend(&previous, &frame);
+        assert_eq!(intensities[3][5], 1.0);
+        assert_eq!(intensities[0][0], 1.0);
+        assert_eq!(intensities[31][63], 1.0);
+        assert_eq!(intensities[10][10], 0.0);
+    }
+}
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+// The random number generator behind CXNN. It's SplitMix64, which is tiny, fast and random enough
+// for games, and more importantly its whole state is one number. That makes it easy to seed, easy
+// to put in a save state, and means a seed plus the same inputs always plays out the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Prng {
+    state: u64,
+}
+
+impl Prng {
+    pub fn from_seed(seed: u64) -> Self {
+        Prng { state: seed }
+    }
+
+    // A different seed every run, for when nobody asked for one
+    pub fn from_entropy() -> Self {
+        Prng::from_seed(rand::random())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let (mut a, mut b) = (Prng::from_seed(1234), Prng::from_seed(1234));
+        for _ in 0..1000 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        let mut c = Prng::from_seed(1235);
+        assert_ne!(a.next_u64(), c.next_u64());
+    }
+
+    #[test]
+    fn matches_splitmix64() {
+        let mut prng = Prng::from_seed(0);
+        assert_eq!(prng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(prng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+        assert_eq!(prng.next_u64(), 0x06C4_5D18_8009_454F);
+    }
+
+    // A save state holds a copy, which has to carry on exactly where the original would have
+    #[test]
+    fn a_copy_carries_on_the_same() {
+        let mut prng = Prng::from_seed(99);
+        prng.next_u64();
+        let mut copy = prng;
+        assert_eq!(prng.next_u8(), copy.next_u8());
+        assert_eq!(prng.next_u64(), copy.next_u64());
+        assert_eq!(
+            Prng::from_seed(99).next_u8(),
+            (Prng::from_seed(99).next_u64() >> 56) as u8
+        );
+    }
+}
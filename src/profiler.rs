@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::cpu::Cpu;
+use crate::disasm::mnemonic;
+
+// How long a COSMAC VIP machine cycle took, 8 clock cycles at 1.7609MHz. Costs are counted in
+// these so the trace lines up with how long things took on the real machine.
+const MICROSECONDS_PER_CYCLE: f64 = 8.0 / 1.7609;
+
+// A long session makes a lot of calls, after this many the trace stops growing but the counts
+// carry on
+const MAX_TRACE_EVENTS: usize = 2_000_000;
+
+const HOTTEST_ADDRESSES: usize = 20;
+
+#[derive(Default)]
+struct Subroutine {
+    calls: u64,
+    // Everything from the call to the return, including other subroutines it called
+    inclusive: u64,
+    // Just its own instructions
+    exclusive: u64,
+}
+
+// A call that hasn't returned yet. The address is None when we came in part way through, like
+// after loading a state with calls already on the stack.
+struct OpenCall {
+    address: Option<u16>,
+    started: u64,
+    // Cycles spent in subroutines it called, taken off to get its exclusive cost
+    in_callees: u64,
+}
+
+// One entry in Chrome's trace event format, which Perfetto and chrome://tracing both read
+#[derive(Serialize)]
+struct TraceEvent {
+    name: String,
+    ph: &'static str,
+    ts: f64,
+    pid: u32,
+    tid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s: Option<&'static str>,
+}
+
+// Watches every instruction go by, counting how often each address runs and what it costs, and
+// follows CALL and 00EE through the depth of the cpu's stack to charge time to subroutines
+pub struct Profiler {
+    runs: Vec<u64>,
+    cycles: Vec<u64>,
+    // The last opcode seen at each address, for naming them in the report
+    opcodes: Vec<u16>,
+    subroutines: BTreeMap<u16, Subroutine>,
+    // One for every return address on the cpu's stack
+    open_calls: Vec<OpenCall>,
+    clock: u64,
+    frames: u64,
+    busiest_frame: u64,
+    frame_started: u64,
+    events: Vec<TraceEvent>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            runs: vec![0; 4096],
+            cycles: vec![0; 4096],
+            opcodes: vec![0; 4096],
+            subroutines: BTreeMap::new(),
+            open_calls: Vec::new(),
+            clock: 0,
+            frames: 0,
+            busiest_frame: 0,
+            frame_started: 0,
+            events: Vec::new(),
+        }
+    }
+
+    // Runs one instruction and returns what it cost on the VIP
+    pub fn step(&mut self, cpu: &mut Cpu) -> u32 {
+        let address = cpu.program_counter;
+        let instruction = cpu.instruction_at(address);
+        let cost = cpu.vip_cycles(&instruction);
+        // Anything that changed the stack behind our back, like loading a state, gets caught up
+        // with before the instruction runs
+        self.follow_stack(cpu.stack_depth());
+
+        let op = cpu.fetch_decoded();
+        cpu.execute(op);
+
+        let index = address as usize;
+        self.runs[index] += 1;
+        self.cycles[index] += cost as u64;
+        self.opcodes[index] = ((instruction.instruction as u16) << 12) | instruction.nnn;
+        self.clock += cost as u64;
+
+        let depth = cpu.stack_depth();
+        if depth > self.open_calls.len() {
+            self.enter(Some(cpu.program_counter));
+        } else if depth < self.open_calls.len() {
+            self.leave();
+        }
+        cost
+    }
+
+    // Called at the end of every frame
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        self.busiest_frame = self.busiest_frame.max(self.clock - self.frame_started);
+        self.frame_started = self.clock;
+        self.push_event("frame", "i", Some("g"));
+    }
+
+    fn follow_stack(&mut self, depth: usize) {
+        while self.open_calls.len() > depth {
+            self.leave();
+        }
+        while self.open_calls.len() < depth {
+            self.enter(None);
+        }
+    }
+
+    fn enter(&mut self, address: Option<u16>) {
+        self.open_calls.push(OpenCall {
+            address,
+            started: self.clock,
+            in_callees: 0,
+        });
+        self.push_event(&call_name(address), "B", None);
+    }
+
+    fn leave(&mut self) {
+        let Some(call) = self.open_calls.pop() else {
+            return;
+        };
+        let inclusive = self.clock - call.started;
+        if let Some(caller) = self.open_calls.last_mut() {
+            caller.in_callees += inclusive;
+        }
+        if let Some(address) = call.address {
+            let subroutine = self.subroutines.entry(address).or_default();
+            subroutine.calls += 1;
+            subroutine.inclusive += inclusive;
+            subroutine.exclusive += inclusive - call.in_callees;
+        }
+        self.push_event(&call_name(call.address), "E", None);
+    }
+
+    fn push_event(&mut self, name: &str, ph: &'static str, s: Option<&'static str>) {
+        if self.events.len() < MAX_TRACE_EVENTS {
+            self.events.push(TraceEvent {
+                name: name.to_string(),
+                ph,
+                ts: self.clock as f64 * MICROSECONDS_PER_CYCLE,
+                pid: 1,
+                tid: 1,
+                s,
+            });
+        }
+    }
+
+    // The hottest addresses and what every subroutine cost, as lines of text
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let total = self.clock.max(1);
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total as f64;
+
+        lines.push(format!(
+            "Profiled {} instructions over {} frames, {} VIP cycles",
+            self.runs.iter().sum::<u64>(),
+            self.frames,
+            self.clock
+        ));
+        if let Some(average) = self.clock.checked_div(self.frames) {
+            lines.push(format!(
+                "Cycles per frame: {average} on average, {} at most",
+                self.busiest_frame
+            ));
+        }
+
+        lines.push(String::new());
+        lines.push(format!(
+            "  {:<27} {:>10} {:>10}",
+            "Hottest addresses", "runs", "cycles"
+        ));
+        let mut addresses: Vec<usize> = (0..self.runs.len())
+            .filter(|address| self.runs[*address] > 0)
+            .collect();
+        addresses.sort_by_key(|address| std::cmp::Reverse(self.cycles[*address]));
+        for address in addresses.into_iter().take(HOTTEST_ADDRESSES) {
+            lines.push(format!(
+                "  {address:#05X}  {:<20} {:>10} {:>10}  {:5.1}%",
+                mnemonic(self.opcodes[address]),
+                self.runs[address],
+                self.cycles[address],
+                percent(self.cycles[address])
+            ));
+        }
+
+        lines.push(String::new());
+        lines.push(format!(
+            "  {:<9}  {:>9}   {:>17}   {:>17}",
+            "Subroutine", "calls", "inclusive", "exclusive"
+        ));
+        // Whatever's still running hasn't been charged to anything yet, so the top level gets
+        // what's left over
+        let charged: u64 = self.subroutines.values().map(|sub| sub.exclusive).sum();
+        let top_level = self.clock.saturating_sub(charged);
+        lines.push(format!(
+            "  top level  {:>9}   {:>10} {:5.1}%   {:>10} {:5.1}%",
+            "-",
+            self.clock,
+            100.0,
+            top_level,
+            percent(top_level)
+        ));
+        let mut subroutines: Vec<(&u16, &Subroutine)> = self.subroutines.iter().collect();
+        subroutines.sort_by_key(|(_, sub)| std::cmp::Reverse(sub.inclusive));
+        for (address, sub) in subroutines {
+            lines.push(format!(
+                "  {address:#05X}      {:>9}   {:>10} {:5.1}%   {:>10} {:5.1}%",
+                sub.calls,
+                sub.inclusive,
+                percent(sub.inclusive),
+                sub.exclusive,
+                percent(sub.exclusive)
+            ));
+        }
+        lines
+    }
+
+    // Anything that hasn't returned yet gets closed off at the end, so the viewer doesn't show
+    // calls running off forever
+    pub fn write_trace(&mut self, path: &Path) -> io::Result<()> {
+        let still_open: Vec<String> = self
+            .open_calls
+            .iter()
+            .rev()
+            .map(|call| call_name(call.address))
+            .collect();
+        for name in still_open {
+            self.push_event(&name, "E", None);
+        }
+        let json = serde_json::json!({
+            "traceEvents": self.events,
+            "displayTimeUnit": "ms",
+        });
+        fs::write(path, json.to_string())
+    }
+}
+
+fn call_name(address: Option<u16>) -> String {
+    match address {
+        Some(address) => format!("sub {address:#05X}"),
+        None => "sub ?".to_string(),
+    }
+}
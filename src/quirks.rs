@@ -0,0 +1,209 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+// The CHIP-8 was reimplemented a lot over the years and each interpreter did a handful of things a
+// little differently. Games were written against whichever one their author had, so to run them
+// properly we need to be able to behave like each of them. The names follow Timendus' quirks test.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Quirks {
+    // 8XY1, 8XY2 and 8XY3 reset VF to zero
+    pub vf_reset: bool,
+    // FX55 and FX65 leave I pointing just past the last register they touched
+    pub memory: bool,
+    // Drawing a sprite waits for the next frame
+    pub display_wait: bool,
+    // Sprites are cut off at the edge of the screen instead of wrapping around to the other side
+    pub clipping: bool,
+    // 8XY6 and 8XYE shift VX in place and ignore VY
+    pub shifting: bool,
+    // BNNN jumps to XNN + VX instead of NNN + V0
+    pub jumping: bool,
+}
+
+pub const QUIRK_NAMES: [&str; 6] = [
+    "vf-reset",
+    "memory",
+    "display-wait",
+    "clipping",
+    "shifting",
+    "jumping",
+];
+
+impl Quirks {
+    pub fn set(&mut self, name: &str, value: bool) -> Result<(), String> {
+        match name {
+            "vf-reset" => self.vf_reset = value,
+            "memory" => self.memory = value,
+            "display-wait" => self.display_wait = value,
+            "clipping" => self.clipping = value,
+            "shifting" => self.shifting = value,
+            "jumping" => self.jumping = value,
+            _ => {
+                return Err(format!(
+                    "Unknown quirk {name}, expected one of {}",
+                    QUIRK_NAMES.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "vf-reset" => Some(self.vf_reset),
+            "memory" => Some(self.memory),
+            "display-wait" => Some(self.display_wait),
+            "clipping" => Some(self.clipping),
+            "shifting" => Some(self.shifting),
+            "jumping" => Some(self.jumping),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quirks: Vec<String> = QUIRK_NAMES
+            .iter()
+            .map(|name| {
+                let value = if self.get(name) == Some(true) {
+                    "on"
+                } else {
+                    "off"
+                };
+                format!("{name}={value}")
+            })
+            .collect();
+        write!(f, "{}", quirks.join(","))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    // The original interpreter on the COSMAC VIP
+    Chip8,
+    // SUPER-CHIP 1.1 on the HP48 calculators
+    SuperChip,
+    // Octo's XO-CHIP
+    XoChip,
+}
+
+impl Platform {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" | "vip" => Some(Platform::Chip8),
+            "schip" | "superchip" | "super-chip" => Some(Platform::SuperChip),
+            "xochip" | "xo-chip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip8",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    pub fn default_quirks(&self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks {
+                vf_reset: true,
+                memory: true,
+                display_wait: true,
+                clipping: true,
+                shifting: false,
+                jumping: false,
+            },
+            Platform::SuperChip => Quirks {
+                vf_reset: false,
+                memory: false,
+                display_wait: false,
+                clipping: true,
+                shifting: true,
+                jumping: true,
+            },
+            Platform::XoChip => Quirks {
+                vf_reset: false,
+                memory: true,
+                display_wait: false,
+                clipping: false,
+                shifting: false,
+                jumping: false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_match_timendus_quirks_test() {
+        // vf-reset, memory, display-wait, clipping, shifting, jumping
+        let expected = [
+            (Platform::Chip8, "on,on,on,on,off,off"),
+            (Platform::SuperChip, "off,off,off,on,on,on"),
+            (Platform::XoChip, "off,on,off,off,off,off"),
+        ];
+        for (platform, values) in expected {
+            let quirks = platform.default_quirks();
+            let actual: Vec<&str> = QUIRK_NAMES
+                .iter()
+                .map(|name| {
+                    if quirks.get(name).unwrap() {
+                        "on"
+                    } else {
+                        "off"
+                    }
+                })
+                .collect();
+            assert_eq!(actual.join(","), values, "{}", platform.name());
+        }
+    }
+
+    #[test]
+    fn platforms_are_found_by_any_of_their_names() {
+        for name in ["chip8", "CHIP-8", "vip"] {
+            assert_eq!(Platform::from_name(name), Some(Platform::Chip8));
+        }
+        for name in ["schip", "SuperChip", "super-chip"] {
+            assert_eq!(Platform::from_name(name), Some(Platform::SuperChip));
+        }
+        for name in ["xochip", "XO-CHIP"] {
+            assert_eq!(Platform::from_name(name), Some(Platform::XoChip));
+        }
+        assert_eq!(Platform::from_name("eti660"), None);
+
+        for platform in [Platform::Chip8, Platform::SuperChip, Platform::XoChip] {
+            assert_eq!(Platform::from_name(platform.name()), Some(platform));
+        }
+    }
+
+    #[test]
+    fn each_quirk_can_be_set_by_name() {
+        for name in QUIRK_NAMES {
+            let mut quirks = Platform::Chip8.default_quirks();
+            quirks.set(name, false).unwrap();
+            assert_eq!(quirks.get(name), Some(false));
+            quirks.set(name, true).unwrap();
+            assert_eq!(quirks.get(name), Some(true));
+        }
+
+        let mut quirks = Platform::Chip8.default_quirks();
+        assert!(quirks.set("wrapping", true).is_err());
+        assert_eq!(quirks, Platform::Chip8.default_quirks());
+        assert_eq!(quirks.get("wrapping"), None);
+    }
+
+    #[test]
+    fn quirks_print_the_way_quirks_takes_them() {
+        assert_eq!(
+            Platform::SuperChip.default_quirks().to_string(),
+            "vf-reset=off,memory=off,display-wait=off,clipping=on,shifting=on,jumping=on"
+        );
+    }
+}
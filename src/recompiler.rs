@@ -0,0 +1,307 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::cpu::Cpu;
+use crate::decode::Op;
+
+// Long enough for any real straight line of code, short enough that working out which blocks a
+// memory write touches stays cheap
+const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+
+// How many times code has to come round through the decode cache before it's worth compiling.
+// Every time a block gets written over this doubles for the code there, so code that keeps being
+// rewritten settles into the decode cache rather than being compiled over and over.
+const COMPILE_AFTER: u32 = 16;
+const MOST_REWRITES: u8 = 12;
+
+// Running a block costs a bit more than going through the decode cache for one instruction, so
+// blocks shorter than this are left to the decode cache
+const MIN_BLOCK_INSTRUCTIONS: usize = 3;
+
+// One instruction with its operands already baked in
+type Step = Box<dyn Fn(&mut Cpu)>;
+
+// A straight run of instructions turned into closures. Only the last instruction of a block can
+// read or change the program counter, so everything before it runs without touching it at all.
+pub struct Block {
+    start: u16,
+    steps: Vec<Step>,
+    // Whether the last step is one that sets the program counter itself, rather than the block
+    // just running into the size limit or the end of memory
+    terminated: bool,
+    // How many instructions from the start have been marked in the coverage. It only gets cleared
+    // along with the blocks, so each instruction only needs marking the first time it runs.
+    marked: Cell<usize>,
+}
+
+impl Block {
+    pub fn compile(cpu: &Cpu, start: u16) -> Block {
+        let mut steps: Vec<Step> = Vec::new();
+        let mut address = start;
+        let mut terminated = false;
+        // The last whole instruction in memory starts at 0xFFE, nothing else gets compiled off the
+        // end, but a block always gets at least one so there's something to run
+        while steps.is_empty() || (steps.len() < MAX_BLOCK_INSTRUCTIONS && address <= 0xFFE) {
+            let op = Op::decode(&cpu.instruction_at(address));
+            address += 2;
+            if op.ends_block() {
+                // The interpreter has already moved past an instruction by the time it runs it
+                let next = address & 0xFFF;
+                steps.push(Box::new(move |cpu| {
+                    cpu.program_counter = next;
+                    cpu.execute(op);
+                }));
+                terminated = true;
+                break;
+            }
+            steps.push(step(op));
+        }
+        Block {
+            start,
+            steps,
+            terminated,
+            marked: Cell::new(0),
+        }
+    }
+
+    // Runs at most `budget` instructions and returns how many that was, so a block that doesn't
+    // fit in what's left of a frame stops exactly where the interpreter would have. Only the last
+    // instruction can write to memory, so marking them all before any of them run is the same as
+    // marking each one just before it does.
+    pub fn run(&self, cpu: &mut Cpu, budget: u64) -> u64 {
+        let count = self.steps.len().min(budget as usize);
+        let marked = self.marked.get();
+        if count > marked {
+            cpu.mark_executed(self.start + 2 * marked as u16, count - marked);
+            self.marked.set(count);
+        }
+        for step in &self.steps[..count] {
+            step(cpu);
+        }
+        if count < self.steps.len() || !self.terminated {
+            cpu.program_counter = (self.start + 2 * count as u16) & 0xFFF;
+        }
+        count as u64
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+}
+
+// The register instructions make up most of a block, so they get closures of their own. The
+// quirks are still read when they run since a save state can bring different ones with it.
+// Everything else is rare enough to just hand the op to the interpreter.
+fn step(op: Op) -> Step {
+    match op {
+        Op::Nop => Box::new(|_| ()),
+        Op::Load { x, nn } => {
+            let x = x as usize;
+            Box::new(move |cpu| cpu.v_registers[x] = nn)
+        }
+        Op::Add { x, nn } => {
+            let x = x as usize;
+            Box::new(move |cpu| cpu.v_registers[x] = cpu.v_registers[x].wrapping_add(nn))
+        }
+        Op::Move { x, y } => {
+            let (x, y) = (x as usize, y as usize);
+            Box::new(move |cpu| cpu.v_registers[x] = cpu.v_registers[y])
+        }
+        Op::Or { x, y } => logic(x, y, |a, b| a | b),
+        Op::And { x, y } => logic(x, y, |a, b| a & b),
+        Op::Xor { x, y } => logic(x, y, |a, b| a ^ b),
+        Op::AddRegisters { x, y } => {
+            let (x, y) = (x as usize, y as usize);
+            Box::new(move |cpu| {
+                let (result, overflowed) = cpu.v_registers[x].overflowing_add(cpu.v_registers[y]);
+                cpu.v_registers[x] = result;
+                cpu.v_registers[0xF] = overflowed as u8;
+            })
+        }
+        Op::Subtract { x, y } => subtract(x, x, y),
+        Op::SubtractReversed { x, y } => subtract(x, y, x),
+        Op::ShiftRight { x, y } => {
+            let (x, y) = (x as usize, y as usize);
+            Box::new(move |cpu| {
+                let value = cpu.v_registers[if cpu.quirks.shifting { x } else { y }];
+                cpu.v_registers[x] = value >> 1;
+                cpu.v_registers[0xF] = value & 1;
+            })
+        }
+        Op::ShiftLeft { x, y } => {
+            let (x, y) = (x as usize, y as usize);
+            Box::new(move |cpu| {
+                let value = cpu.v_registers[if cpu.quirks.shifting { x } else { y }];
+                cpu.v_registers[x] = value << 1;
+                cpu.v_registers[0xF] = value >> 7;
+            })
+        }
+        _ => Box::new(move |cpu| cpu.execute(op)),
+    }
+}
+
+fn logic(x: u8, y: u8, combine: fn(u8, u8) -> u8) -> Step {
+    let (x, y) = (x as usize, y as usize);
+    Box::new(move |cpu| {
+        cpu.v_registers[x] = combine(cpu.v_registers[x], cpu.v_registers[y]);
+        if cpu.quirks.vf_reset {
+            cpu.v_registers[0xF] = 0;
+        }
+    })
+}
+
+// VX = `from` minus `minus`, with VF set when it didn't borrow
+fn subtract(x: u8, from: u8, minus: u8) -> Step {
+    let (x, from, minus) = (x as usize, from as usize, minus as usize);
+    Box::new(move |cpu| {
+        let (result, underflowed) = cpu.v_registers[from].overflowing_sub(cpu.v_registers[minus]);
+        cpu.v_registers[x] = result;
+        cpu.v_registers[0xF] = !underflowed as u8;
+    })
+}
+
+// Compiled blocks by the address they start at
+pub struct BlockCache {
+    blocks: Vec<Option<Rc<Block>>>,
+    // How many compiled blocks each byte is part of. Most writes are to data, and this lets them
+    // skip looking for blocks altogether.
+    covered: Vec<u8>,
+    // How many more times the code at each address has to run before it gets compiled
+    countdown: Vec<u32>,
+    // How many times a block starting at each address has been written over
+    rewrites: Vec<u8>,
+}
+
+impl BlockCache {
+    pub fn new(memory_size: usize) -> Self {
+        BlockCache {
+            blocks: vec![None; memory_size],
+            covered: vec![0; memory_size],
+            countdown: vec![COMPILE_AFTER; memory_size],
+            rewrites: vec![0; memory_size],
+        }
+    }
+
+    pub fn get(&self, address: u16) -> Option<Rc<Block>> {
+        self.blocks[address as usize].clone()
+    }
+
+    // Counts another run of the code at an address, and says whether there's a block compiled there
+    // already or it's come round often enough to compile one now
+    pub fn hit(&mut self, address: u16) -> bool {
+        let countdown = &mut self.countdown[address as usize];
+        *countdown = countdown.saturating_sub(1);
+        *countdown == 0
+    }
+
+    // Keeps a block that's just been compiled and says whether it did. One that's too short to be
+    // worth it is turned down, and the code there is left alone for as long as code that keeps
+    // getting rewritten would be.
+    pub fn insert(&mut self, address: u16, block: Rc<Block>) -> bool {
+        let start = address as usize;
+        if block.len() < MIN_BLOCK_INSTRUCTIONS {
+            self.countdown[start] = COMPILE_AFTER << MOST_REWRITES;
+            return false;
+        }
+        let end = (start + block.len() * 2).min(self.covered.len());
+        for count in &mut self.covered[start..end] {
+            *count += 1;
+        }
+        self.blocks[start] = Some(block);
+        true
+    }
+
+    // Drops every block with a byte in the ones that were written. A block can start up to a whole
+    // block's length before the write and still reach into it.
+    pub fn invalidate(&mut self, start: usize, len: usize) {
+        let to = (start + len).min(self.blocks.len());
+        if self.covered[start.min(to)..to]
+            .iter()
+            .all(|count| *count == 0)
+        {
+            return;
+        }
+        let from = start.saturating_sub(MAX_BLOCK_INSTRUCTIONS * 2);
+        for address in from..to {
+            let end = match &self.blocks[address] {
+                Some(block) => (address + block.len() * 2).min(self.covered.len()),
+                None => continue,
+            };
+            if end > start {
+                self.blocks[address] = None;
+                self.rewrites[address] = (self.rewrites[address] + 1).min(MOST_REWRITES);
+                self.countdown[address] = COMPILE_AFTER << self.rewrites[address];
+                for count in &mut self.covered[address..end] {
+                    *count -= 1;
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.fill(None);
+        self.covered.fill(0);
+        self.countdown.fill(COMPILE_AFTER);
+        self.rewrites.fill(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::WORKLOADS;
+
+    fn load(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_program_into_memory(&mut &program[..]).unwrap();
+        cpu
+    }
+
+    fn self_modifications(cpu: &Cpu) -> Vec<(u16, u16)> {
+        cpu.coverage()
+            .self_modifications()
+            .iter()
+            .map(|modification| (modification.address, modification.written_by))
+            .collect()
+    }
+
+    // Every workload has to end up exactly where the interpreter does after every block, including
+    // ones cut short by the budget, and mark the same coverage. It runs long enough for blocks to
+    // be compiled, written over and compiled again.
+    #[test]
+    fn blocks_match_the_interpreter() {
+        for workload in &WORKLOADS {
+            let mut recompiled = load(workload.program);
+            let mut interpreted = load(workload.program);
+            interpreted.load_state(&recompiled.save_state()).unwrap();
+
+            for round in 0..5000 {
+                let address = recompiled.program_counter;
+                let ran = recompiled.run_block(1 + round % 50);
+                for _ in 0..ran {
+                    let op = interpreted.fetch_decoded();
+                    interpreted.execute(op);
+                }
+                let (expected, actual) = (interpreted.save_state(), recompiled.save_state());
+                assert!(
+                    actual == expected,
+                    "{} went wrong in the block at {address:#05X}: {:?}",
+                    workload.name,
+                    actual.differences(&expected)
+                );
+            }
+            for address in 0..0x1000 {
+                assert_eq!(
+                    recompiled.coverage().flags(address),
+                    interpreted.coverage().flags(address),
+                    "{} marked {address:#05X} differently",
+                    workload.name
+                );
+            }
+            assert_eq!(
+                self_modifications(&recompiled),
+                self_modifications(&interpreted)
+            );
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use gif::{Encoder, Frame, Repeat};
+
+use crate::config::Config;
+use crate::palette::Palette;
+use crate::phosphor::Intensities;
+use crate::timing::FRAME_RATE;
+
+pub const DEFAULT_RECORDING_SCALE: u32 = 4;
+// GIF sizes are 16 bit, but long before that every frame gets too big to write out 60 times a second
+pub const MAX_RECORDING_SCALE: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordingFormat {
+    Gif,
+    // Uncompressed YUV video that ffmpeg, mpv and most editors can read
+    Y4m,
+    // A folder with one numbered PNG per frame
+    Png,
+}
+
+impl RecordingFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "gif" => Some(RecordingFormat::Gif),
+            "y4m" => Some(RecordingFormat::Y4m),
+            "png" => Some(RecordingFormat::Png),
+            _ => None,
+        }
+    }
+
+    // Anything without a .gif or .y4m extension is taken to be a folder for PNGs
+    pub fn from_path(path: &Path) -> Self {
+        path.extension()
+            .and_then(|extension| RecordingFormat::from_name(&extension.to_string_lossy()))
+            .unwrap_or(RecordingFormat::Png)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordingFormat::Gif => "gif",
+            RecordingFormat::Y4m => "y4m",
+            RecordingFormat::Png => "png",
+        }
+    }
+}
+
+// How recordings started with the hotkey get saved, from the [recording] section
+pub struct RecordingSettings {
+    pub format: RecordingFormat,
+    pub scale: u32,
+    pub directory: PathBuf,
+}
+
+impl RecordingSettings {
+    pub fn from_config(config: &Config) -> Self {
+        let format = match config.get("recording", "format") {
+            Some(name) => RecordingFormat::from_name(name).unwrap_or_else(|| {
+                eprintln!("[recording] Unknown format {name}, expected gif, y4m or png");
+                RecordingFormat::Gif
+            }),
+            None => RecordingFormat::Gif,
+        };
+        let scale = config
+            .get_parsed("recording", "scale")
+            .unwrap_or(DEFAULT_RECORDING_SCALE);
+        if !(1..=MAX_RECORDING_SCALE).contains(&scale) {
+            eprintln!("[recording] scale has to be from 1 to {MAX_RECORDING_SCALE}, got {scale}");
+        }
+        RecordingSettings {
+            format,
+            scale: scale.clamp(1, MAX_RECORDING_SCALE),
+            directory: config
+                .get("recording", "directory")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(".")),
+        }
+    }
+
+    // Named after the ROM and the time so recordings never overwrite each other, e.g.
+    // pong-1718000000.gif. PNG recordings are a folder with the same name and no extension.
+    pub fn new_path(&self, rom_path: &str) -> PathBuf {
+        let rom_name = Path::new(rom_path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "recording".to_string());
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let name = match self.format {
+            RecordingFormat::Png => format!("{rom_name}-{seconds}"),
+            format => format!("{rom_name}-{seconds}.{}", format.extension()),
+        };
+        self.directory.join(name)
+    }
+}
+
+enum Output {
+    Gif {
+        encoder: Encoder<BufWriter<File>>,
+        // GIF delays are in hundredths of a second, which 60Hz doesn't divide into, so a frame
+        // waits here until we know how long it stays on screen
+        pending: Option<PendingGifFrame>,
+    },
+    Y4m(BufWriter<File>),
+    Png,
+}
+
+struct PendingGifFrame {
+    pixels: Vec<u8>,
+    start: u64,
+}
+
+// Captures every emulated frame with the palette applied. Frames are added once per tick of the
+// 60Hz frame clock rather than once per redraw, so the recording plays back at the speed the game
+// actually ran at.
+pub struct Recorder {
+    path: PathBuf,
+    scale: u32,
+    frames: u64,
+    output: Output,
+}
+
+impl Recorder {
+    pub fn start(path: &Path, format: RecordingFormat, scale: u32) -> io::Result<Self> {
+        let scale = scale.clamp(1, MAX_RECORDING_SCALE);
+        let (width, height) = (64 * scale, 32 * scale);
+        let output = match format {
+            RecordingFormat::Gif => {
+                let file = BufWriter::new(File::create(path)?);
+                let mut encoder = Encoder::new(file, width as u16, height as u16, &[])
+                    .map_err(io::Error::other)?;
+                encoder
+                    .set_repeat(Repeat::Infinite)
+                    .map_err(io::Error::other)?;
+                Output::Gif {
+                    encoder,
+                    pending: None,
+                }
+            }
+            RecordingFormat::Y4m => {
+                let mut file = BufWriter::new(File::create(path)?);
+                // 4:4:4 so the pixel edges stay sharp instead of smearing into each other
+                writeln!(
+                    file,
+                    "YUV4MPEG2 W{width} H{height} F{FRAME_RATE}:1 Ip A1:1 C444"
+                )?;
+                Output::Y4m(file)
+            }
+            RecordingFormat::Png => {
+                fs::create_dir_all(path)?;
+                Output::Png
+            }
+        };
+
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            scale,
+            frames: 0,
+            output,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn add_frame(&mut self, pixels: &Intensities, palette: &Palette) -> io::Result<()> {
+        let rgb = render_rgb(pixels, palette, self.scale);
+        let (width, height) = (64 * self.scale, 32 * self.scale);
+
+        match &mut self.output {
+            Output::Gif { encoder, pending } => {
+                let start = centiseconds(self.frames);
+                match pending {
+                    // Nothing changed, the frame we're holding just stays up for longer
+                    Some(frame) if frame.pixels == rgb => {}
+                    // Anything shorter than 2/100ths gets slowed down by most viewers, so a frame
+                    // that would be on screen for less than that is swapped for the newer one.
+                    // The timeline itself never drifts, we only lose the odd in-between frame.
+                    Some(frame) if start - frame.start < 2 => frame.pixels = rgb,
+                    Some(frame) => {
+                        write_gif_frame(encoder, frame, start, width, height)?;
+                        *pending = Some(PendingGifFrame { pixels: rgb, start });
+                    }
+                    None => *pending = Some(PendingGifFrame { pixels: rgb, start }),
+                }
+            }
+            Output::Y4m(file) => {
+                file.write_all(b"FRAME\n")?;
+                file.write_all(&rgb_to_yuv444(&rgb))?;
+            }
+            Output::Png => {
+                let path = self.path.join(format!("frame_{:05}.png", self.frames));
+                write_png(&path, &rgb, width, height)?;
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    // Writes out anything still buffered. Returns how many frames were captured.
+    pub fn finish(self) -> io::Result<u64> {
+        let (width, height) = (64 * self.scale, 32 * self.scale);
+        match self.output {
+            Output::Gif {
+                mut encoder,
+                pending,
+            } => {
+                if let Some(frame) = pending {
+                    let end = centiseconds(self.frames).max(frame.start + 2);
+                    write_gif_frame(&mut encoder, &frame, end, width, height)?;
+                }
+                encoder.into_inner().map_err(io::Error::other)?.flush()?;
+            }
+            Output::Y4m(mut file) => file.flush()?,
+            Output::Png => {}
+        }
+        Ok(self.frames)
+    }
+}
+
+// When a frame starts, counting in the hundredths of a second GIFs use
+fn centiseconds(frame: u64) -> u64 {
+    frame * 100 / FRAME_RATE as u64
+}
+
+fn render_rgb(pixels: &Intensities, palette: &Palette, scale: u32) -> Vec<u8> {
+    let scale = scale as usize;
+    let mut rgb = Vec::with_capacity(64 * 32 * scale * scale * 3);
+    for row in pixels {
+        for _ in 0..scale {
+            for &intensity in row {
+                let colour = palette.shade(intensity);
+                for _ in 0..scale {
+                    rgb.extend_from_slice(&[colour.r, colour.g, colour.b]);
+                }
+            }
+        }
+    }
+    rgb
+}
+
+fn write_gif_frame(
+    encoder: &mut Encoder<BufWriter<File>>,
+    frame: &PendingGifFrame,
+    end: u64,
+    width: u32,
+    height: u32,
+) -> io::Result<()> {
+    let (width, height) = (width as u16, height as u16);
+    let mut gif_frame = match index_colours(&frame.pixels) {
+        Some((indices, palette)) => {
+            Frame::from_palette_pixels(width, height, indices, palette, None)
+        }
+        // Only happens with a lot of fading pixels at once, the quantizer copes with it
+        None => Frame::from_rgb_speed(width, height, &frame.pixels, 10),
+    };
+    gif_frame.delay = (end - frame.start) as u16;
+    encoder.write_frame(&gif_frame).map_err(io::Error::other)
+}
+
+// CHIP-8 frames rarely have more than a handful of colours, so we can usually give the GIF an exact
+// palette instead of quantizing
+fn index_colours(rgb: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut lookup: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut palette: Vec<u8> = Vec::new();
+    let mut indices = Vec::with_capacity(rgb.len() / 3);
+    for pixel in rgb.chunks_exact(3) {
+        let colour = [pixel[0], pixel[1], pixel[2]];
+        let index = match lookup.get(&colour) {
+            Some(index) => *index,
+            None => {
+                if lookup.len() == 256 {
+                    return None;
+                }
+                let index = lookup.len() as u8;
+                lookup.insert(colour, index);
+                palette.extend_from_slice(&colour);
+                index
+            }
+        };
+        indices.push(index);
+    }
+    Some((indices, palette))
+}
+
+// BT.601, the studio range most players expect from Y4M. Planes go one after another.
+fn rgb_to_yuv444(rgb: &[u8]) -> Vec<u8> {
+    let pixel_count = rgb.len() / 3;
+    let mut yuv = vec![0; pixel_count * 3];
+    for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+        let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+        let y = 16.0 + 0.257 * r + 0.504 * g + 0.098 * b;
+        let u = 128.0 - 0.148 * r - 0.291 * g + 0.439 * b;
+        let v = 128.0 + 0.439 * r - 0.368 * g - 0.071 * b;
+        yuv[i] = y.round() as u8;
+        yuv[pixel_count + i] = u.round() as u8;
+        yuv[pixel_count * 2 + i] = v.round() as u8;
+    }
+    yuv
+}
+
+fn write_png(path: &Path, rgb: &[u8], width: u32, height: u32) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(rgb).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_is_kept_to_what_a_recording_can_hold() {
+        let scale = |text: &str| RecordingSettings::from_config(&Config::parse(text)).scale;
+        assert_eq!(scale(""), DEFAULT_RECORDING_SCALE);
+        assert_eq!(scale("[recording]\nscale = 8"), 8);
+        assert_eq!(scale("[recording]\nscale = 0"), 1);
+        assert_eq!(scale("[recording]\nscale = 1024"), MAX_RECORDING_SCALE);
+        assert!(64 * MAX_RECORDING_SCALE <= u16::MAX as u32);
+    }
+
+    #[test]
+    fn yuv_comes_out_in_planes() {
+        let rgb = [0, 0, 0, 255, 255, 255, 255, 0, 0];
+        assert_eq!(
+            rgb_to_yuv444(&rgb),
+            [16, 235, 82, 128, 128, 90, 128, 128, 240]
+        );
+    }
+
+    #[test]
+    fn colours_are_indexed_in_the_order_theyre_seen() {
+        let rgb = [9, 9, 9, 1, 2, 3, 9, 9, 9, 9, 9, 9];
+        let (indices, palette) = index_colours(&rgb).unwrap();
+        assert_eq!(indices, [0, 1, 0, 0]);
+        assert_eq!(palette, [9, 9, 9, 1, 2, 3]);
+
+        // One more colour than a GIF palette has room for
+        let rgb: Vec<u8> = (0..257u32)
+            .flat_map(|colour| colour.to_be_bytes()[1..].to_vec())
+            .collect();
+        assert!(index_colours(&rgb[..256 * 3]).is_some());
+        assert!(index_colours(&rgb).is_none());
+    }
+
+    #[test]
+    fn y4m_has_a_header_and_a_frame_per_tick() {
+        let path = std::env::temp_dir().join(format!("chipp-test-{}.y4m", std::process::id()));
+        let palette = Palette::from_colours("test", [255, 255, 255], [0, 0, 0]);
+        let mut pixels = [[0.0; 64]; 32];
+        pixels[0][0] = 1.0;
+
+        let mut recorder = Recorder::start(&path, RecordingFormat::Y4m, 2).unwrap();
+        recorder.add_frame(&pixels, &palette).unwrap();
+        recorder.add_frame(&pixels, &palette).unwrap();
+        assert_eq!(recorder.finish().unwrap(), 2);
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let header = b"YUV4MPEG2 W128 H64 F60:1 Ip A1:1 C444\n";
+        let frame_size = b"FRAME\n".len() + 128 * 64 * 3;
+        assert!(bytes.starts_with(header));
+        assert_eq!(bytes.len(), header.len() + frame_size * 2);
+
+        // The lit pixel is 2x2 in the top left corner of the Y plane
+        let y_plane = &bytes[header.len() + b"FRAME\n".len()..][..128 * 64];
+        assert_eq!(&y_plane[..3], [235, 235, 16]);
+        assert_eq!(&y_plane[128..131], [235, 235, 16]);
+        assert_eq!(y_plane[256], 16);
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::config::config_dir;
+use crate::palette::parse_hex_colour;
+use crate::quirks::{Platform, Quirks};
+
+// The database follows the layout of the community chip-8-database
+// (https://github.com/chip-8/chip-8-database): sha1-hashes.json maps the hash of a ROM to its
+// index in programs.json, and platforms.json holds the quirks each platform had. We only ship the
+// platforms, so to know about actual games drop the three files from that repo into a
+// chip-8-database folder in the config directory and they'll be used instead.
+const DATABASE_DIR_NAME: &str = "chip-8-database";
+const BUNDLED_HASHES: &str = include_str!("../database/sha1-hashes.json");
+const BUNDLED_PROGRAMS: &str = include_str!("../database/programs.json");
+const BUNDLED_PLATFORMS: &str = include_str!("../database/platforms.json");
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Program {
+    title: String,
+    roms: HashMap<String, RomEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct RomEntry {
+    file: Option<String>,
+    platforms: Vec<String>,
+    // Some ROMs only work with a quirk or two flipped from what their platform normally does
+    quirky_platforms: HashMap<String, PlatformQuirks>,
+    tickrate: Option<u32>,
+    keys: HashMap<String, u8>,
+    colors: Option<Colours>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Colours {
+    // Background first, then foreground, then the extra XO-CHIP planes we don't draw
+    pixels: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PlatformEntry {
+    id: String,
+    name: String,
+    default_tickrate: Option<u32>,
+    quirks: PlatformQuirks,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PlatformQuirks {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl PlatformQuirks {
+    // The database's quirk names don't line up exactly with ours. CHIP-48 bumped I by X rather
+    // than X + 1 after FX55/FX65, which we can't do, so it counts as moving I.
+    fn apply_to(&self, quirks: &mut Quirks) {
+        if let Some(shift) = self.shift {
+            quirks.shifting = shift;
+        }
+        if let Some(leave_unchanged) = self.memory_leave_i_unchanged {
+            quirks.memory = !leave_unchanged;
+        }
+        if self.memory_increment_by_x == Some(true) {
+            quirks.memory = true;
+        }
+        if let Some(wrap) = self.wrap {
+            quirks.clipping = !wrap;
+        }
+        if let Some(jump) = self.jump {
+            quirks.jumping = jump;
+        }
+        if let Some(vblank) = self.vblank {
+            quirks.display_wait = vblank;
+        }
+        if let Some(logic) = self.logic {
+            quirks.vf_reset = logic;
+        }
+    }
+}
+
+// Everything the database knows about one ROM, already turned into our own types
+#[derive(Debug, Clone)]
+pub struct RomInfo {
+    pub title: String,
+    pub file: Option<String>,
+    pub platform: String,
+    pub quirks: Quirks,
+    pub tickrate: Option<u32>,
+    // e.g. ("up", 5), the names are the database's
+    pub keys: Vec<(String, u8)>,
+    pub colours: Option<([u8; 3], [u8; 3])>,
+}
+
+#[derive(Debug, Default)]
+pub struct RomDatabase {
+    hashes: HashMap<String, usize>,
+    programs: Vec<Program>,
+    platforms: Vec<PlatformEntry>,
+}
+
+impl RomDatabase {
+    // Uses the copy in the config directory if there is one, otherwise what's built in. A broken
+    // database is reported and ignored, it shouldn't stop anything from running.
+    pub fn load() -> Self {
+        let user_dir = config_dir()
+            .map(|dir| dir.join(DATABASE_DIR_NAME))
+            .filter(|dir| dir.is_dir());
+        let result = match &user_dir {
+            Some(dir) => Self::load_dir(dir),
+            None => Self::parse(BUNDLED_HASHES, BUNDLED_PROGRAMS, BUNDLED_PLATFORMS),
+        };
+        result.unwrap_or_else(|err| {
+            eprintln!("Couldn't load the ROM database: {err}");
+            RomDatabase::default()
+        })
+    }
+
+    fn load_dir(dir: &Path) -> Result<Self, String> {
+        let read = |name: &str| {
+            fs::read_to_string(dir.join(name))
+                .map_err(|err| format!("{}: {err}", dir.join(name).display()))
+        };
+        // Only the hashes and programs are needed from the user, the platforms rarely change
+        let platforms = read("platforms.json").unwrap_or_else(|_| BUNDLED_PLATFORMS.to_string());
+        Self::parse(
+            &read("sha1-hashes.json")?,
+            &read("programs.json")?,
+            &platforms,
+        )
+    }
+
+    fn parse(hashes: &str, programs: &str, platforms: &str) -> Result<Self, String> {
+        Ok(RomDatabase {
+            hashes: serde_json::from_str(hashes)
+                .map_err(|err| format!("sha1-hashes.json: {err}"))?,
+            programs: serde_json::from_str(programs)
+                .map_err(|err| format!("programs.json: {err}"))?,
+            platforms: serde_json::from_str(platforms)
+                .map_err(|err| format!("platforms.json: {err}"))?,
+        })
+    }
+
+    pub fn lookup(&self, sha1: &str) -> Option<RomInfo> {
+        let sha1 = sha1.to_ascii_lowercase();
+        let program = self.programs.get(*self.hashes.get(&sha1)?)?;
+        let rom = program.roms.get(&sha1)?;
+
+        // A ROM can list several platforms it runs on, the first is the one it was made for
+        let platform_id = rom
+            .platforms
+            .first()
+            .cloned()
+            .unwrap_or_else(|| "originalChip8".to_string());
+        let platform = self.platforms.iter().find(|p| p.id == platform_id);
+
+        let mut quirks = Platform::Chip8.default_quirks();
+        if let Some(platform) = platform {
+            platform.quirks.apply_to(&mut quirks);
+        }
+        if let Some(overrides) = rom.quirky_platforms.get(&platform_id) {
+            overrides.apply_to(&mut quirks);
+        }
+
+        let mut keys: Vec<(String, u8)> = rom
+            .keys
+            .iter()
+            .map(|(name, key)| (name.clone(), *key))
+            .collect();
+        keys.sort();
+
+        let colours = rom.colors.as_ref().and_then(|colours| {
+            let background = parse_hex_colour(colours.pixels.first()?)?;
+            let foreground = parse_hex_colour(colours.pixels.get(1)?)?;
+            Some((foreground, background))
+        });
+
+        Some(RomInfo {
+            title: program.title.clone(),
+            file: rom.file.clone(),
+            platform: platform.map_or(platform_id, |p| p.name.clone()),
+            quirks,
+            tickrate: rom
+                .tickrate
+                .or_else(|| platform.and_then(|p| p.default_tickrate)),
+            keys,
+            colours,
+        })
+    }
+}
+
+// Lower case hex, the same as the database keys
+pub fn sha1_hex(data: &[u8]) -> String {
+    sha1_smol::Sha1::from(data).digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASHES: &str = r#"{
+        "1111111111111111111111111111111111111111": 0,
+        "2222222222222222222222222222222222222222": 0,
+        "3333333333333333333333333333333333333333": 1,
+        "4444444444444444444444444444444444444444": 1
+    }"#;
+
+    const PROGRAMS: &str = r##"[
+        {
+            "title": "Plain",
+            "roms": {
+                "1111111111111111111111111111111111111111": {
+                    "file": "plain.ch8",
+                    "platforms": ["originalChip8"]
+                },
+                "2222222222222222222222222222222222222222": {
+                    "platforms": ["superchip", "xochip"],
+                    "quirkyPlatforms": { "superchip": { "wrap": true, "vblank": true } },
+                    "tickrate": 200,
+                    "keys": { "up": 5, "a": 6, "down": 8 },
+                    "colors": { "pixels": ["#112233", "#ffeedd", "#000000"] }
+                }
+            }
+        },
+        {
+            "title": "Odd",
+            "roms": {
+                "3333333333333333333333333333333333333333": { "platforms": ["xochip"] },
+                "4444444444444444444444444444444444444444": { "platforms": ["somethingNew"] }
+            }
+        }
+    ]"##;
+
+    fn database() -> RomDatabase {
+        RomDatabase::parse(HASHES, PROGRAMS, BUNDLED_PLATFORMS).unwrap()
+    }
+
+    #[test]
+    fn unknown_roms_arent_found() {
+        assert!(database().lookup(&"5".repeat(40)).is_none());
+        // Listed in the hashes, but its program doesn't have it
+        let hashes = r#"{ "5555555555555555555555555555555555555555": 0 }"#;
+        let database = RomDatabase::parse(hashes, PROGRAMS, BUNDLED_PLATFORMS).unwrap();
+        assert!(database.lookup(&"5".repeat(40)).is_none());
+    }
+
+    #[test]
+    fn lookup_uses_the_platforms_quirks_and_speed() {
+        let info = database().lookup(&"1".repeat(40)).unwrap();
+        assert_eq!(info.title, "Plain");
+        assert_eq!(info.file.as_deref(), Some("plain.ch8"));
+        assert_eq!(info.platform, "Cosmac VIP CHIP-8");
+        assert_eq!(info.quirks, Platform::Chip8.default_quirks());
+        assert_eq!(info.tickrate, Some(15));
+        assert!(info.keys.is_empty());
+        assert_eq!(info.colours, None);
+
+        let info = database().lookup(&"3".repeat(40)).unwrap();
+        assert_eq!(info.quirks, Platform::XoChip.default_quirks());
+        assert_eq!(info.tickrate, Some(100));
+    }
+
+    #[test]
+    fn lookup_takes_the_roms_own_settings_over_the_platforms() {
+        // Upper case hashes still match
+        let info = database().lookup(&"2".repeat(40).to_uppercase()).unwrap();
+        assert_eq!(info.platform, "SUPER-CHIP 1.1");
+        let mut quirks = Platform::SuperChip.default_quirks();
+        quirks.clipping = false;
+        quirks.display_wait = true;
+        assert_eq!(info.quirks, quirks);
+        assert_eq!(info.tickrate, Some(200));
+        assert_eq!(
+            info.keys,
+            [
+                ("a".to_string(), 6),
+                ("down".to_string(), 8),
+                ("up".to_string(), 5)
+            ]
+        );
+        assert_eq!(info.colours, Some(([0xFF, 0xEE, 0xDD], [0x11, 0x22, 0x33])));
+    }
+
+    #[test]
+    fn an_unknown_platform_is_named_but_runs_as_chip8() {
+        let info = database().lookup(&"4".repeat(40)).unwrap();
+        assert_eq!(info.platform, "somethingNew");
+        assert_eq!(info.quirks, Platform::Chip8.default_quirks());
+        assert_eq!(info.tickrate, None);
+    }
+
+    #[test]
+    fn apply_to_only_changes_what_it_mentions() {
+        let mut quirks = Platform::Chip8.default_quirks();
+        PlatformQuirks::default().apply_to(&mut quirks);
+        assert_eq!(quirks, Platform::Chip8.default_quirks());
+
+        let flipped = PlatformQuirks {
+            shift: Some(true),
+            wrap: Some(true),
+            jump: Some(true),
+            vblank: Some(false),
+            logic: Some(false),
+            ..PlatformQuirks::default()
+        };
+        flipped.apply_to(&mut quirks);
+        assert_eq!(
+            quirks,
+            Quirks {
+                vf_reset: false,
+                memory: true,
+                display_wait: false,
+                clipping: false,
+                shifting: true,
+                jumping: true,
+            }
+        );
+    }
+
+    #[test]
+    fn apply_to_maps_both_memory_quirks() {
+        let mut quirks = Platform::Chip8.default_quirks();
+        let leave_unchanged = PlatformQuirks {
+            memory_leave_i_unchanged: Some(true),
+            ..PlatformQuirks::default()
+        };
+        leave_unchanged.apply_to(&mut quirks);
+        assert!(!quirks.memory);
+
+        // CHIP-48's off by one increment is as close as we get to moving I
+        let increment_by_x = PlatformQuirks {
+            memory_leave_i_unchanged: Some(false),
+            memory_increment_by_x: Some(true),
+            ..PlatformQuirks::default()
+        };
+        increment_by_x.apply_to(&mut quirks);
+        assert!(quirks.memory);
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::config_dir;
+use crate::prng::Prng;
+use crate::quirks::Quirks;
+
+// Everything needed to put the cpu back exactly how it was, including the random number generator
+// so CXNN carries on giving the same numbers after loading. Stored as JSON so it's easy to poke at.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SaveState {
+    // A state only makes sense for the ROM it came from
+    pub rom_sha1: String,
+    pub memory: Vec<u8>,
+    pub v_registers: [u8; 16],
+    pub index_register: u16,
+    pub program_counter: u16,
+    pub stack: Vec<u16>,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    // One u64 per row of the screen, the leftmost pixel is the top bit
+    pub pixel_rows: Vec<u64>,
+    pub awaited_key: Option<u8>,
+    pub waiting_for_vblank: bool,
+    pub quirks: Quirks,
+    pub rng: Prng,
+}
+
+impl SaveState {
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let json = serde_json::to_string(self).map_err(io::Error::other)?;
+        fs::write(path, json)
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let json = fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    // A line for each part of the cpu that isn't the same in both, for working out where two runs
+    // went their separate ways
+    pub fn differences(&self, other: &SaveState) -> Vec<String> {
+        let mut differences = Vec::new();
+        for register in 0..16 {
+            let (ours, theirs) = (self.v_registers[register], other.v_registers[register]);
+            if ours != theirs {
+                differences.push(format!("V{register:X}: {ours:#04X} vs {theirs:#04X}"));
+            }
+        }
+        if self.index_register != other.index_register {
+            differences.push(format!(
+                "I: {:#05X} vs {:#05X}",
+                self.index_register, other.index_register
+            ));
+        }
+        if self.program_counter != other.program_counter {
+            differences.push(format!(
+                "PC: {:#05X} vs {:#05X}",
+                self.program_counter, other.program_counter
+            ));
+        }
+        if self.stack != other.stack {
+            differences.push(format!("Stack: {:X?} vs {:X?}", self.stack, other.stack));
+        }
+        if (self.delay_timer, self.sound_timer) != (other.delay_timer, other.sound_timer) {
+            differences.push(format!(
+                "Timers: {} {} vs {} {}",
+                self.delay_timer, self.sound_timer, other.delay_timer, other.sound_timer
+            ));
+        }
+        let changed_memory = (0..self.memory.len().min(other.memory.len()))
+            .filter(|address| self.memory[*address] != other.memory[*address]);
+        for address in changed_memory {
+            differences.push(format!(
+                "Memory at {address:#05X}: {:#04X} vs {:#04X}",
+                self.memory[address], other.memory[address]
+            ));
+        }
+        if self.pixel_rows != other.pixel_rows {
+            differences.push("The screen".to_string());
+        }
+        if self.awaited_key != other.awaited_key
+            || self.waiting_for_vblank != other.waiting_for_vblank
+        {
+            differences.push("What it's waiting on".to_string());
+        }
+        if self.rng != other.rng {
+            differences.push("The random number generator".to_string());
+        }
+        differences
+    }
+}
+
+// States live in a states folder in the config directory, one per ROM, e.g. states/pong.ch8.state
+pub fn state_path(rom_path: &str) -> Option<PathBuf> {
+    let rom_name = Path::new(rom_path).file_name()?;
+    let dir = config_dir()?.join("states");
+    Some(dir.join(format!("{}.state", rom_name.to_string_lossy())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    // Random numbers, a call and some memory writes, so there's a bit of everything in the state
+    const ROM: [u8; 14] = [
+        0xC0, 0xFF, // 200: V0 = random
+        0x22, 0x0A, // 202: call 20A
+        0xA3, 0x00, // 204: I = 300
+        0xF1, 0x55, // 206: save V0 to V1 at I
+        0x12, 0x00, // 208: jump 200
+        0x71, 0x01, // 20A: V1 += 1
+        0x00, 0xEE, // 20C: return
+    ];
+
+    fn run(cpu: &mut Cpu, instructions: usize) {
+        for _ in 0..instructions {
+            let op = cpu.fetch_decoded();
+            cpu.execute(op);
+        }
+    }
+
+    #[test]
+    fn a_loaded_state_carries_on_the_same() {
+        let mut cpu = Cpu::new();
+        cpu.load_program_into_memory(&mut &ROM[..]).unwrap();
+        cpu.seed_rng(7);
+        // Stops inside the subroutine, so there's something on the stack
+        run(&mut cpu, 100);
+        let path = std::env::temp_dir().join(format!("chipp-test-{}.state", std::process::id()));
+        cpu.save_state().write(&path).unwrap();
+
+        run(&mut cpu, 500);
+        let carried_on = cpu.save_state();
+
+        let state = SaveState::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(state.stack, [0x204]);
+        let mut loaded = Cpu::new();
+        loaded.load_program_into_memory(&mut &ROM[..]).unwrap();
+        loaded.load_state(&state).unwrap();
+        assert!(
+            loaded.save_state() == state,
+            "{:?}",
+            loaded.save_state().differences(&state)
+        );
+        run(&mut loaded, 500);
+        let differences = loaded.save_state().differences(&carried_on);
+        assert!(differences.is_empty(), "{differences:?}");
+    }
+
+    #[test]
+    fn states_only_load_into_their_own_rom() {
+        let mut cpu = Cpu::new();
+        cpu.load_program_into_memory(&mut &ROM[..]).unwrap();
+        let state = cpu.save_state();
+        let mut other = Cpu::new();
+        other.load_program_into_memory(&mut &ROM[..2]).unwrap();
+        assert_eq!(
+            other.load_state(&state),
+            Err("That save state is from a different ROM".to_string())
+        );
+    }
+
+    #[test]
+    fn differences_name_what_changed() {
+        let cpu = Cpu::new();
+        let state = cpu.save_state();
+        let mut other = state.clone();
+        other.v_registers[0xA] = 3;
+        other.memory[0x300] = 1;
+        other.rng = Prng::from_seed(1);
+        assert_eq!(
+            state.differences(&other),
+            [
+                "VA: 0x00 vs 0x03",
+                "Memory at 0x300: 0x00 vs 0x01",
+                "The random number generator"
+            ]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+// How far past a label an address still gets named after it, so the font and anything else
+// before the first label stays a plain address
+const MAX_LABEL_OFFSET: u16 = 0x100;
+
+// The symbol file as Octo's compiler keeps it: every label's address, and which line of the source
+// each address was assembled from. The source is relative to the symbol file.
+#[derive(Serialize, Deserialize)]
+struct SymbolFile {
+    #[serde(default)]
+    source: Option<String>,
+    labels: BTreeMap<String, u16>,
+    #[serde(default)]
+    lines: BTreeMap<u16, usize>,
+}
+
+// Labels and source lines for a ROM, for showing `main+4` and `game.8o:12` where there would
+// otherwise be an address
+#[derive(Debug, Clone)]
+pub struct Symbols {
+    // By address, so the label an address comes after can be found
+    labels: Vec<(u16, String)>,
+    lines: BTreeMap<u16, usize>,
+    // As the symbol file has it, which might be a path
+    source_path: Option<String>,
+    // The source itself if it could be found, one entry per line
+    source: Vec<String>,
+}
+
+impl Symbols {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let json = fs::read_to_string(path)?;
+        let file: SymbolFile = serde_json::from_str(&json)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        // Missing source just means line numbers without the text
+        let source = file
+            .source
+            .as_ref()
+            .and_then(|name| fs::read_to_string(path.with_file_name(name)).ok())
+            .unwrap_or_default();
+        Ok(Symbols::new(file.labels, file.lines, file.source, &source))
+    }
+
+    pub fn new(
+        labels: BTreeMap<String, u16>,
+        lines: BTreeMap<u16, usize>,
+        source_path: Option<String>,
+        source: &str,
+    ) -> Self {
+        let mut labels: Vec<(u16, String)> = labels
+            .into_iter()
+            .map(|(name, address)| (address, name))
+            .collect();
+        labels.sort();
+        Symbols {
+            labels,
+            lines,
+            source_path,
+            source: source.lines().map(str::to_string).collect(),
+        }
+    }
+
+    // Saves them the way load reads them, with the source given relative to the symbol file
+    pub fn save(&self, path: &Path, source_path: &str) -> io::Result<()> {
+        let file = SymbolFile {
+            source: Some(source_path.to_string()),
+            labels: self
+                .labels
+                .iter()
+                .map(|(address, name)| (name.clone(), *address))
+                .collect(),
+            lines: self.lines.clone(),
+        };
+        let json = serde_json::to_string_pretty(&file).map_err(io::Error::other)?;
+        fs::write(path, json)
+    }
+
+    // Where a ROM's symbols get looked for when they aren't given, `game.ch8` has `game.sym`
+    pub fn path_for(rom: &str) -> PathBuf {
+        Path::new(rom).with_extension("sym")
+    }
+
+    // Octo writes labels as `: main`, so the colon is fine to leave on
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        let name = name.trim_start_matches(':').trim();
+        self.labels
+            .iter()
+            .find(|(_, label)| label == name)
+            .map(|(address, _)| *address)
+    }
+
+    pub fn label_at(&self, address: u16) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(at, _)| *at == address)
+            .map(|(_, name)| name.as_str())
+    }
+
+    // The nearest label at or before the address, as `main` or `main+4`
+    pub fn name(&self, address: u16) -> String {
+        let before = self.labels.partition_point(|(at, _)| *at <= address);
+        match before.checked_sub(1).map(|index| &self.labels[index]) {
+            Some((at, name)) if address == *at => name.clone(),
+            Some((at, name)) if address - at < MAX_LABEL_OFFSET => {
+                format!("{name}+{}", address - at)
+            }
+            _ => format!("{address:#05X}"),
+        }
+    }
+
+    // `game.8o:12  v0 += 1`, or just the line number if the source isn't there
+    pub fn source_line(&self, address: u16) -> Option<String> {
+        let line = *self.lines.get(&address)?;
+        let file = self
+            .source_path
+            .as_deref()
+            .and_then(|path| Path::new(path).file_name())
+            .map_or("line".into(), |name| name.to_string_lossy());
+        match self.source.get(line.wrapping_sub(1)) {
+            Some(text) => Some(format!("{file}:{line}  {}", text.trim())),
+            None => Some(format!("{file}:{line}")),
+        }
+    }
+}
+
+// A breakpoint is a label if there's one by that name, otherwise an address in hex. Once there are
+// labels, a plain word is taken to be one, so `add` doesn't quietly turn into 0xADD and addresses
+// need their 0x.
+pub fn resolve_address(text: &str, symbols: Option<&Symbols>) -> Result<u16, String> {
+    if let Some(address) = symbols.and_then(|symbols| symbols.address_of(text)) {
+        return Ok(address);
+    }
+    let digits = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(digits) => digits,
+        None if symbols.is_some() => {
+            return Err(format!(
+                "Unknown label {text} to break at, put 0x in front if it's an address"
+            ));
+        }
+        None => text,
+    };
+    match u16::from_str_radix(digits, 16) {
+        Ok(address) if address < 0x1000 => Ok(address),
+        Ok(_) => Err(format!(
+            "Can't break at {text}, it's past the end of memory"
+        )),
+        Err(_) => Err(format!(
+            "There's no label or address called {text} to break at"
+        )),
+    }
+}
@@ -0,0 +1,435 @@
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::{
+    self, Attribute, Print, ResetColor, SetBackgroundColor, SetForegroundColor,
+};
+use crossterm::terminal::{
+    self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen,
+    supports_keyboard_enhancement,
+};
+use crossterm::{execute, queue};
+use raylib::prelude::{Color, KeyboardKey};
+
+use crate::backend::{DisplaySink, FrameView, HostEvent, InputSource, NullBackend};
+use crate::cli::RunOptions;
+use crate::config::Config;
+use crate::disasm::mnemonic;
+use crate::emulator::Emulator;
+use crate::keymap::{Hotkeys, KEYPAD_LAYOUT, Keymap, key_from_name};
+use crate::timing::FRAME_DURATION;
+
+// Most terminals only tell us when a key goes down, so without a release to go on a key counts as
+// held for this long after the last press or auto-repeat
+const DEFAULT_KEY_HOLD_MS: u64 = 200;
+
+// How the 64x32 screen is squeezed into text
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Characters {
+    // Two pixels to a character with ▀, in full colour. 64x16 characters.
+    HalfBlock,
+    // Eight pixels to a character with the braille dots, in one colour. 32x8 characters, small
+    // enough for a split pane.
+    Braille,
+}
+
+impl Characters {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "half-block" | "halfblock" => Some(Characters::HalfBlock),
+            "braille" => Some(Characters::Braille),
+            _ => None,
+        }
+    }
+}
+
+// Plays in the terminal the program was started from, for machines with no display. There's no
+// sound besides the terminal bell, so the emulator gets a null audio sink.
+pub fn run_terminal(options: &RunOptions, config: &Config) -> io::Result<()> {
+    let mut emulator = Emulator::new(options, config)?;
+    let database_keys = emulator.rom_info().map_or(&[][..], |info| &info.keys[..]);
+    let keymap = Keymap::load(config, &options.rom, database_keys);
+
+    let mut frontend = TerminalFrontend::new(keymap, config)?;
+    let mut audio = NullBackend::default();
+    let result = emulator.run(&mut frontend, &mut audio);
+    // Put the terminal back before anything else gets printed
+    frontend.restore()?;
+    result?;
+    if let Some(err) = frontend.error.take() {
+        return Err(err);
+    }
+    emulator.finish()
+}
+
+struct TerminalFrontend {
+    out: io::Stdout,
+    keymap: Keymap,
+    hotkeys: Hotkeys,
+    characters: Characters,
+    show_debug: bool,
+    bell: bool,
+    key_hold: Duration,
+    // True when the terminal sends key releases, so keys can be held for as long as they really are
+    releases: bool,
+    // When each CHIP-8 key stops counting as held, None if it isn't
+    held_until: [Option<Instant>; 16],
+    quit: bool,
+    was_beeping: bool,
+    last_present: Instant,
+    // The first thing that went wrong talking to the terminal, which also stops the run
+    error: Option<io::Error>,
+    restored: bool,
+}
+
+impl TerminalFrontend {
+    fn new(keymap: Keymap, config: &Config) -> io::Result<Self> {
+        let characters = match config.get("terminal", "characters") {
+            Some(name) => Characters::from_name(name).unwrap_or_else(|| {
+                eprintln!("[terminal] Unknown characters {name}, expected half-block or braille");
+                Characters::HalfBlock
+            }),
+            None => Characters::HalfBlock,
+        };
+        let key_hold_ms = config
+            .get_parsed("terminal", "key_hold_ms")
+            .unwrap_or(DEFAULT_KEY_HOLD_MS);
+
+        let mut out = io::stdout();
+        terminal::enable_raw_mode()?;
+        execute!(out, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+        // Only some terminals (kitty, foot, WezTerm, ...) can report releases
+        let releases = supports_keyboard_enhancement().unwrap_or(false);
+        if releases {
+            execute!(
+                out,
+                PushKeyboardEnhancementFlags(
+                    KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                        | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                )
+            )?;
+        }
+
+        Ok(TerminalFrontend {
+            out,
+            keymap,
+            hotkeys: Hotkeys::from_config(config),
+            characters,
+            show_debug: !config.get_parsed("display", "game_only").unwrap_or(false),
+            bell: config.get_parsed("terminal", "bell").unwrap_or(true),
+            key_hold: Duration::from_millis(key_hold_ms),
+            releases,
+            held_until: [None; 16],
+            quit: false,
+            was_beeping: false,
+            last_present: Instant::now(),
+            error: None,
+            restored: false,
+        })
+    }
+
+    fn restore(&mut self) -> io::Result<()> {
+        if self.restored {
+            return Ok(());
+        }
+        self.restored = true;
+        if self.releases {
+            execute!(self.out, PopKeyboardEnhancementFlags)?;
+        }
+        execute!(self.out, ResetColor, Show, LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, events: &mut Vec<HostEvent>) {
+        let ctrl_c =
+            key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+        if key.code == KeyCode::Esc || ctrl_c {
+            self.quit = true;
+            return;
+        }
+        let Some(keyboard_key) = keyboard_key(key.code) else {
+            return;
+        };
+
+        if key.kind == KeyEventKind::Press {
+            for (hotkey, event) in &self.hotkeys.events {
+                if *hotkey == keyboard_key {
+                    events.push(*event);
+                }
+            }
+            if self.hotkeys.game_only == keyboard_key {
+                self.show_debug = !self.show_debug;
+                let _ = queue!(self.out, Clear(ClearType::All));
+            }
+        }
+
+        let held_until = match key.kind {
+            KeyEventKind::Release => None,
+            _ => Some(Instant::now() + self.key_hold),
+        };
+        for chip8_key in KEYPAD_LAYOUT {
+            if self.keymap.bindings(chip8_key).contains(&keyboard_key) {
+                self.held_until[chip8_key as usize] = held_until;
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &FrameView<'_>) -> io::Result<()> {
+        let mut lines = match self.characters {
+            Characters::HalfBlock => half_block_lines(frame),
+            Characters::Braille => braille_lines(frame),
+        };
+        if self.show_debug {
+            let sidebar = sidebar_lines(frame);
+            let width = match self.characters {
+                Characters::HalfBlock => 64,
+                Characters::Braille => 32,
+            };
+            if lines.len() < sidebar.len() {
+                lines.resize(sidebar.len(), " ".repeat(width));
+            }
+            let ui = (
+                colour(frame.palette.ui_text),
+                colour(frame.palette.ui_background),
+            );
+            for (line, text) in lines.iter_mut().zip(sidebar) {
+                line.push_str(&format!(
+                    "{}{}  {text}",
+                    SetForegroundColor(ui.0),
+                    SetBackgroundColor(ui.1)
+                ));
+            }
+        }
+
+        // Build the whole screen first so it goes out in one write and doesn't tear
+        let mut screen = Vec::new();
+        for (row, line) in lines.iter().enumerate() {
+            queue!(
+                screen,
+                MoveTo(0, row as u16),
+                Print(line),
+                ResetColor,
+                Clear(ClearType::UntilNewLine)
+            )?;
+        }
+        let beeping = frame.cpu.sound_timer > 0;
+        if self.bell && beeping && !self.was_beeping {
+            screen.push(0x07);
+        }
+        self.was_beeping = beeping;
+
+        self.out.write_all(&screen)?;
+        self.out.flush()
+    }
+}
+
+impl Drop for TerminalFrontend {
+    // Also covers panics, a terminal left in raw mode is no fun
+    fn drop(&mut self) {
+        let _ = self.restore();
+    }
+}
+
+impl InputSource for TerminalFrontend {
+    fn poll(&mut self, keypad: &mut [bool; 16], events: &mut Vec<HostEvent>) {
+        loop {
+            match next_event() {
+                Ok(Some(Event::Key(key))) => self.handle_key(key, events),
+                Ok(Some(Event::Resize(..))) => {
+                    let _ = queue!(self.out, Clear(ClearType::All));
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(err) => {
+                    self.error.get_or_insert(err);
+                    break;
+                }
+            }
+        }
+
+        // With releases to go on, a key is held until it's let go
+        let now = Instant::now();
+        for (down, held_until) in keypad.iter_mut().zip(&self.held_until) {
+            *down = held_until.is_some_and(|until| self.releases || until > now);
+        }
+    }
+
+    fn should_quit(&self) -> bool {
+        self.quit || self.error.is_some()
+    }
+}
+
+// Whatever the terminal has sent, without waiting for it
+fn next_event() -> io::Result<Option<Event>> {
+    if event::poll(Duration::ZERO)? {
+        event::read().map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+impl DisplaySink for TerminalFrontend {
+    fn present(&mut self, frame: &FrameView<'_>) {
+        if let Err(err) = self.draw(frame) {
+            self.error.get_or_insert(err);
+        }
+
+        // Nothing else is keeping time, so wait out the rest of the frame here
+        let elapsed = self.last_present.elapsed();
+        if elapsed < FRAME_DURATION {
+            thread::sleep(FRAME_DURATION - elapsed);
+        }
+        self.last_present = Instant::now();
+    }
+}
+
+fn half_block_lines(frame: &FrameView<'_>) -> Vec<String> {
+    frame
+        .pixels
+        .chunks(2)
+        .map(|rows| {
+            let mut line = String::new();
+            let mut current = None;
+            for (top, bottom) in rows[0].iter().zip(&rows[1]) {
+                // The top pixel is the character, the bottom one is the background behind it
+                let colours = (
+                    colour(frame.palette.shade(*top)),
+                    colour(frame.palette.shade(*bottom)),
+                );
+                if current != Some(colours) {
+                    line.push_str(&format!(
+                        "{}{}",
+                        SetForegroundColor(colours.0),
+                        SetBackgroundColor(colours.1)
+                    ));
+                    current = Some(colours);
+                }
+                line.push('▀');
+            }
+            line
+        })
+        .collect()
+}
+
+fn braille_lines(frame: &FrameView<'_>) -> Vec<String> {
+    // Which bit of a braille character each dot in its 2x4 block is
+    const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+    let prefix = format!(
+        "{}{}",
+        SetForegroundColor(colour(frame.palette.foreground())),
+        SetBackgroundColor(colour(frame.palette.background()))
+    );
+    frame
+        .pixels
+        .chunks(4)
+        .map(|rows| {
+            let mut line = prefix.clone();
+            for column in (0..64).step_by(2) {
+                let mut bits = 0;
+                for (row, dots) in rows.iter().zip(DOTS) {
+                    for (x, dot) in dots.iter().enumerate() {
+                        // Braille dots can't fade, so they're either on or off
+                        if row[column + x] >= 0.5 {
+                            bits |= dot;
+                        }
+                    }
+                }
+                line.push(char::from_u32(0x2800 + bits).unwrap_or(' '));
+            }
+            line
+        })
+        .collect()
+}
+
+// The same things the window's debug panels show, squeezed into text next to the game
+fn sidebar_lines(frame: &FrameView<'_>) -> Vec<String> {
+    let cpu = frame.cpu;
+    let mut lines = vec![format!("PC {:04X}", cpu.program_counter)];
+    if let Some(symbols) = &cpu.symbols {
+        lines.push(symbols.name(cpu.program_counter));
+        lines.extend(symbols.source_line(cpu.program_counter));
+        let calls: Vec<String> = cpu
+            .stack()
+            .iter()
+            .rev()
+            .map(|return_address| symbols.name(return_address.wrapping_sub(2) & 0xFFF))
+            .collect();
+        if !calls.is_empty() {
+            lines.push(format!("in {}", calls.join(" < ")));
+        }
+    }
+    lines.push(String::new());
+    for (marker, opcode) in ["  ", "> ", "  "].iter().zip(frame.opcodes) {
+        lines.push(format!("{marker}{opcode:04X}  {}", mnemonic(opcode)));
+    }
+    lines.push(String::new());
+    for (row, values) in cpu.v_registers.chunks(4).enumerate() {
+        let cells: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("V{:X} {value:02X}", row * 4 + i))
+            .collect();
+        lines.push(cells.join("  "));
+    }
+    lines.push(String::new());
+    // Held keys are shown reversed, like the lit up boxes in the window
+    for keys in KEYPAD_LAYOUT.chunks(4) {
+        let cells: Vec<String> = keys
+            .iter()
+            .map(|&key| {
+                if cpu.keypad[key as usize] {
+                    format!("{}{key:X}{}", Attribute::Reverse, Attribute::NoReverse)
+                } else {
+                    format!("{key:X}")
+                }
+            })
+            .collect();
+        lines.push(cells.join(" "));
+    }
+    lines.push(String::new());
+    lines.push(frame.status());
+    lines
+}
+
+fn colour(colour: Color) -> style::Color {
+    style::Color::Rgb {
+        r: colour.r,
+        g: colour.g,
+        b: colour.b,
+    }
+}
+
+// Terminals give us characters rather than physical keys, so this works back to the raylib key
+// that's usually behind them. That way the keymap and hotkeys from the config work here too.
+fn keyboard_key(code: KeyCode) -> Option<KeyboardKey> {
+    let key = match code {
+        KeyCode::Char(' ') => KeyboardKey::KEY_SPACE,
+        KeyCode::Char(',') => KeyboardKey::KEY_COMMA,
+        KeyCode::Char('-') => KeyboardKey::KEY_MINUS,
+        // Plus is shift and equals on most keyboards
+        KeyCode::Char('=' | '+') => KeyboardKey::KEY_EQUAL,
+        KeyCode::Char('.') => KeyboardKey::KEY_PERIOD,
+        KeyCode::Char('/') => KeyboardKey::KEY_SLASH,
+        KeyCode::Char(';') => KeyboardKey::KEY_SEMICOLON,
+        KeyCode::Char('\'') => KeyboardKey::KEY_APOSTROPHE,
+        KeyCode::Char(c) if c.is_ascii_alphanumeric() => {
+            return key_from_name(&c.to_string());
+        }
+        KeyCode::Up => KeyboardKey::KEY_UP,
+        KeyCode::Down => KeyboardKey::KEY_DOWN,
+        KeyCode::Left => KeyboardKey::KEY_LEFT,
+        KeyCode::Right => KeyboardKey::KEY_RIGHT,
+        KeyCode::Enter => KeyboardKey::KEY_ENTER,
+        KeyCode::Tab => KeyboardKey::KEY_TAB,
+        KeyCode::Backspace => KeyboardKey::KEY_BACKSPACE,
+        KeyCode::F(number) => return key_from_name(&format!("F{number}")),
+        _ => return None,
+    };
+    Some(key)
+}
@@ -0,0 +1,305 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::cpu::Cpu;
+use crate::profiler::Profiler;
+
+// The timers and the display on the original hardware both ran at 60Hz, so everything is built
+// around fixed 1/60th of a second frames.
+pub const FRAME_RATE: u32 = 60;
+pub const FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / FRAME_RATE as u64);
+
+// Roughly the speed of the original COSMAC VIP interpreter, most games are written with this in mind
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 15;
+pub const DEFAULT_MAX_CATCH_UP_FRAMES: u32 = 4;
+
+// A VIP frame is 3668 machine cycles, but the display interrupt and the video DMA eat the rest,
+// leaving the interpreter this many to actually run instructions in
+pub const VIP_CYCLES_PER_FRAME: u32 = 1802;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TimingMode {
+    // Every instruction takes the same amount of time, with a fixed number of them per frame
+    InstructionsPerFrame(u32),
+    // Every instruction costs what it did on the COSMAC VIP, and a frame is a budget of cycles
+    Vip,
+}
+
+pub struct TimingSettings {
+    pub mode: TimingMode,
+    pub max_catch_up_frames: u32,
+}
+
+impl TimingSettings {
+    pub fn from_config(config: &Config) -> Self {
+        let instructions_per_frame = config
+            .get_parsed("timing", "ipf")
+            .unwrap_or(DEFAULT_INSTRUCTIONS_PER_FRAME);
+        let mode = match config.get("timing", "mode") {
+            Some("vip") => TimingMode::Vip,
+            Some("ipf") | None => TimingMode::InstructionsPerFrame(instructions_per_frame),
+            Some(other) => {
+                eprintln!("[timing] Unknown mode {other}, expected ipf or vip");
+                TimingMode::InstructionsPerFrame(instructions_per_frame)
+            }
+        };
+
+        TimingSettings {
+            mode,
+            max_catch_up_frames: config
+                .get_parsed("timing", "max_catch_up_frames")
+                .unwrap_or(DEFAULT_MAX_CATCH_UP_FRAMES)
+                .max(1),
+        }
+    }
+}
+
+// A fixed timestep accumulator. Real time gets added up and handed out in whole frames, so the
+// emulator runs at the same speed no matter how unevenly the host gets around to us.
+pub struct FrameClock {
+    accumulator: Duration,
+    last_tick: Instant,
+    max_catch_up_frames: u32,
+}
+
+impl FrameClock {
+    pub fn new(max_catch_up_frames: u32) -> Self {
+        FrameClock {
+            accumulator: Duration::ZERO,
+            last_tick: Instant::now(),
+            max_catch_up_frames,
+        }
+    }
+
+    // How many emulated frames we owe since the last call. If the host has fallen so far behind
+    // that we'd need more than the catch up limit, the extra time is thrown away instead of
+    // running the game in fast forward trying to make it up.
+    pub fn frames_due(&mut self) -> u32 {
+        let now = Instant::now();
+        self.accumulator += now - self.last_tick;
+        self.last_tick = now;
+
+        let mut frames = 0;
+        while self.accumulator >= FRAME_DURATION && frames < self.max_catch_up_frames {
+            self.accumulator -= FRAME_DURATION;
+            frames += 1;
+        }
+
+        if self.accumulator >= FRAME_DURATION {
+            self.accumulator = Duration::ZERO;
+        }
+        frames
+    }
+}
+
+// Fast forward and slow motion, as steps either side of normal speed
+const SPEEDS: [f32; 7] = [0.125, 0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+const NORMAL_SPEED: usize = 3;
+
+// Scales the frames the clock hands out. Fractions carry over to the next call, so half speed
+// runs a frame every other time round rather than never.
+pub struct SpeedControl {
+    index: usize,
+    carried: f32,
+}
+
+impl SpeedControl {
+    pub fn new() -> Self {
+        SpeedControl {
+            index: NORMAL_SPEED,
+            carried: 0.0,
+        }
+    }
+
+    pub fn multiplier(&self) -> f32 {
+        SPEEDS[self.index]
+    }
+
+    pub fn faster(&mut self) {
+        self.index = (self.index + 1).min(SPEEDS.len() - 1);
+    }
+
+    pub fn slower(&mut self) {
+        self.index = self.index.saturating_sub(1);
+        self.carried = 0.0;
+    }
+
+    pub fn scale(&mut self, frames: u32) -> u32 {
+        let total = frames as f32 * self.multiplier() + self.carried;
+        let whole = total.floor();
+        self.carried = total - whole;
+        whole as u32
+    }
+}
+
+// Runs the cpu for exactly one 60Hz frame's worth of work and then ticks the timers
+pub struct FrameRunner {
+    mode: TimingMode,
+    // In VIP mode the last instruction of a frame can run over the budget, those cycles come out of
+    // the next frame instead of getting lost
+    overrun_cycles: u32,
+    // With a profiler every instruction goes through it, one at a time
+    pub profiler: Option<Profiler>,
+}
+
+impl FrameRunner {
+    pub fn new(mode: TimingMode) -> Self {
+        FrameRunner {
+            mode,
+            overrun_cycles: 0,
+            profiler: None,
+        }
+    }
+
+    // Returns how many instructions were executed
+    pub fn run_frame(&mut self, cpu: &mut Cpu) -> u64 {
+        let mut executed: u64 = 0;
+        match self.mode {
+            TimingMode::InstructionsPerFrame(instructions_per_frame) => {
+                let instructions_per_frame = instructions_per_frame as u64;
+                while executed < instructions_per_frame {
+                    if !cpu.breakpoints.is_empty() && cpu.hit_breakpoint() {
+                        break;
+                    }
+                    // Blocks only ever end in the middle of a frame on the instructions that
+                    // could stop it, so checking after each one is the same as after every
+                    // instruction
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.step(cpu);
+                        executed += 1;
+                    } else if cpu.recompile || cpu.native.is_some() {
+                        executed += cpu.run_block(instructions_per_frame - executed);
+                    } else {
+                        let op = cpu.fetch_decoded();
+                        cpu.execute(op);
+                        executed += 1;
+                    }
+                    // With the display wait quirk, drawing ends the frame early
+                    if cpu.should_halt || (cpu.quirks.display_wait && cpu.waiting_for_vblank) {
+                        break;
+                    }
+                }
+            }
+            TimingMode::Vip => {
+                let mut cycles = self.overrun_cycles;
+                while cycles < VIP_CYCLES_PER_FRAME {
+                    if !cpu.breakpoints.is_empty() && cpu.hit_breakpoint() {
+                        break;
+                    }
+                    if let Some(profiler) = &mut self.profiler {
+                        cycles += profiler.step(cpu);
+                    } else {
+                        // The cycle count needs the raw fields, which are cheap to pull back out
+                        cycles += cpu.vip_cycles(&cpu.instruction_at(cpu.program_counter));
+                        let op = cpu.fetch_decoded();
+                        cpu.execute(op);
+                    }
+                    executed += 1;
+                    // Drawing a sprite stalls until the display interrupt, which is the end of
+                    // the frame. This is what makes flicker in VIP games look the way it should.
+                    if cpu.should_halt || cpu.waiting_for_vblank {
+                        break;
+                    }
+                }
+                self.overrun_cycles = cycles.saturating_sub(VIP_CYCLES_PER_FRAME);
+            }
+        }
+        cpu.waiting_for_vblank = false;
+        cpu.tick_timers();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end_frame();
+        }
+        executed
+    }
+}
+
+// Counts up what actually happened over the last second for the on screen readout
+pub struct SpeedMeter {
+    window_start: Instant,
+    frames: u32,
+    instructions: u64,
+    pub frames_per_second: u32,
+    pub instructions_per_second: u64,
+}
+
+impl SpeedMeter {
+    pub fn new() -> Self {
+        SpeedMeter {
+            window_start: Instant::now(),
+            frames: 0,
+            instructions: 0,
+            frames_per_second: 0,
+            instructions_per_second: 0,
+        }
+    }
+
+    pub fn record_frame(&mut self, instructions: u64) {
+        self.frames += 1;
+        self.instructions += instructions;
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let seconds = elapsed.as_secs_f64();
+            self.frames_per_second = (self.frames as f64 / seconds).round() as u32;
+            self.instructions_per_second = (self.instructions as f64 / seconds).round() as u64;
+            self.window_start = Instant::now();
+            self.frames = 0;
+            self.instructions = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every one of these costs 6 cycles on the VIP
+    const SET_V0: [u8; 2] = [0x60, 0x00];
+
+    fn cpu_with(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_program_into_memory(&mut &program[..]).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn vip_frames_spend_the_cycle_budget_and_carry_the_overrun() {
+        let mut cpu = cpu_with(&SET_V0.repeat(1500));
+        let mut runner = FrameRunner::new(TimingMode::Vip);
+
+        // 301 of them are 1806 cycles, 4 over. The next frame starts 4 in so 300 are enough, and
+        // that leaves it 2 over, then 300 fit exactly and the one after starts from nothing again.
+        let executed: Vec<u64> = (0..4).map(|_| runner.run_frame(&mut cpu)).collect();
+        assert_eq!(executed, [301, 300, 300, 301]);
+        assert_eq!(cpu.program_counter, 0x200 + 2 * 1202);
+    }
+
+    #[test]
+    fn vip_frames_end_on_a_draw() {
+        let mut program = SET_V0.repeat(3);
+        program.extend_from_slice(&[0xD0, 0x05]);
+        program.extend(SET_V0.repeat(400));
+        let mut cpu = cpu_with(&program);
+        let mut runner = FrameRunner::new(TimingMode::Vip);
+
+        assert_eq!(runner.run_frame(&mut cpu), 4);
+        assert_eq!(cpu.program_counter, 0x208);
+        assert!(!cpu.waiting_for_vblank);
+        // Stopping early doesn't run into the next frame's budget
+        assert_eq!(runner.run_frame(&mut cpu), 301);
+    }
+
+    #[test]
+    fn ipf_frames_run_their_instructions_and_tick_the_timers_once() {
+        let mut cpu = cpu_with(&SET_V0.repeat(100));
+        cpu.delay_timer = 10;
+        let mut runner = FrameRunner::new(TimingMode::InstructionsPerFrame(15));
+
+        assert_eq!(runner.run_frame(&mut cpu), 15);
+        assert_eq!(runner.run_frame(&mut cpu), 15);
+        assert_eq!(cpu.program_counter, 0x200 + 2 * 30);
+        assert_eq!(cpu.delay_timer, 8);
+    }
+}
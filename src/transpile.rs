@@ -0,0 +1,284 @@
+use std::fmt::Write;
+
+use crate::cpu::{Cpu, Instruction};
+use crate::decode::Op;
+use crate::disasm::{BasicBlock, Flow, find_blocks, flow, mnemonic};
+
+// The bench workloads put through `chipp transpile`, so the tests build and run what it writes
+#[cfg(test)]
+mod alu;
+#[cfg(test)]
+mod memory;
+#[cfg(test)]
+mod self_modifying;
+#[cfg(test)]
+mod sprites;
+
+// One block of a translated ROM. The bytes are what it was translated from, so it can tell when
+// the code has been written over since.
+pub struct NativeBlock {
+    pub start: u16,
+    pub bytes: &'static [u8],
+    pub run: fn(&mut Cpu),
+}
+
+// Games translated with `chipp transpile` and built in, by the SHA-1 of their ROM. Add a module's
+// SHA1 and BLOCKS here and the game runs on them whenever that ROM gets loaded.
+#[cfg(not(test))]
+const NATIVE_GAMES: &[(&str, &[NativeBlock])] = &[];
+// The tests get the bench workloads instead, and load them the same way a game would be
+#[cfg(test)]
+const NATIVE_GAMES: &[(&str, &[NativeBlock])] = &[
+    (alu::SHA1, alu::BLOCKS),
+    (sprites::SHA1, sprites::BLOCKS),
+    (memory::SHA1, memory::BLOCKS),
+    (self_modifying::SHA1, self_modifying::BLOCKS),
+];
+
+pub fn native_blocks(rom_sha1: &str) -> Option<&'static [NativeBlock]> {
+    NATIVE_GAMES
+        .iter()
+        .find(|(sha1, _)| *sha1 == rom_sha1)
+        .map(|(_, blocks)| *blocks)
+}
+
+// Runs the translated block at the program counter and returns how many instructions that was.
+// Anywhere there isn't a block for (code only reached through BNNN, or the middle of a block), a
+// block that doesn't fit in the budget, and one that's been written over all get a single
+// instruction from the interpreter instead, so it always runs the same as the ROM would.
+pub fn run_native(cpu: &mut Cpu, blocks: &[NativeBlock], budget: u64) -> u64 {
+    if let Ok(index) = blocks.binary_search_by_key(&cpu.program_counter, |block| block.start) {
+        let block = &blocks[index];
+        let instructions = block.bytes.len() as u64 / 2;
+        if instructions <= budget && cpu.code_matches(block.start, block.bytes) {
+            cpu.mark_executed(block.start, instructions as usize);
+            (block.run)(cpu);
+            return instructions;
+        }
+    }
+    let op = cpu.fetch_decoded();
+    cpu.execute(op);
+    1
+}
+
+// Turns every basic block the disassembler can find into a Rust function working on a Cpu, along
+// with a table of them for run_native
+pub fn transpile(rom: &[u8], name: &str, rom_sha1: &str) -> String {
+    let blocks = find_blocks(rom);
+    let translated: Vec<(&BasicBlock, usize, String)> = blocks
+        .iter()
+        .map(|block| {
+            let (count, function) = block_function(block);
+            (block, count, function)
+        })
+        .collect();
+    let instructions: usize = blocks.iter().map(|block| block.opcodes.len()).sum();
+    let computed_jumps: Vec<String> = blocks
+        .iter()
+        .flat_map(|block| {
+            (block.start..)
+                .step_by(2)
+                .zip(&block.opcodes)
+                .filter(|(_, opcode)| flow(**opcode) == Flow::ComputedJump)
+                .map(|(address, _)| format!("{address:#05X}"))
+        })
+        .collect();
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "// Translated from {name} by `chipp transpile`, translate it again rather than editing this"
+    );
+    let _ = writeln!(
+        out,
+        "// {} blocks covering {instructions} instructions, everything else goes through the interpreter",
+        blocks.len()
+    );
+    if !computed_jumps.is_empty() {
+        let _ = writeln!(
+            out,
+            "// Computed jumps (BNNN) that can land anywhere: {}",
+            computed_jumps.join(", ")
+        );
+    }
+    out.push('\n');
+    out.push_str("use crate::cpu::Cpu;\n");
+    if translated
+        .iter()
+        .any(|(_, _, function)| function.contains("Op::"))
+    {
+        out.push_str("use crate::decode::Op;\n");
+    }
+    out.push_str("use crate::transpile::NativeBlock;\n");
+    let _ = writeln!(out, "\npub const SHA1: &str = \"{rom_sha1}\";\n");
+
+    out.push_str("pub const BLOCKS: &[NativeBlock] = &[\n");
+    for (block, count, _) in &translated {
+        let bytes: Vec<String> = block.opcodes[..*count]
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .map(|byte| format!("{byte:#04X}"))
+            .collect();
+        let _ = writeln!(
+            out,
+            "    NativeBlock {{\n        start: {0:#05X},\n        bytes: &[{1}],\n        run: block_{0:03x},\n    }},",
+            block.start,
+            bytes.join(", ")
+        );
+    }
+    out.push_str("];\n");
+    for (_, _, function) in translated {
+        out.push('\n');
+        out.push_str(&function);
+    }
+    out
+}
+
+// One function for one block, along with how many of its instructions it covers. An instruction
+// the interpreter would treat as a jump can turn up in the middle of a block (0x01EE is a return
+// as far as it's concerned), and the function stops there.
+fn block_function(block: &BasicBlock) -> (usize, String) {
+    let mut function = String::new();
+    let _ = writeln!(function, "fn block_{:03x}(cpu: &mut Cpu) {{", block.start);
+    let mut address = block.start;
+    let mut count = 0;
+    for opcode in &block.opcodes {
+        let op = Op::decode(&Instruction::from_opcode(*opcode));
+        let _ = writeln!(function, "    // {address:#05X}  {}", mnemonic(*opcode));
+        address += 2;
+        count += 1;
+        if op.ends_block() {
+            // The interpreter has already moved past an instruction by the time it runs it
+            let _ = writeln!(
+                function,
+                "    cpu.program_counter = {:#05X};",
+                address & 0xFFF
+            );
+            let _ = writeln!(function, "    cpu.execute(Op::{op:?});");
+            function.push_str("}\n");
+            return (count, function);
+        }
+        function.push_str(&translate(op));
+    }
+    let _ = writeln!(
+        function,
+        "    cpu.program_counter = {:#05X};\n}}",
+        block.end() & 0xFFF
+    );
+    (count, function)
+}
+
+// The register instructions written out in Rust, everything else goes through Cpu::execute. VX and
+// VY being the same register goes through execute as well, rather than writing out things like
+// V1 = V1 for the compiler to complain about.
+fn translate(op: Op) -> String {
+    let register = |index: u8| format!("cpu.v_registers[{index:#X}]");
+    let vf_reset = "    if cpu.quirks.vf_reset {\n        cpu.v_registers[0xF] = 0;\n    }\n";
+    match op {
+        Op::Nop => String::new(),
+        Op::Load { x, nn } => format!("    {} = {nn:#04X};\n", register(x)),
+        Op::Add { x, nn } => format!("    {0} = {0}.wrapping_add({nn:#04X});\n", register(x)),
+        Op::Move { x, y } if x != y => format!("    {} = {};\n", register(x), register(y)),
+        Op::Or { x, y } if x != y => {
+            format!("    {} |= {};\n{vf_reset}", register(x), register(y))
+        }
+        Op::And { x, y } if x != y => {
+            format!("    {} &= {};\n{vf_reset}", register(x), register(y))
+        }
+        Op::Xor { x, y } if x != y => {
+            format!("    {} ^= {};\n{vf_reset}", register(x), register(y))
+        }
+        Op::AddRegisters { x, y } => format!(
+            "    let (result, carried) = {0}.overflowing_add({1});\n    {0} = result;\n    cpu.v_registers[0xF] = carried as u8;\n",
+            register(x),
+            register(y)
+        ),
+        Op::Subtract { x, y } if x != y => subtract(register(x), register(x), register(y)),
+        Op::SubtractReversed { x, y } if x != y => subtract(register(x), register(y), register(x)),
+        _ => format!("    cpu.execute(Op::{op:?});\n"),
+    }
+}
+
+fn subtract(target: String, from: String, minus: String) -> String {
+    format!(
+        "    let (result, borrowed) = {from}.overflowing_sub({minus});\n    {target} = result;\n    cpu.v_registers[0xF] = !borrowed as u8;\n"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::WORKLOADS;
+    use crate::romdb::sha1_hex;
+
+    // Each workload's translation, in the same order as WORKLOADS
+    const TRANSLATED: [&str; 4] = [
+        include_str!("transpile/alu.rs"),
+        include_str!("transpile/sprites.rs"),
+        include_str!("transpile/memory.rs"),
+        include_str!("transpile/self_modifying.rs"),
+    ];
+
+    fn load(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_program_into_memory(&mut &program[..]).unwrap();
+        cpu
+    }
+
+    // Otherwise the tests would be checking code transpile doesn't write any more
+    #[test]
+    fn translations_are_up_to_date() {
+        for (workload, source) in WORKLOADS.iter().zip(TRANSLATED) {
+            let name = format!("{}.ch8", workload.name);
+            assert!(
+                transpile(workload.program, &name, &sha1_hex(workload.program)) == source,
+                "src/transpile/{}.rs needs translating again",
+                workload.name.replace('-', "_")
+            );
+        }
+    }
+
+    // The translated blocks have to end up exactly where the interpreter does, including when the
+    // self-modifying workload writes over one of them
+    #[test]
+    fn native_blocks_match_the_interpreter() {
+        for workload in &WORKLOADS {
+            let mut native = load(workload.program);
+            native.native = native_blocks(&native.rom_sha1);
+            assert!(native.native.is_some(), "{} isn't built in", workload.name);
+            let mut interpreted = load(workload.program);
+            interpreted.load_state(&native.save_state()).unwrap();
+
+            let mut longest = 0;
+            for round in 0..5000 {
+                let address = native.program_counter;
+                let ran = native.run_block(1 + round % 50);
+                longest = longest.max(ran);
+                for _ in 0..ran {
+                    let op = interpreted.fetch_decoded();
+                    interpreted.execute(op);
+                }
+                let (expected, actual) = (interpreted.save_state(), native.save_state());
+                assert!(
+                    actual == expected,
+                    "{} went wrong in the block at {address:#05X}: {:?}",
+                    workload.name,
+                    actual.differences(&expected)
+                );
+            }
+            assert!(
+                longest > 1,
+                "{} never ran a translated block",
+                workload.name
+            );
+            for address in 0..0x1000 {
+                assert_eq!(
+                    native.coverage().flags(address),
+                    interpreted.coverage().flags(address),
+                    "{} marked {address:#05X} differently",
+                    workload.name
+                );
+            }
+        }
+    }
+}
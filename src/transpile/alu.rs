@@ -0,0 +1,70 @@
+// Translated from alu.ch8 by `chipp transpile`, translate it again rather than editing this
+// 4 blocks covering 9 instructions, everything else goes through the interpreter
+
+use crate::cpu::Cpu;
+use crate::decode::Op;
+use crate::transpile::NativeBlock;
+
+pub const SHA1: &str = "fd964c9afde46d4257ba071a777d9be24e88e69a";
+
+pub const BLOCKS: &[NativeBlock] = &[
+    NativeBlock {
+        start: 0x200,
+        bytes: &[0x60, 0x00, 0x61, 0x01],
+        run: block_200,
+    },
+    NativeBlock {
+        start: 0x204,
+        bytes: &[0x80, 0x14, 0x82, 0x03, 0x83, 0x06, 0x72, 0x01, 0x30, 0x00],
+        run: block_204,
+    },
+    NativeBlock {
+        start: 0x20E,
+        bytes: &[0x12, 0x04],
+        run: block_20e,
+    },
+    NativeBlock {
+        start: 0x210,
+        bytes: &[0x12, 0x00],
+        run: block_210,
+    },
+];
+
+fn block_200(cpu: &mut Cpu) {
+    // 0x200  LD V0, 0x00
+    cpu.v_registers[0x0] = 0x00;
+    // 0x202  LD V1, 0x01
+    cpu.v_registers[0x1] = 0x01;
+    cpu.program_counter = 0x204;
+}
+
+fn block_204(cpu: &mut Cpu) {
+    // 0x204  ADD V0, V1
+    let (result, carried) = cpu.v_registers[0x0].overflowing_add(cpu.v_registers[0x1]);
+    cpu.v_registers[0x0] = result;
+    cpu.v_registers[0xF] = carried as u8;
+    // 0x206  XOR V2, V0
+    cpu.v_registers[0x2] ^= cpu.v_registers[0x0];
+    if cpu.quirks.vf_reset {
+        cpu.v_registers[0xF] = 0;
+    }
+    // 0x208  SHR V3, V0
+    cpu.execute(Op::ShiftRight { x: 3, y: 0 });
+    // 0x20A  ADD V2, 0x01
+    cpu.v_registers[0x2] = cpu.v_registers[0x2].wrapping_add(0x01);
+    // 0x20C  SE V0, 0x00
+    cpu.program_counter = 0x20E;
+    cpu.execute(Op::SkipIfEqual { x: 0, nn: 0 });
+}
+
+fn block_20e(cpu: &mut Cpu) {
+    // 0x20E  JP 0x204
+    cpu.program_counter = 0x210;
+    cpu.execute(Op::Jump { nnn: 516 });
+}
+
+fn block_210(cpu: &mut Cpu) {
+    // 0x210  JP 0x200
+    cpu.program_counter = 0x212;
+    cpu.execute(Op::Jump { nnn: 512 });
+}
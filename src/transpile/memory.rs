@@ -0,0 +1,85 @@
+// Translated from memory.ch8 by `chipp transpile`, translate it again rather than editing this
+// 6 blocks covering 10 instructions, everything else goes through the interpreter
+
+use crate::cpu::Cpu;
+use crate::decode::Op;
+use crate::transpile::NativeBlock;
+
+pub const SHA1: &str = "f4d2de91a99743918bad6893118c939ecaf4d7d5";
+
+pub const BLOCKS: &[NativeBlock] = &[
+    NativeBlock {
+        start: 0x200,
+        bytes: &[0x6A, 0x7B],
+        run: block_200,
+    },
+    NativeBlock {
+        start: 0x202,
+        bytes: &[0x22, 0x10],
+        run: block_202,
+    },
+    NativeBlock {
+        start: 0x204,
+        bytes: &[0x7A, 0x01, 0x12, 0x02],
+        run: block_204,
+    },
+    NativeBlock {
+        start: 0x210,
+        bytes: &[0xA3, 0x00, 0xFA, 0x33],
+        run: block_210,
+    },
+    NativeBlock {
+        start: 0x214,
+        bytes: &[0xF2, 0x65, 0xA3, 0x10, 0xF2, 0x55],
+        run: block_214,
+    },
+    NativeBlock {
+        start: 0x21A,
+        bytes: &[0x00, 0xEE],
+        run: block_21a,
+    },
+];
+
+fn block_200(cpu: &mut Cpu) {
+    // 0x200  LD VA, 0x7B
+    cpu.v_registers[0xA] = 0x7B;
+    cpu.program_counter = 0x202;
+}
+
+fn block_202(cpu: &mut Cpu) {
+    // 0x202  CALL 0x210
+    cpu.program_counter = 0x204;
+    cpu.execute(Op::Call { nnn: 528 });
+}
+
+fn block_204(cpu: &mut Cpu) {
+    // 0x204  ADD VA, 0x01
+    cpu.v_registers[0xA] = cpu.v_registers[0xA].wrapping_add(0x01);
+    // 0x206  JP 0x202
+    cpu.program_counter = 0x208;
+    cpu.execute(Op::Jump { nnn: 514 });
+}
+
+fn block_210(cpu: &mut Cpu) {
+    // 0x210  LD I, 0x300
+    cpu.execute(Op::LoadIndex { nnn: 768 });
+    // 0x212  LD B, VA
+    cpu.program_counter = 0x214;
+    cpu.execute(Op::StoreBcd { x: 10 });
+}
+
+fn block_214(cpu: &mut Cpu) {
+    // 0x214  LD V2, [I]
+    cpu.execute(Op::LoadRegisters { x: 2 });
+    // 0x216  LD I, 0x310
+    cpu.execute(Op::LoadIndex { nnn: 784 });
+    // 0x218  LD [I], V2
+    cpu.program_counter = 0x21A;
+    cpu.execute(Op::StoreRegisters { x: 2 });
+}
+
+fn block_21a(cpu: &mut Cpu) {
+    // 0x21A  RET
+    cpu.program_counter = 0x21C;
+    cpu.execute(Op::Return);
+}
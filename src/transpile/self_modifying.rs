@@ -0,0 +1,51 @@
+// Translated from self-modifying.ch8 by `chipp transpile`, translate it again rather than editing this
+// 3 blocks covering 7 instructions, everything else goes through the interpreter
+
+use crate::cpu::Cpu;
+use crate::decode::Op;
+use crate::transpile::NativeBlock;
+
+pub const SHA1: &str = "77e1129661a05186948a24d30d5b498a4d393b7d";
+
+pub const BLOCKS: &[NativeBlock] = &[
+    NativeBlock {
+        start: 0x200,
+        bytes: &[0x60, 0x72, 0x61, 0x01],
+        run: block_200,
+    },
+    NativeBlock {
+        start: 0x204,
+        bytes: &[0xA2, 0x0A, 0xF1, 0x55],
+        run: block_204,
+    },
+    NativeBlock {
+        start: 0x208,
+        bytes: &[0x71, 0x01, 0x00, 0x00, 0x12, 0x04],
+        run: block_208,
+    },
+];
+
+fn block_200(cpu: &mut Cpu) {
+    // 0x200  LD V0, 0x72
+    cpu.v_registers[0x0] = 0x72;
+    // 0x202  LD V1, 0x01
+    cpu.v_registers[0x1] = 0x01;
+    cpu.program_counter = 0x204;
+}
+
+fn block_204(cpu: &mut Cpu) {
+    // 0x204  LD I, 0x20A
+    cpu.execute(Op::LoadIndex { nnn: 522 });
+    // 0x206  LD [I], V1
+    cpu.program_counter = 0x208;
+    cpu.execute(Op::StoreRegisters { x: 1 });
+}
+
+fn block_208(cpu: &mut Cpu) {
+    // 0x208  ADD V1, 0x01
+    cpu.v_registers[0x1] = cpu.v_registers[0x1].wrapping_add(0x01);
+    // 0x20A  SYS 0x000
+    // 0x20C  JP 0x204
+    cpu.program_counter = 0x20E;
+    cpu.execute(Op::Jump { nnn: 516 });
+}
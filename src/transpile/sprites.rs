@@ -0,0 +1,54 @@
+// Translated from sprites.ch8 by `chipp transpile`, translate it again rather than editing this
+// 3 blocks covering 8 instructions, everything else goes through the interpreter
+
+use crate::cpu::Cpu;
+use crate::decode::Op;
+use crate::transpile::NativeBlock;
+
+pub const SHA1: &str = "43098ad132fd0d7483ae5edb2882e474f68e10c1";
+
+pub const BLOCKS: &[NativeBlock] = &[
+    NativeBlock {
+        start: 0x200,
+        bytes: &[0x00, 0xE0, 0x60, 0x00, 0x61, 0x00],
+        run: block_200,
+    },
+    NativeBlock {
+        start: 0x206,
+        bytes: &[0xF0, 0x29, 0xD0, 0x15],
+        run: block_206,
+    },
+    NativeBlock {
+        start: 0x20A,
+        bytes: &[0x70, 0x07, 0x71, 0x03, 0x12, 0x06],
+        run: block_20a,
+    },
+];
+
+fn block_200(cpu: &mut Cpu) {
+    // 0x200  CLS
+    cpu.execute(Op::ClearScreen);
+    // 0x202  LD V0, 0x00
+    cpu.v_registers[0x0] = 0x00;
+    // 0x204  LD V1, 0x00
+    cpu.v_registers[0x1] = 0x00;
+    cpu.program_counter = 0x206;
+}
+
+fn block_206(cpu: &mut Cpu) {
+    // 0x206  LD F, V0
+    cpu.execute(Op::LoadFont { x: 0 });
+    // 0x208  DRW V0, V1, 5
+    cpu.program_counter = 0x20A;
+    cpu.execute(Op::Draw { x: 0, y: 1, n: 5 });
+}
+
+fn block_20a(cpu: &mut Cpu) {
+    // 0x20A  ADD V0, 0x07
+    cpu.v_registers[0x0] = cpu.v_registers[0x0].wrapping_add(0x07);
+    // 0x20C  ADD V1, 0x03
+    cpu.v_registers[0x1] = cpu.v_registers[0x1].wrapping_add(0x03);
+    // 0x20E  JP 0x206
+    cpu.program_counter = 0x210;
+    cpu.execute(Op::Jump { nnn: 518 });
+}
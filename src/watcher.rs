@@ -0,0 +1,148 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+// How often the file gets looked at, often enough to feel instant after saving
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+// What happens after the ROM gets reloaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReloadMode {
+    // Start from scratch, like a fresh launch
+    Reset,
+    // Go back to the quick save slot, with the new code swapped into it
+    State,
+    // Play the keys pressed so far back into the new code, as fast as possible
+    Replay,
+}
+
+impl ReloadMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "reset" => Some(ReloadMode::Reset),
+            "state" => Some(ReloadMode::State),
+            "replay" => Some(ReloadMode::Replay),
+            _ => None,
+        }
+    }
+}
+
+// Notices when a file changes by checking its modified time and size every so often. That's all a
+// ROM needs, and it works the same everywhere without a file system events library.
+pub struct FileWatcher {
+    path: PathBuf,
+    last_seen: Option<(SystemTime, u64)>,
+    // A change we've seen once but not twice yet
+    pending: Option<(SystemTime, u64)>,
+    last_check: Instant,
+    interval: Duration,
+}
+
+impl FileWatcher {
+    pub fn new(path: &Path) -> Self {
+        FileWatcher::with_interval(path, CHECK_INTERVAL)
+    }
+
+    pub fn with_interval(path: &Path, interval: Duration) -> Self {
+        FileWatcher {
+            path: path.to_path_buf(),
+            last_seen: stamp(path),
+            pending: None,
+            last_check: Instant::now(),
+            interval,
+        }
+    }
+
+    // True once the file has changed and then stayed the same for a whole check, so we don't read
+    // it half way through an assembler writing it out
+    pub fn changed(&mut self) -> bool {
+        if self.last_check.elapsed() < self.interval {
+            return false;
+        }
+        self.last_check = Instant::now();
+
+        let current = stamp(&self.path);
+        if current == self.last_seen {
+            self.pending = None;
+            false
+        } else if current.is_some() && current == self.pending {
+            self.last_seen = current;
+            self.pending = None;
+            true
+        } else {
+            self.pending = current;
+            false
+        }
+    }
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each test gets its own file, they run at the same time
+    fn temp_rom(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("chipp-test-{}-{name}.ch8", std::process::id()));
+        fs::write(&path, [0x12, 0x00]).unwrap();
+        path
+    }
+
+    #[test]
+    fn a_change_has_to_be_seen_twice() {
+        let path = temp_rom("twice");
+        let mut watcher = FileWatcher::with_interval(&path, Duration::ZERO);
+        assert!(!watcher.changed());
+
+        fs::write(&path, [0x12, 0x00, 0x00, 0xE0]).unwrap();
+        assert!(!watcher.changed());
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_file_still_being_written_waits_until_it_settles() {
+        let path = temp_rom("settles");
+        let mut watcher = FileWatcher::with_interval(&path, Duration::ZERO);
+
+        fs::write(&path, [0x12, 0x00, 0x00]).unwrap();
+        assert!(!watcher.changed());
+        fs::write(&path, [0x12, 0x00, 0x00, 0xE0]).unwrap();
+        assert!(!watcher.changed());
+        assert!(watcher.changed());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_missing_file_isnt_a_change() {
+        let path = temp_rom("missing");
+        let mut watcher = FileWatcher::with_interval(&path, Duration::ZERO);
+
+        fs::remove_file(&path).unwrap();
+        assert!(!watcher.changed());
+        assert!(!watcher.changed());
+        // Saving it again counts, once it's settled
+        fs::write(&path, [0x12, 0x00, 0x00, 0xE0]).unwrap();
+        assert!(!watcher.changed());
+        assert!(watcher.changed());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn nothing_is_looked_at_between_checks() {
+        let path = temp_rom("interval");
+        let mut watcher = FileWatcher::with_interval(&path, Duration::from_secs(3600));
+
+        fs::write(&path, [0x12, 0x00, 0x00, 0xE0]).unwrap();
+        assert!(!watcher.changed());
+        assert!(!watcher.changed());
+        assert!(watcher.pending.is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
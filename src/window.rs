@@ -0,0 +1,232 @@
+use std::io;
+use std::path::PathBuf;
+
+use raylib::prelude::*;
+
+use crate::backend::{AudioSink, DisplaySink, FrameView, HostEvent, InputSource};
+use crate::buzzer::SAMPLE_RATE;
+use crate::cli::RunOptions;
+use crate::config::Config;
+use crate::emulator::Emulator;
+use crate::graphics::{
+    Layout, MIN_WINDOW_SIZE, draw_game_pixels, draw_status_bar, draw_ui_elements, window_size,
+};
+use crate::input::poll_input;
+use crate::keymap::{Hotkeys, Keymap};
+use crate::launcher::{Launcher, dropped_rom};
+use crate::palette::{load_palettes, pick_palette};
+use crate::timing::FRAME_RATE;
+
+// How many samples we hand raylib each time it asks for more audio
+const AUDIO_BUFFER_SIZE: usize = 1024;
+
+// The normal way of playing: a raylib window with the debug panels next to the game. Without a ROM
+// it opens on the launcher, and the window stays open from one ROM to the next.
+pub fn run_window(options: &RunOptions, config: &Config) -> io::Result<()> {
+    // --scale only picks how big the window starts, after that it follows the window
+    let (window_width, window_height) = window_size(options.scale);
+    let (mut rl, thread) = raylib::init()
+        .width(window_width)
+        .height(window_height)
+        .title("C8-Emu")
+        .resizable()
+        .build();
+    rl.set_window_min_size(MIN_WINDOW_SIZE.0, MIN_WINDOW_SIZE.1);
+    rl.set_target_fps(FRAME_RATE);
+    rl.set_trace_log(TraceLogLevel::LOG_NONE);
+
+    // Not having a sound card shouldn't stop anyone from playing, we just won't beep
+    let audio = match RaylibAudio::init_audio_device() {
+        Ok(audio) => Some(audio),
+        Err(err) => {
+            eprintln!("Couldn't start the audio device, continuing without sound: {err}");
+            None
+        }
+    };
+    if let Some(audio) = &audio {
+        audio.set_audio_stream_buffer_size_default(AUDIO_BUFFER_SIZE as i32);
+    }
+    let mut speaker = RaylibSpeaker {
+        stream: audio.as_ref().map(|audio| {
+            let stream = audio.new_audio_stream(SAMPLE_RATE, 8, 1);
+            stream.play();
+            stream
+        }),
+    };
+
+    let mut launcher = Launcher::new(config);
+    let mut frontend = RaylibFrontend::new(rl, thread, config);
+    let mut options = options.clone();
+    let mut next_rom = (!options.rom.is_empty()).then(|| PathBuf::from(&options.rom));
+    loop {
+        let rom = match next_rom.take() {
+            Some(rom) => rom,
+            None => match frontend.choose_rom(&mut launcher, &options, config) {
+                Some(rom) => rom,
+                None => return Ok(()),
+            },
+        };
+
+        // Every ROM gets a fresh cpu, the same as starting the program again
+        options.rom = rom.to_string_lossy().into_owned();
+        let mut emulator = match Emulator::new(&options, config) {
+            Ok(emulator) => emulator,
+            Err(err) => {
+                launcher.message = Some(format!("Couldn't start {}: {err}", rom.display()));
+                continue;
+            }
+        };
+        launcher.message = None;
+        launcher.add_recent(&rom);
+        let database_keys = emulator.rom_info().map_or(&[][..], |info| &info.keys[..]);
+        frontend.keymap = Keymap::load(config, &options.rom, database_keys);
+
+        emulator.run(&mut frontend, &mut speaker)?;
+        emulator.finish()?;
+        // Recordings and movies were asked for with the first ROM, not whatever comes after it
+        options.record = None;
+        options.record_movie = None;
+        options.play_movie = None;
+
+        match frontend.switch.take() {
+            Some(Switch::Launcher) => {}
+            Some(Switch::Load(rom)) => next_rom = Some(rom),
+            None => return Ok(()),
+        }
+    }
+}
+
+// Why the game stopped when the window wasn't closed
+enum Switch {
+    Launcher,
+    Load(PathBuf),
+}
+
+struct RaylibFrontend {
+    rl: RaylibHandle,
+    thread: RaylibThread,
+    keymap: Keymap,
+    hotkeys: Hotkeys,
+    show_debug: bool,
+    show_memory: bool,
+    switch: Option<Switch>,
+}
+
+impl RaylibFrontend {
+    fn new(rl: RaylibHandle, thread: RaylibThread, config: &Config) -> Self {
+        RaylibFrontend {
+            rl,
+            thread,
+            keymap: Keymap::default(),
+            hotkeys: Hotkeys::from_config(config),
+            show_debug: !config.get_parsed("display", "game_only").unwrap_or(false),
+            show_memory: config
+                .get_parsed("display", "memory_viewer")
+                .unwrap_or(false),
+            switch: None,
+        }
+    }
+
+    // Shows the launcher until a ROM gets picked, None if the window was closed instead
+    fn choose_rom(
+        &mut self,
+        launcher: &mut Launcher,
+        options: &RunOptions,
+        config: &Config,
+    ) -> Option<PathBuf> {
+        let mut palettes = load_palettes(config);
+        let palette_index = pick_palette(&mut palettes, options, config, None).unwrap_or(0);
+        while !self.rl.window_should_close() {
+            if let Some(rom) = launcher.update(&self.rl) {
+                return Some(rom);
+            }
+            let mut d = self.rl.begin_drawing(&self.thread);
+            launcher.draw(&mut d, &palettes[palette_index]);
+        }
+        None
+    }
+}
+
+impl InputSource for RaylibFrontend {
+    fn poll(&mut self, keypad: &mut [bool; 16], events: &mut Vec<HostEvent>) {
+        for (key, event) in &self.hotkeys.events {
+            if self.rl.is_key_pressed(*key) {
+                events.push(*event);
+            }
+        }
+        // The window's own business, the emulator doesn't need to hear about these
+        if self.rl.is_key_pressed(self.hotkeys.game_only) {
+            self.show_debug = !self.show_debug;
+        }
+        if self.rl.is_key_pressed(self.hotkeys.memory_viewer) {
+            self.show_memory = !self.show_memory;
+        }
+        if self.rl.is_key_pressed(self.hotkeys.fullscreen) {
+            // Borderless rather than real fullscreen, so the window just grows to cover the
+            // monitor and the layout works out the rest without changing video modes
+            self.rl.toggle_borderless_windowed();
+        }
+
+        if self.rl.is_key_pressed(self.hotkeys.launcher) {
+            self.switch = Some(Switch::Launcher);
+        }
+        if let Some(rom) = dropped_rom(&self.rl) {
+            self.switch = Some(Switch::Load(rom));
+        }
+
+        poll_input(&self.rl, keypad, &self.keymap);
+    }
+
+    fn should_quit(&self) -> bool {
+        self.switch.is_some() || self.rl.window_should_close()
+    }
+}
+
+impl DisplaySink for RaylibFrontend {
+    fn present(&mut self, frame: &FrameView<'_>) {
+        let layout = Layout::compute(
+            self.rl.get_screen_width(),
+            self.rl.get_screen_height(),
+            self.show_debug,
+        );
+        let mut d = self.rl.begin_drawing(&self.thread);
+        draw_ui_elements(
+            &mut d,
+            frame.cpu,
+            &layout,
+            frame.opcodes,
+            frame.palette,
+            self.show_memory,
+        );
+        draw_game_pixels(&mut d, frame.pixels, &layout, frame.palette);
+        draw_status_bar(&mut d, &layout, &frame.status(), frame.palette);
+    }
+}
+
+struct RaylibSpeaker<'a> {
+    stream: Option<AudioStream<'a>>,
+}
+
+impl AudioSink for RaylibSpeaker<'_> {
+    fn samples_wanted(&mut self) -> usize {
+        match &self.stream {
+            Some(stream) if stream.is_processed() => AUDIO_BUFFER_SIZE,
+            _ => 0,
+        }
+    }
+
+    fn play(&mut self, samples: &[i16]) {
+        if let Some(stream) = self.stream.as_mut() {
+            stream.update(&to_unsigned_8bit(samples));
+        }
+    }
+}
+
+// raylib-rs passes the length of the slice in bytes where raylib expects a sample count, which
+// only lines up for 8 bit samples. 8 bit PCM in raylib is unsigned so we shift it up by 128.
+fn to_unsigned_8bit(samples: &[i16]) -> Vec<u8> {
+    samples
+        .iter()
+        .map(|sample| ((sample >> 8) + 128) as u8)
+        .collect()
+}